@@ -0,0 +1,11 @@
+-- url_id for restaurants, so they can be looked up by key like the levels above.
+-- It's derived from the name, so scrapers don't need to care about it.
+create or replace function url_slug(s text)
+    returns text
+    language sql immutable parallel safe strict as
+$$
+    select trim(both '-' from regexp_replace(lower(translate(s, 'åäöæøéèüÅÄÖÆØÉÈÜ', 'aaoaoeeuAAOAOEEU')), '[^a-z0-9]+', '-', 'g'))
+$$;
+
+alter table restaurant add column url_id text generated always as (url_slug(restaurant_name)) stored;
+create index on restaurant (site_id, url_id);
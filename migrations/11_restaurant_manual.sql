@@ -0,0 +1,3 @@
+-- Restaurants that are curated by hand, and not by a scraper.
+-- Scraper updates never touch these, so a scraped restaurant with the same name is skipped instead.
+alter table restaurant add column manual boolean not null default false;
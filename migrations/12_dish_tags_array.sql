@@ -0,0 +1,5 @@
+-- Tags were stored as comma separated text, which breaks for tags containing a comma
+alter table dish alter column tags type text[] using coalesce(string_to_array(nullif(tags, ''), ','), '{}');
+alter table dish alter column tags set default '{}';
+update dish set tags = '{}' where tags is null;
+alter table dish alter column tags set not null;
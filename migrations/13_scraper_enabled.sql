@@ -0,0 +1,9 @@
+-- Scrapers can be disabled without a rebuild. Scrapers without a row here are enabled.
+create table scraper_enabled
+(
+  scraper_name text primary key,
+  enabled boolean not null default true,
+  created_at timestamptz not null default now(),
+  updated_at timestamptz
+);
+select trigger_updated_at('scraper_enabled');
@@ -0,0 +1,21 @@
+-- Full text search over dishes, ignoring accents, so "kottbullar" finds "köttbullar".
+-- The simple config is used, since menus are in many languages, and often mix them.
+create extension if not exists unaccent;
+
+-- unaccent is only stable, since the dictionary could change, so it needs an immutable wrapper to
+-- be used in an index
+create or replace function immutable_unaccent(text) returns text
+  language sql immutable parallel safe strict
+  as $$ select public.unaccent('public.unaccent', $1) $$;
+
+create or replace function dish_search_vector(dish_name text, description text, comment text)
+  returns tsvector
+  language sql immutable parallel safe
+  as $$
+    select to_tsvector('simple', immutable_unaccent(
+      coalesce(dish_name, '') || ' ' || coalesce(description, '') || ' ' || coalesce(comment, '')
+    ))
+  $$;
+
+-- search queries must use the same expression to use the index
+create index dish_search_idx on dish using gin (dish_search_vector(dish_name, description, comment));
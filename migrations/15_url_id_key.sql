@@ -0,0 +1,18 @@
+-- Key for comparing url_ids from requests with those in the DB, ignoring case and accents, so that
+-- e.g. "göteborg" and "goteborg" resolve to the same city. Accents are stripped before slugging,
+-- since url_slug only knows about a few of them. See immutable_unaccent in 14_dish_search.sql.
+create or replace function url_id_key(s text)
+    returns text
+    language sql immutable parallel safe strict as
+$$
+    select url_slug(immutable_unaccent(s))
+$$;
+
+-- Lookups compare url_id_key on both sides, which the plain url_id indexes can't help with.
+-- The indexes are unique, since a key has to resolve to a single row within its parent, and it
+-- takes a constraint to keep concurrent inserts from both getting through. The names are matched
+-- on in db.rs, to tell these apart from other unique violations.
+create unique index country_url_id_key_unique on country (url_id_key(url_id));
+create unique index city_url_id_key_unique on city (country_id, url_id_key(url_id));
+create unique index site_url_id_key_unique on site (city_id, url_id_key(url_id));
+create unique index restaurant_url_id_key_unique on restaurant (site_id, url_id_key(url_id));
@@ -0,0 +1,5 @@
+-- Coordinates for restaurants, parsed from their map URLs when available
+alter table restaurant
+  add column lat double precision,
+  add column lon double precision;
+create index on restaurant (lat, lon);
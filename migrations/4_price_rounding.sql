@@ -0,0 +1,3 @@
+-- What to round scraped prices to for each country, e.g. 1 for whole krona.
+-- Null or 0 keeps the prices as scraped.
+alter table country add column price_rounding real;
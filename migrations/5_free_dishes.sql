@@ -0,0 +1,3 @@
+-- Set for countries where dishes without a price are actually free, so they're not filtered out as
+-- parse failures
+alter table country add column allow_free_dishes boolean not null default false;
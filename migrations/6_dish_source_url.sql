@@ -0,0 +1,2 @@
+-- The page each dish was scraped from
+alter table dish add column source_url text;
@@ -0,0 +1,13 @@
+-- Outcome of the last run of each scraper, for monitoring
+create table scraper_status
+(
+  scraper_name text primary key,
+  site_id uuid references site (site_id) on delete set null,
+  last_success_at timestamptz,
+  last_failure_at timestamptz,
+  last_error text,
+  last_dish_count integer,
+  created_at timestamptz not null default now(),
+  updated_at timestamptz
+);
+select trigger_updated_at('scraper_status');
@@ -0,0 +1,3 @@
+-- Optional override of the country's currency_suffix, for sites that differ from the rest of the
+-- country
+alter table site add column currency_suffix text;
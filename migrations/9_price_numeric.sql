@@ -0,0 +1,2 @@
+-- Store prices with exact decimals, instead of as floats that turn 129.90 into 129.9000015
+alter table dish alter column price type numeric(10, 2);
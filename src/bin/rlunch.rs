@@ -1,11 +1,12 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use compact_str::CompactString;
 use rlunch::{
-    cache, cli, scrape,
-    web::{api, html},
+    cache, cli, metrics, scrape,
+    web::{admin, api, html},
 };
 use sqlx::PgPool;
-use tracing::{trace, warn};
+use std::{path::Path, time::Duration};
+use tracing::{error, trace, warn};
 
 // Use Jemalloc only for musl-64 bits platforms
 #[cfg(all(target_env = "musl", target_pointer_width = "64"))]
@@ -19,7 +20,7 @@ async fn main() -> Result<()> {
     }
 
     let c = cli::Cli::parse_args();
-    c.init_logger()?;
+    let _log_guard = c.init_logger()?;
 
     // just for testing log output during development
     // cli::test_tracing();
@@ -34,51 +35,178 @@ async fn main() -> Result<()> {
 // #[tracing::instrument]
 async fn dispatch_commands(c: cli::Cli) -> Result<()> {
     trace!("Checking args and running desired subcommand");
+    // commands not needing the DB
+    match c.command {
+        cli::Commands::Validate { request_timeout } => {
+            return scrape::validate(cache::Opts {
+                request_delay: Duration::ZERO,
+                request_timeout: request_timeout.into(),
+                cache_ttl: Duration::ZERO,
+                cache_ttl_overrides: vec![],
+                cache_capacity: 0,
+                cache_path: None,
+                cache_compression: cache::Compression::None,
+                cache_revalidate: false,
+                pool_max_idle_per_host: None,
+                pool_idle_timeout: None,
+            })
+            .await;
+        }
+        cli::Commands::Render {
+            ref template,
+            ref data,
+        } => return render_template(template, data),
+        _ => {}
+    }
     let pool = c.get_pg_pool().await?;
     match c.command {
         cli::Commands::Scrape {
             cron,
+            site,
+            timezone,
+            dish_history,
+            exit_code,
+            allow_no_scrapers,
+            ensure_hierarchy,
+            scrape_timeout,
+            scrape_retries,
+            shutdown_grace,
+            metrics_listen,
+            db_writers,
+            result_buffer,
+            max_dishes_per_restaurant,
             request_delay,
             request_timeout,
             cache_ttl,
+            cache_ttl_override,
             cache_capacity,
             cache_path,
+            cache_compression,
+            cache_revalidate,
+            pool_max_idle_per_host,
+            pool_idle_timeout,
         } => {
+            if let Some(addr) = metrics_listen {
+                tokio::spawn(async move {
+                    if let Err(err) = metrics::serve(&addr).await {
+                        error!(%err, "Metrics server failed");
+                    }
+                });
+            }
             scrape::run(
                 pool,
                 cron,
+                timezone,
+                exit_code,
+                allow_no_scrapers,
+                shutdown_grace.into(),
+                site,
+                scrape::ScraperOpts {
+                    timeout: scrape_timeout.into(),
+                    retries: scrape_retries,
+                },
+                scrape::DbOpts {
+                    dish_history: dish_history.filter(|&n| n > 0),
+                    writers: db_writers,
+                    result_buffer,
+                    ensure_hierarchy,
+                    max_dishes_per_restaurant: max_dishes_per_restaurant as usize,
+                },
                 cache::Opts {
                     request_delay: request_delay.into(),
                     request_timeout: request_timeout.into(),
                     cache_ttl: cache_ttl.into(),
+                    cache_ttl_overrides: cache_ttl_override,
                     cache_capacity,
                     cache_path,
+                    cache_compression: cache_compression.into(),
+                    cache_revalidate,
+                    pool_max_idle_per_host: Some(pool_max_idle_per_host),
+                    pool_idle_timeout: Some(pool_idle_timeout.into()),
                 },
             )
             .await?
         }
+        // handled above, before connecting to the DB
+        cli::Commands::Validate { .. } | cli::Commands::Render { .. } => {}
         cli::Commands::Serve { listen, commands } => match commands {
-            cli::ServeCommands::Json => run_server_json(pool, listen).await?,
-            cli::ServeCommands::Admin => run_server_admin(pool, listen).await?,
-            cli::ServeCommands::Html { gtag } => run_server_html(pool, listen, gtag).await?,
+            cli::ServeCommands::Json {
+                internal_token,
+                heavy_timeout,
+                no_metrics,
+                cors_allow_origin,
+                max_dishes_per_restaurant,
+            } => {
+                run_server_json(
+                    pool,
+                    listen,
+                    internal_token,
+                    heavy_timeout.into(),
+                    !no_metrics,
+                    cors_allow_origin,
+                    max_dishes_per_restaurant as usize,
+                )
+                .await?
+            }
+            cli::ServeCommands::Admin { admin_token } => {
+                run_server_admin(pool, listen, admin_token).await?
+            }
+            cli::ServeCommands::Html {
+                gtag,
+                stale_after,
+                country,
+            } => run_server_html(pool, listen, gtag, stale_after.into(), country).await?,
         },
     }
     Ok(())
 }
 
 // #[tracing::instrument]
-async fn run_server_json(pg: PgPool, addr: CompactString) -> Result<()> {
-    api::serve(pg, &addr).await
+async fn run_server_json(
+    pg: PgPool,
+    addr: CompactString,
+    internal_token: Option<CompactString>,
+    heavy_timeout: Duration,
+    enable_metrics: bool,
+    cors_origins: Vec<CompactString>,
+    max_dishes_per_restaurant: usize,
+) -> Result<()> {
+    api::serve(
+        pg,
+        &addr,
+        internal_token,
+        heavy_timeout,
+        enable_metrics,
+        &cors_origins,
+        max_dishes_per_restaurant,
+    )
+    .await
 }
 
 // #[tracing::instrument]
-async fn run_server_admin(_pg: PgPool, addr: CompactString) -> Result<()> {
-    warn!("TODO: Actually start ADMIN server on addr: {addr}");
-
-    Ok(())
+async fn run_server_admin(
+    pg: PgPool,
+    addr: CompactString,
+    admin_token: CompactString,
+) -> Result<()> {
+    admin::serve(pg, &addr, admin_token).await
 }
 
 // #[tracing::instrument]
-async fn run_server_html(pg: PgPool, addr: CompactString, gtag: CompactString) -> Result<()> {
-    html::serve(pg, &addr, gtag).await
+async fn run_server_html(
+    pg: PgPool,
+    addr: CompactString,
+    gtag: CompactString,
+    stale_after: Duration,
+    country: Option<CompactString>,
+) -> Result<()> {
+    html::serve(pg, &addr, gtag, stale_after, country).await
+}
+
+fn render_template(template: &str, data: &Path) -> Result<()> {
+    let data = std::fs::read_to_string(data)
+        .with_context(|| format!("failed to read {}", data.display()))?;
+    let data = serde_json::from_str(&data).context("invalid JSON in data file")?;
+    println!("{}", html::render_offline(template, data)?);
+    Ok(())
 }
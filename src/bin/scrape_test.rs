@@ -23,9 +23,14 @@ async fn main() -> Result<()> {
     let opts = cache::Opts {
         cache_path: Some("/tmp/scrape_cache.bin".into()),
         cache_capacity: 64,
+        cache_compression: cache::Compression::Zstd,
+        cache_revalidate: false,
         cache_ttl: Duration::from_secs(30),
+        cache_ttl_overrides: vec![],
         request_timeout: Duration::from_secs(5),
         request_delay: Duration::from_millis(1500),
+        pool_max_idle_per_host: Some(4),
+        pool_idle_timeout: Some(Duration::from_secs(30)),
     };
     let client = cache::Client::build(opts).await?;
     let scraper = scrapers::se::gbg::lh::LHScraper::new(client.clone(), Uuid::new_v4());
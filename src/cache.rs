@@ -1,24 +1,45 @@
 use http_cache_reqwest::{
-    Cache, CacheMode, HttpCache, HttpCacheOptions, MokaCache, MokaCacheBuilder, MokaManager,
+    Cache, CacheMode, CacheOptions, HttpCache, HttpCacheOptions, MokaCache, MokaCacheBuilder,
+    MokaManager,
 };
-use reqwest::IntoUrl;
+use moka::Expiry;
+use reqwest::{IntoUrl, Url};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::hash_map::RandomState,
+    collections::{hash_map::RandomState, HashMap},
     fs::File,
-    io::{BufReader, BufWriter, Write},
+    io::{BufRead, BufReader, BufWriter, Write},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
+use tokio::sync::OnceCell;
 use tracing::{debug, error, trace};
 
 static APP_USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36";
+// Header set by the cache middleware, with the value "HIT" when served from cache
+static HEADER_XCACHE: &str = "x-cache";
+static XCACHE_HIT: &str = "HIT";
 
 type MCache = MokaCache<String, Arc<Vec<u8>>, RandomState>;
 
+// Max number of distinct URLs that can have requests coalesced at the same time. Requests beyond
+// this just go ahead on their own, like without coalescing.
+const MAX_IN_FLIGHT: usize = 64;
+
+// Start of every zstd frame, for telling compressed cache files from old uncompressed ones
+const ZSTD_MAGIC: [u8; 4] = 0xFD2FB528u32.to_le_bytes();
+
+/// The shared result of a request that other requests for the same URL can wait for.
+/// The error is stringified, since it can't be cloned.
+type InFlight = Arc<OnceCell<Result<String, String>>>;
+
 #[derive(Serialize, Deserialize)]
 struct CacheEntry {
     key: String,
@@ -78,28 +99,129 @@ impl CacheBuilder {
     }
 
     /// Write whatever has been loaded in `from_cache` to the given file
-    fn save<P: AsRef<Path>>(self, path: P) -> bincode::Result<()> {
+    fn save<P: AsRef<Path>>(self, path: P, compression: Compression) -> bincode::Result<()> {
         let mut f = BufWriter::new(File::create(path)?);
-        let res = bincode::serialize_into(&mut f, &self.store);
+        let res = match compression {
+            Compression::None => bincode::serialize_into(&mut f, &self.store),
+            Compression::Zstd => {
+                let mut enc = zstd::Encoder::new(&mut f, 0)?;
+                bincode::serialize_into(&mut enc, &self.store)?;
+                enc.finish().map(|_| ()).map_err(bincode::Error::from)
+            }
+        };
         f.flush()?;
         res
     }
 
-    /// Used by Self::populate_cache to load file contents into a new cache
+    /// Used by Self::populate_cache to load file contents into a new cache.
+    /// Compressed files are detected by the zstd magic number, so uncompressed files from before
+    /// compression was added still load.
     fn load<P: AsRef<Path>>(&mut self, path: P) -> bincode::Result<()> {
-        let f = BufReader::new(File::open(path)?);
-        self.store = bincode::deserialize_from(f)?;
+        let mut f = BufReader::new(File::open(path)?);
+        self.store = if f.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+            bincode::deserialize_from(zstd::Decoder::with_buffer(f)?)?
+        } else {
+            bincode::deserialize_from(f)?
+        };
         Ok(())
     }
 }
 
+/// TTL for cached responses from URLs starting with the given prefix, instead of the default.
+/// Parsed from "PREFIX=DURATION", e.g. "https://example.com/menu=10m".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TtlOverride {
+    pub url_prefix: String,
+    pub ttl: Duration,
+}
+
+impl FromStr for TtlOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // split on the last '=', since URLs may contain them in the query string
+        let (prefix, ttl) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PREFIX=DURATION, got {s:?}"))?;
+        if prefix.is_empty() {
+            return Err(format!("empty URL prefix in {s:?}"));
+        }
+        let ttl = humantime::parse_duration(ttl).map_err(|e| format!("{e} in {s:?}"))?;
+        Ok(Self {
+            url_prefix: prefix.into(),
+            ttl,
+        })
+    }
+}
+
+/// Per entry expiration for the cache, using the TTL of the longest matching URL prefix, or the
+/// default TTL if none match.
+struct UrlExpiry {
+    default: Duration,
+    overrides: Vec<TtlOverride>,
+}
+
+impl UrlExpiry {
+    fn ttl_for(&self, key: &str) -> Duration {
+        // keys from the cache middleware are "METHOD:URL"
+        let url = key.split_once(':').map_or(key, |(_, url)| url);
+        self.overrides
+            .iter()
+            .filter(|o| url.starts_with(&o.url_prefix))
+            .max_by_key(|o| o.url_prefix.len())
+            .map_or(self.default, |o| o.ttl)
+    }
+}
+
+impl Expiry<String, Arc<Vec<u8>>> for UrlExpiry {
+    fn expire_after_create(
+        &self,
+        key: &String,
+        _value: &Arc<Vec<u8>>,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(self.ttl_for(key))
+    }
+
+    // restart the TTL on updates, like time_to_live does
+    fn expire_after_update(
+        &self,
+        key: &String,
+        _value: &Arc<Vec<u8>>,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.ttl_for(key))
+    }
+}
+
+/// How the cache file is compressed when saved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Opts {
     pub request_delay: Duration,
     pub request_timeout: Duration,
     pub cache_ttl: Duration,
+    /// TTLs to use instead of cache_ttl for URLs with the given prefixes.
+    /// Has no effect if cache_ttl is 0, since caching is then disabled.
+    pub cache_ttl_overrides: Vec<TtlOverride>,
     pub cache_capacity: usize,
     pub cache_path: Option<PathBuf>,
+    pub cache_compression: Compression,
+    /// Revalidate cached responses with the server when they're stale by their HTTP headers,
+    /// instead of using them as is until the TTL expires. See Opts::cache_mode.
+    pub cache_revalidate: bool,
+    /// Max idle connections kept open per host, for reuse by later requests.
+    /// None uses the reqwest default, which has no limit.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle connection is kept open. None uses the reqwest default of 90s.
+    pub pool_idle_timeout: Option<Duration>,
 }
 
 impl Opts {
@@ -108,32 +230,94 @@ impl Opts {
             // Disable caching alltogether if TTL is set to 0
             return CacheMode::NoStore;
         }
-        // Using Default does not work when offline.
-        // ForceCache works offline, and seems to work fine with TTL cache eviction as well
+        if self.cache_revalidate {
+            // Default follows the HTTP caching rules, sending If-None-Match/If-Modified-Since for
+            // stale responses, where a 304 counts as a hit and restarts the TTL. When the server
+            // can't be reached, the stale response is used, so it mostly works offline as well,
+            // except for responses marked must-revalidate, which then fail. Responses the server
+            // says not to store are fetched every time.
+            return CacheMode::Default;
+        }
+        // ForceCache ignores the HTTP caching rules and uses whatever is cached until the TTL
+        // expires, so it works offline, at the cost of not seeing changes until then
         CacheMode::ForceCache
     }
 
+    fn cache_options(&self) -> HttpCacheOptions {
+        if !self.cache_revalidate {
+            return HttpCacheOptions::default();
+        }
+        HttpCacheOptions {
+            // we're a single client, so responses marked private may be stored as well
+            cache_options: Some(CacheOptions {
+                shared: false,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
     fn build_cache(&self) -> MCache {
         MokaCacheBuilder::new(self.cache_capacity as u64)
             .name("LunchScraperCache")
-            .time_to_live(self.cache_ttl)
+            .expire_after(UrlExpiry {
+                default: self.cache_ttl,
+                overrides: self.cache_ttl_overrides.clone(),
+            })
             .build()
     }
 
     fn build_client(&self) -> reqwest::Result<reqwest::Client> {
-        reqwest::ClientBuilder::new()
+        let mut builder = reqwest::ClientBuilder::new()
             .user_agent(APP_USER_AGENT)
-            .timeout(self.request_timeout)
-            .build()
+            .timeout(self.request_timeout);
+        if let Some(n) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(n);
+        }
+        if let Some(d) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(d);
+        }
+        builder.build()
+    }
+}
+
+/// Counts of requests served from cache vs. the network, and the current size of the cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of entries currently in the cache
+    pub entries: u64,
+    /// Estimated size in bytes of the cached keys and response bodies
+    pub size_bytes: u64,
+}
+
+impl CacheStats {
+    /// Ratio of requests served from cache, 0.0 if no requests have been made
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
     }
 }
 
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
 #[derive(Clone)]
 pub struct Client {
     client: ClientWithMiddleware,
     cache: MCache,
     cache_path: Option<PathBuf>,
+    cache_compression: Compression,
     request_delay: Duration,
+    counters: Arc<Counters>,
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
 }
 
 impl Client {
@@ -152,12 +336,15 @@ impl Client {
                 .with(Cache(HttpCache {
                     mode: opts.cache_mode(),
                     manager: MokaManager::new(cache.clone()),
-                    options: HttpCacheOptions::default(),
+                    options: opts.cache_options(),
                 }))
                 .build(),
             cache,
             cache_path: opts.cache_path,
+            cache_compression: opts.cache_compression,
             request_delay: opts.request_delay,
+            counters: Default::default(),
+            in_flight: Default::default(),
         })
     }
 
@@ -165,12 +352,29 @@ impl Client {
         self.request_delay
     }
 
+    /// Get the number of cache hits and misses for requests made via get_as_string so far,
+    /// along with the number of entries and their estimated size.
+    /// This walks the whole cache, so it's meant for occasional use, not per request.
+    pub fn stats(&self) -> CacheStats {
+        let (entries, size_bytes) = self.cache.iter().fold((0, 0), |(n, size), (k, v)| {
+            (n + 1, size + (k.len() + v.len()) as u64)
+        });
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            entries,
+            size_bytes,
+        }
+    }
+
     /// Consume self and write cache contents to file for later loading, if a file path was set at
     /// build time
     pub async fn save(self) -> bincode::Result<()> {
         // try to save to file if a path is given
         match self.cache_path {
-            Some(p) => CacheBuilder::from_cache(self.cache).await.save(p),
+            Some(p) => CacheBuilder::from_cache(self.cache)
+                .await
+                .save(p, self.cache_compression),
             None => {
                 debug!("No cache file path set, unable to save");
                 Ok(())
@@ -179,15 +383,83 @@ impl Client {
     }
 
     /// Wrapper to make an HTTP GET request via the inner client instance, and get the body
-    /// contents as a String
+    /// contents as a String.
+    /// Concurrent requests for the same URL share a single request, since none of them would be
+    /// served from the cache before the first one is done. Those waiting count as cache hits.
     pub async fn get_as_string<U: IntoUrl>(&self, url: U) -> anyhow::Result<String> {
-        self.client
-            .get(url)
-            .send()
-            .await?
-            .text()
+        let url = url.into_url()?;
+        let key = url.to_string();
+        let cell = {
+            let mut in_flight = self.in_flight.lock().expect("in-flight map poisoned");
+            match in_flight.get(&key) {
+                Some(cell) => Some(cell.clone()),
+                None if in_flight.len() < MAX_IN_FLIGHT => {
+                    Some(in_flight.entry(key.clone()).or_default().clone())
+                }
+                None => None,
+            }
+        };
+        let Some(cell) = cell else {
+            return self.fetch(url).await;
+        };
+
+        let mut fetched = false;
+        let res = cell
+            .get_or_init(|| async {
+                fetched = true;
+                // later requests should go through the cache as usual, so the entry is removed
+                // when done, and also if this future is dropped before then, since that would
+                // otherwise leave the entry behind for good
+                let _guard = InFlightGuard {
+                    in_flight: &self.in_flight,
+                    key: &key,
+                    cell: &cell,
+                };
+                self.fetch(url).await.map_err(|e| format!("{e:#}"))
+            })
             .await
-            .map_err(anyhow::Error::from)
+            .clone();
+        if !fetched {
+            trace!(url = key, "Shared result with a concurrent request");
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        res.map_err(anyhow::Error::msg)
+    }
+
+    async fn fetch(&self, url: Url) -> anyhow::Result<String> {
+        let res = self.client.get(url).send().await?;
+        let hit = res
+            .headers()
+            .get(HEADER_XCACHE)
+            .is_some_and(|v| v == XCACHE_HIT);
+        if hit {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        res.text().await.map_err(anyhow::Error::from)
+    }
+}
+
+/// Removes an entry from the in-flight map when dropped, unless it has been replaced by another
+struct InFlightGuard<'a> {
+    in_flight: &'a Mutex<HashMap<String, InFlight>>,
+    key: &'a str,
+    cell: &'a InFlight,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        // not worth a panic while dropping
+        let Ok(mut in_flight) = self.in_flight.lock() else {
+            return;
+        };
+        if in_flight
+            .get(self.key)
+            .is_some_and(|c| Arc::ptr_eq(c, self.cell))
+        {
+            in_flight.remove(self.key);
+        }
     }
 }
 
@@ -199,3 +471,206 @@ impl Deref for Client {
         &self.client
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::FixtureServer;
+
+    async fn populated() -> MCache {
+        let cache: MCache = MokaCacheBuilder::new(16).build();
+        for i in 0..3 {
+            cache
+                .insert(
+                    format!("key{i}"),
+                    Arc::new(format!("value{i}").into_bytes()),
+                )
+                .await;
+        }
+        cache
+    }
+
+    async fn reloaded(path: &Path) -> HashMap<String, Vec<u8>> {
+        let cache = CacheBuilder::populate_cache(path, 16, MokaCacheBuilder::new(16).build()).await;
+        cache
+            .iter()
+            .map(|(k, v)| ((*k).clone(), (*v).clone()))
+            .collect()
+    }
+
+    async fn save_and_reload(compression: Compression) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.bin");
+        CacheBuilder::from_cache(populated().await)
+            .await
+            .save(&path, compression)
+            .unwrap();
+
+        let saved = std::fs::read(&path).unwrap();
+        assert_eq!(
+            saved.starts_with(&ZSTD_MAGIC),
+            compression == Compression::Zstd
+        );
+
+        let got = reloaded(&path).await;
+        assert_eq!(got.len(), 3);
+        for i in 0..3 {
+            assert_eq!(got[&format!("key{i}")], format!("value{i}").into_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn hit_ratio() {
+        let server = FixtureServer::start(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"))
+            .await
+            .unwrap();
+        let client = Client::build(Opts {
+            request_timeout: Duration::from_secs(10),
+            cache_ttl: Duration::from_secs(60),
+            cache_capacity: 16,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.stats().hit_ratio(), 0.0);
+
+        let url = server.url("se/gbg/lh/dagens-lunch.html");
+        client.get_as_string(&url).await.unwrap();
+        client.get_as_string(&url).await.unwrap();
+        let stats = client.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.hit_ratio(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn save_and_reload_compressed() {
+        save_and_reload(Compression::Zstd).await;
+    }
+
+    #[tokio::test]
+    async fn save_and_reload_uncompressed() {
+        save_and_reload(Compression::None).await;
+    }
+
+    #[tokio::test]
+    async fn load_old_format() {
+        // plain bincode, as written before compression was added
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.bin");
+        let store = vec![CacheEntry {
+            key: "key".into(),
+            value: b"value".to_vec(),
+        }];
+        bincode::serialize_into(File::create(&path).unwrap(), &store).unwrap();
+
+        let got = reloaded(&path).await;
+        assert_eq!(got.len(), 1);
+        assert_eq!(got["key"], b"value");
+    }
+
+    /// Serves a slow response on every path, counting the requests
+    async fn slow_server(hits: Arc<AtomicU64>) -> String {
+        use axum::{routing::get, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().fallback(get(move || async move {
+            hits.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            "menu"
+        }));
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{addr}/menu")
+    }
+
+    async fn uncached_client() -> Client {
+        Client::build(Opts {
+            request_timeout: Duration::from_secs(10),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Answers with the client port of each request, which differs per connection
+    async fn port_server() -> String {
+        use axum::{extract::ConnectInfo, routing::get, Router};
+        use std::net::SocketAddr;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().fallback(get(
+            |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.port().to_string() },
+        ));
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        format!("http://{addr}/port")
+    }
+
+    #[tokio::test]
+    async fn pool_settings() {
+        let url = port_server().await;
+        let ports = |opts| async {
+            let client = Client::build(opts).await.unwrap();
+            let first = client.get_as_string(&url).await.unwrap();
+            let second = client.get_as_string(&url).await.unwrap();
+            (first, second)
+        };
+        let opts = Opts {
+            request_timeout: Duration::from_secs(10),
+            pool_idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+
+        let (first, second) = ports(opts.clone()).await;
+        assert_eq!(first, second, "the connection should be reused");
+        let (first, second) = ports(Opts {
+            pool_max_idle_per_host: Some(0),
+            ..opts
+        })
+        .await;
+        assert_ne!(first, second, "no connection should be kept for reuse");
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_are_coalesced() {
+        let hits = Arc::new(AtomicU64::new(0));
+        let url = slow_server(hits.clone()).await;
+        let client = uncached_client().await;
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..10 {
+            let client = client.clone();
+            let url = url.clone();
+            tasks.spawn(async move { client.get_as_string(&url).await.unwrap() });
+        }
+        while let Some(res) = tasks.join_next().await {
+            assert_eq!(res.unwrap(), "menu");
+        }
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+        let stats = client.stats();
+        assert_eq!((stats.hits, stats.misses), (9, 1));
+        assert!(client.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_leaves_no_entry() {
+        let hits = Arc::new(AtomicU64::new(0));
+        let url = slow_server(hits.clone()).await;
+        let client = uncached_client().await;
+
+        let res = tokio::time::timeout(Duration::from_millis(50), client.get_as_string(&url)).await;
+        assert!(res.is_err());
+        assert!(client.in_flight.lock().unwrap().is_empty());
+
+        // and the next request goes ahead as usual
+        assert_eq!(client.get_as_string(&url).await.unwrap(), "menu");
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,14 +1,27 @@
-use anyhow::{Error, Result};
+use crate::{
+    cache::{self, TtlOverride},
+    scrape::SitePath,
+};
+use anyhow::{anyhow, bail, Error, Result};
 use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::{log::LevelFilter, ErrorLevel, Verbosity};
 use compact_str::CompactString;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
 use shadow_rs::shadow;
 use sqlx::{postgres::PgPoolOptions, PgPool};
-use std::{io, path::PathBuf};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tracing::Subscriber;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{
     filter::LevelFilter as TFilter,
-    fmt::{self, time::ChronoLocal},
+    fmt::{self, time::ChronoLocal, MakeWriter},
     layer::SubscriberExt,
+    registry::LookupSpan,
     util::SubscriberInitExt,
     EnvFilter, Layer,
 };
@@ -24,6 +37,56 @@ pub enum LogFormat {
     Json,
 }
 
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum CacheCompression {
+    None,
+    #[default]
+    Zstd,
+}
+
+impl From<CacheCompression> for cache::Compression {
+    fn from(c: CacheCompression) -> Self {
+        match c {
+            CacheCompression::None => cache::Compression::None,
+            CacheCompression::Zstd => cache::Compression::Zstd,
+        }
+    }
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(r: LogRotation) -> Self {
+        match r {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+        }
+    }
+}
+
+/// Flushes exported spans when dropped
+#[derive(Debug, Default)]
+pub struct LogGuard {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {e}");
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about, long_version = build::CLAP_LONG_VERSION, propagate_version = true)]
 pub struct Cli {
@@ -31,15 +94,44 @@ pub struct Cli {
     #[command(flatten)]
     pub verbosity: Verbosity<ErrorLevel>,
 
+    /// Only log errors, overriding both the verbosity flags and any RUST_LOG directives.
+    /// Unlike -q, which turns off logging completely, errors are still logged.
+    #[arg(long, env, global = true)]
+    pub errors_only: bool,
+
     /// Which log formatter to use.
     /// The value can also be picked up from env if the key in uppercase has a valid value.
     #[arg(short = 'f', long, env, default_value_t, value_enum)]
     pub log_format: LogFormat,
 
+    /// Also write logs to this file, in the same format as to stderr
+    #[arg(long, env)]
+    pub log_file: Option<PathBuf>,
+
+    /// Export spans to this OpenTelemetry collector, using OTLP over HTTP,
+    /// e.g. "http://localhost:4318/v1/traces".
+    /// Leave unset to not export spans.
+    #[arg(long, env)]
+    pub otlp_endpoint: Option<String>,
+
+    /// How often to start a new log file, when logging to file.
+    /// Rotated files get the date and hour appended to the name.
+    #[arg(long, env, default_value_t, value_enum)]
+    pub log_rotation: LogRotation,
+
     /// URL for Postgres database backend.
     /// The value can also be picked up from env if the key in uppercase has a valid value.
+    /// Required by all commands using the DB.
     #[arg(short, long, env)]
-    pub database_url: String,
+    pub database_url: Option<String>,
+
+    /// Max number of connections in the DB pool
+    #[arg(long, env, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub db_max_connections: u32,
+
+    /// How long to wait for a free DB connection, including connecting, before giving up
+    #[arg(long, env, default_value = "30s")]
+    pub db_connect_timeout: humantime::Duration,
 
     /// Subcommand to run
     #[command(subcommand)]
@@ -51,10 +143,76 @@ pub enum Commands {
     /// Start scraper manager
     Scrape {
         /// Cron spec for running scrapers.
-        /// Leave unset to run a one-off scrape.
+        /// Scrapers with a schedule of their own use that instead.
+        /// Leave unset to run a one-off scrape of all scrapers.
         #[arg(long)]
         cron: Option<CompactString>,
 
+        /// Only set up the scraper for this site, given as COUNTRY/CITY/SITE url_ids, e.g.
+        /// "se/gbg/lh", even if it's disabled in the DB. Without --cron, it's run just once.
+        #[arg(long)]
+        site: Option<SitePath>,
+
+        /// Timezone used for the cron schedule, and for deciding which day it is when scraping
+        /// menus that differ per weekday, e.g. "Europe/Stockholm".
+        /// Leave unset to use the local timezone of the host.
+        #[arg(long, env)]
+        timezone: Option<chrono_tz::Tz>,
+
+        /// Keep this many versions of each dish, instead of replacing all dishes on each scrape.
+        /// Dishes are matched by restaurant and dish name.
+        /// Leave unset or set to 0 to only keep the latest scrape.
+        #[arg(long, env)]
+        dish_history: Option<usize>,
+
+        /// Exit with an error if any scraper fails or returns no dishes.
+        /// Only applies to one-off scrapes, e.g. for smoke testing scrapers in CI.
+        #[arg(long)]
+        exit_code: bool,
+
+        /// Exit successfully if there are no scrapers to run, instead of failing with an error.
+        /// Only useful if all scrapers have been disabled on purpose.
+        #[arg(long)]
+        allow_no_scrapers: bool,
+
+        /// Insert any missing country, city and site rows for the scrapers before the first
+        /// scrape, instead of failing on startup. Inserted rows get their url_id as name.
+        #[arg(long)]
+        ensure_hierarchy: bool,
+
+        /// Fail a scraper run taking longer than this, including all its requests, so a hanging
+        /// site doesn't stall the others
+        #[arg(long, default_value = "60s")]
+        scrape_timeout: humantime::Duration,
+
+        /// Retry a failed scraper run this many times before giving up until the next run.
+        /// The delay between attempts starts at the request delay and doubles for each attempt.
+        #[arg(long, env, default_value_t = 2)]
+        scrape_retries: u32,
+
+        /// On shutdown, wait this long for scraper runs in progress to finish and have their
+        /// results written to the DB, before aborting them. Zero aborts them right away.
+        #[arg(long, env, default_value = "30s")]
+        shutdown_grace: humantime::Duration,
+
+        /// Serve scrape metrics in the Prometheus format at /metrics on this address, e.g.
+        /// "127.0.0.1:9090". Leave unset to not collect metrics.
+        #[arg(long, env)]
+        metrics_listen: Option<String>,
+
+        /// Max number of scrape results written to the DB concurrently
+        #[arg(long, default_value_t = 4)]
+        db_writers: usize,
+
+        /// How many scrape results can be queued up waiting for a DB writer
+        #[arg(long, default_value_t = 8)]
+        result_buffer: usize,
+
+        /// Ignore scraped restaurants with more dishes than this, keeping what they already have
+        /// in the DB, since it's most likely a broken parser rather than a real menu
+        #[arg(long, env, default_value_t = 200, value_parser = clap::value_parser!(u64).range(1..))]
+        max_dishes_per_restaurant: u64,
+
         /// How long to wait between requests to the same site.
         /// Useful to not get blocked for DDoS'ing target sites.
         #[arg(short = 'd', long, default_value = "1500ms")]
@@ -70,6 +228,12 @@ pub enum Commands {
         #[arg(short = 'l', long, default_value = "20m")]
         cache_ttl: humantime::Duration,
 
+        /// TTL for cached requests to URLs starting with a given prefix, instead of --cache-ttl,
+        /// as PREFIX=DURATION. The longest matching prefix wins.
+        /// Can be given multiple times.
+        #[arg(long, env, value_delimiter = ',')]
+        cache_ttl_override: Vec<TtlOverride>,
+
         /// Max items in cache.
         /// Adjust according to how many scrapers, and how many different page requests they make
         /// combined.
@@ -82,6 +246,44 @@ pub enum Commands {
         /// much.
         #[arg(short = 'p', long)]
         cache_path: Option<PathBuf>,
+
+        /// Compression for the cache file. Uncompressed files are still loaded either way.
+        #[arg(long, env, default_value_t, value_enum)]
+        cache_compression: CacheCompression,
+
+        /// Revalidate stale cached responses with the server, using ETag/Last-Modified, instead
+        /// of using them until the cache TTL expires.
+        /// Stale responses are still used if the server can't be reached, unless marked
+        /// must-revalidate.
+        #[arg(long, env)]
+        cache_revalidate: bool,
+
+        /// Max idle connections to keep open per host for reuse.
+        /// Scrapers making many requests to the same site, like address lookups, get a lot faster
+        /// when they don't have to connect for each request.
+        #[arg(long, default_value_t = 8)]
+        pool_max_idle_per_host: usize,
+
+        /// How long to keep idle connections open.
+        /// Should be longer than the request delay, for connections to actually be reused.
+        #[arg(long, default_value = "90s")]
+        pool_idle_timeout: humantime::Duration,
+    },
+    /// Check that the pages of all scrapers still have the elements the scrapers depend on, and
+    /// exit with an error if not. Nothing is written to the DB.
+    Validate {
+        /// How long to wait before timing out a request
+        #[arg(short = 't', long, default_value = "5s")]
+        request_timeout: humantime::Duration,
+    },
+    /// Render a template with the data from a JSON file as context, and print the result.
+    /// For working on the templates without a DB. Nothing is read from or written to the DB.
+    Render {
+        /// Name of the template, e.g. dishes_for_site.html
+        template: String,
+        /// JSON file with the template context, e.g. the site, currency_suffix and stale for
+        /// dishes_for_site.html
+        data: PathBuf,
     },
     /// Start a server
     Serve {
@@ -98,15 +300,53 @@ pub enum Commands {
 #[derive(Debug, Clone, Subcommand)]
 pub enum ServeCommands {
     /// Start a REST API JSON server
-    Json,
+    Json {
+        /// Bearer token required for the /internal/ routes.
+        /// Leave unset to disable them.
+        #[arg(long, env)]
+        internal_token: Option<CompactString>,
+
+        /// Timeout for routes that may return whole sites or more, like /list.
+        /// Other routes have a fixed timeout of 30s.
+        #[arg(long, default_value = "120s")]
+        heavy_timeout: humantime::Duration,
+
+        /// Don't serve metrics in the Prometheus format at /metrics
+        #[arg(long, env)]
+        no_metrics: bool,
+
+        /// Allow browsers on this origin to call the API, e.g. "https://lunch.example.com".
+        /// Can be given several times, or comma separated, and "*" allows any origin.
+        /// No CORS headers are sent if unset.
+        #[arg(long, env, value_delimiter = ',')]
+        cors_allow_origin: Vec<CompactString>,
+
+        /// Reject scrape results posted to /sites/{id}/scrape with restaurants having more dishes
+        /// than this, same as the scrape command ignores them
+        #[arg(long, env, default_value_t = 200, value_parser = clap::value_parser!(u64).range(1..))]
+        max_dishes_per_restaurant: u64,
+    },
     /// Start HTML web server
     Html {
         /// Address of the backend JSON server instance
         #[arg(short, long, default_value_t = CompactString::from(""))]
         gtag: CompactString,
+
+        /// Show a warning on a site's page if its menus haven't been updated for this long
+        #[arg(long, default_value = "24h")]
+        stale_after: humantime::Duration,
+
+        /// Only list sites in the country with this url_id on the index pages, e.g. "se".
+        /// Leave unset to list all sites.
+        #[arg(long, env)]
+        country: Option<CompactString>,
+    },
+    /// Start the admin server, for editing countries, cities, sites and restaurants
+    Admin {
+        /// Bearer token required for all requests
+        #[arg(long, env)]
+        admin_token: CompactString,
     },
-    /// Unimplemented
-    Admin,
 }
 
 impl Cli {
@@ -143,45 +383,161 @@ impl Cli {
         }
     }
 
-    /// Initialize logging via the tracing crate
-    pub fn init_logger(&self) -> Result<()> {
-        let layer = match self.log_format {
+    /// Level filter from the verbosity flags, or RUST_LOG if set, unless only errors are wanted
+    fn env_filter(&self) -> Result<EnvFilter> {
+        if self.errors_only {
+            return Ok(EnvFilter::default().add_directive(TFilter::ERROR.into()));
+        }
+        EnvFilter::builder()
+            .with_default_directive(self.tracing_level_filter().into())
+            .from_env()
+            .map_err(Error::from)
+    }
+
+    /// Build a log formatting layer for the chosen format, writing to the given writer
+    fn fmt_layer<S, W>(&self, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        match self.log_format {
             LogFormat::Json => fmt::layer()
                 .json()
-                .with_writer(io::stderr)
+                .with_writer(writer)
                 .with_timer(ChronoLocal::rfc_3339())
                 .boxed(),
             LogFormat::Pretty => fmt::layer()
                 .pretty()
-                .with_writer(io::stderr)
+                .with_ansi(ansi)
+                .with_writer(writer)
                 .with_timer(ChronoLocal::rfc_3339())
                 .boxed(),
             LogFormat::Compact => fmt::layer()
                 .without_time()
                 .compact()
-                .with_writer(io::stderr)
+                .with_ansi(ansi)
+                .with_writer(writer)
                 .boxed(),
             LogFormat::Normal => fmt::layer()
-                .with_writer(io::stderr)
+                .with_ansi(ansi)
+                .with_writer(writer)
                 .with_timer(ChronoLocal::rfc_3339())
                 .boxed(),
-        };
-        tracing_subscriber::registry()
-            .with(
-                EnvFilter::builder()
-                    .with_default_directive(self.tracing_level_filter().into())
-                    .from_env()?,
-            )
-            .with(layer)
-            .init();
-        Ok(())
+        }
+    }
+
+    /// Initialize logging via the tracing crate.
+    /// Keep the returned guard alive until exit, so that any pending spans are exported.
+    pub fn init_logger(&self) -> Result<LogGuard> {
+        let (subscriber, guard) = self.subscriber()?;
+        subscriber.init();
+        Ok(guard)
+    }
+
+    /// Build the subscriber with all configured layers, without setting it as the global default
+    fn subscriber(&self) -> Result<(impl Subscriber + Send + Sync + 'static, LogGuard)> {
+        let mut guard = LogGuard::default();
+        let mut layers = vec![self.fmt_layer(io::stderr, true)];
+        if let Some(ref endpoint) = self.otlp_endpoint {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()?;
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(env!("CARGO_PKG_NAME"))
+                        .build(),
+                )
+                .build();
+            layers.push(
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+                    .boxed(),
+            );
+            guard.tracer_provider = Some(provider);
+        }
+        if let Some(ref path) = self.log_file {
+            let dir = path
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("invalid log file path: {}", path.display()))?;
+            let appender = RollingFileAppender::builder()
+                .rotation(self.log_rotation.into())
+                .filename_prefix(name.to_string_lossy())
+                .build(dir)?;
+            layers.push(self.fmt_layer(appender, false));
+        }
+        let subscriber = tracing_subscriber::registry()
+            .with(self.env_filter()?)
+            .with(layers);
+        Ok((subscriber, guard))
     }
 
     pub async fn get_pg_pool(&self) -> Result<PgPool> {
+        if self.db_connect_timeout.is_zero() {
+            bail!("--db-connect-timeout must be larger than 0");
+        }
+        let Some(ref url) = self.database_url else {
+            bail!("--database-url is required for this command");
+        };
         PgPoolOptions::new()
-            .max_connections(20) // TODO: evaluate this value
-            .connect(&self.database_url)
+            .max_connections(self.db_max_connections)
+            .acquire_timeout(self.db_connect_timeout.into())
+            .connect(url)
             .await
             .map_err(Error::from)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["rlunch"].iter().chain(args).chain(&["scrape"]))
+    }
+
+    #[test]
+    fn log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rlunch.log");
+        let cli = cli(&["--log-file", path.to_str().unwrap()]);
+        let (subscriber, _guard) = cli.subscriber().unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("written to file");
+        });
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.contains("written to file"), "{log}");
+    }
+    #[test]
+    fn otlp_layer() {
+        let (_, guard) = cli(&[]).subscriber().unwrap();
+        assert!(guard.tracer_provider.is_none());
+
+        let cli = cli(&["--otlp-endpoint", "http://localhost:4318/v1/traces"]);
+        let (_, guard) = cli.subscriber().unwrap();
+        assert!(guard.tracer_provider.is_some());
+    }
+    #[test]
+    fn errors_only_overrides_rust_log() {
+        // no other test depends on RUST_LOG
+        std::env::set_var(EnvFilter::DEFAULT_ENV, "debug");
+        let enabled = |cli: Cli| {
+            let subscriber = tracing_subscriber::registry().with(cli.env_filter().unwrap());
+            tracing::subscriber::with_default(subscriber, || {
+                (
+                    tracing::enabled!(tracing::Level::INFO),
+                    tracing::enabled!(tracing::Level::ERROR),
+                )
+            })
+        };
+        assert_eq!(enabled(cli(&[])), (true, true));
+        assert_eq!(enabled(cli(&["--errors-only"])), (false, true));
+        std::env::remove_var(EnvFilter::DEFAULT_ENV);
+    }
+}
@@ -13,37 +13,125 @@
 // If anyone ever reads this and have an idea of how to do this, I'd be happy to hear it!
 
 use crate::{
-    models::{City, Country, Dish, LunchData, Restaurant, RestaurantRows, Site},
+    models::{
+        City, Country, Dish, DishRows, LunchData, Price, Restaurant, RestaurantRows, ScraperFlag,
+        ScraperStatus, Site, TagCount, UuidMap,
+    },
     scrape::ScrapeResult,
+    util,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local};
+use serde::Serialize;
 use sqlx::{Error, Executor, PgPool, Postgres};
-use std::time::Instant;
-use tracing::trace;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+use tracing::{debug, trace};
 use uuid::Uuid;
 
 pub type Transaction<'a> = sqlx::Transaction<'a, Postgres>;
 
-enum SiteKeyLevel {
+/// Channel used with Postgres NOTIFY whenever data has been updated, with the id of the updated
+/// site or country as payload
+pub const UPDATE_CHANNEL: &str = "lunch_update";
+
+/// Returned when inserting or renaming something would give it the same url_id as something else
+/// under the same parent, which would make lookups by key ambiguous
+#[derive(Debug, thiserror::Error)]
+#[error("conflict: a {what} with url_id {url_id} already exists")]
+pub struct UrlIdConflict {
+    pub what: &'static str,
+    pub url_id: String,
+}
+
+/// Unique violations on the url_id_key indexes happen when another insert with the same url_id gets
+/// in between checking with url_id_exists and writing, which is the same conflict. Other errors
+/// are passed on as is.
+fn url_id_conflict(err: Error, what: &'static str, url_id: &str) -> anyhow::Error {
+    match err {
+        Error::Database(ref e)
+            if e.is_unique_violation()
+                && e.constraint()
+                    .is_some_and(|c| c.ends_with("_url_id_key_unique")) =>
+        {
+            UrlIdConflict {
+                what,
+                url_id: url_id.to_string(),
+            }
+            .into()
+        }
+        err => err.into(),
+    }
+}
+
+/// Levels of the hierarchy that can be addressed by url_id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteKeyLevel {
     Empty,
     Country,
     City,
     Site,
+    Restaurant,
+}
+
+// Newtypes for the url_id of each level, so that SiteKey::new won't accept them in the wrong order.
+macro_rules! url_id_type {
+    ($name:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name<'a>(pub &'a str);
+
+        impl<'a> $name<'a> {
+            pub fn as_str(&self) -> &'a str {
+                self.0
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.0.is_empty()
+            }
+        }
+
+        impl<'a> From<&'a str> for $name<'a> {
+            fn from(v: &'a str) -> Self {
+                Self(v)
+            }
+        }
+    };
 }
 
+url_id_type!(CountryUrlId);
+url_id_type!(CityUrlId);
+url_id_type!(SiteUrlId);
+url_id_type!(RestaurantUrlId);
+
 #[derive(Debug)]
 pub struct SiteKey<'a> {
-    pub country_url_id: &'a str,
-    pub city_url_id: &'a str,
-    pub site_url_id: &'a str,
+    pub country_url_id: CountryUrlId<'a>,
+    pub city_url_id: CityUrlId<'a>,
+    pub site_url_id: SiteUrlId<'a>,
+    pub restaurant_url_id: RestaurantUrlId<'a>,
 }
 
 impl<'a> SiteKey<'a> {
-    pub fn new(country_url_id: &'a str, city_url_id: &'a str, site_url_id: &'a str) -> Self {
+    pub fn new(
+        country_url_id: CountryUrlId<'a>,
+        city_url_id: CityUrlId<'a>,
+        site_url_id: SiteUrlId<'a>,
+    ) -> Self {
         Self {
             country_url_id,
             city_url_id,
             site_url_id,
+            restaurant_url_id: RestaurantUrlId(""),
+        }
+    }
+
+    /// Extend the key down to a restaurant within the site
+    pub fn with_restaurant(self, restaurant_url_id: RestaurantUrlId<'a>) -> Self {
+        Self {
+            restaurant_url_id,
+            ..self
         }
     }
 
@@ -51,6 +139,12 @@ impl<'a> SiteKey<'a> {
         if !self.country_url_id.is_empty()
             && !self.city_url_id.is_empty()
             && !self.site_url_id.is_empty()
+            && !self.restaurant_url_id.is_empty()
+        {
+            return SiteKeyLevel::Restaurant;
+        } else if !self.country_url_id.is_empty()
+            && !self.city_url_id.is_empty()
+            && !self.site_url_id.is_empty()
         {
             return SiteKeyLevel::Site;
         } else if !self.country_url_id.is_empty() && !self.city_url_id.is_empty() {
@@ -62,12 +156,13 @@ impl<'a> SiteKey<'a> {
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq, sqlx::FromRow)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, sqlx::FromRow)]
 #[sqlx(default)]
 pub struct SiteRelation {
     pub country_id: Uuid,
     pub city_id: Uuid,
     pub site_id: Uuid,
+    pub restaurant_id: Uuid,
 }
 
 impl SiteRelation {
@@ -76,6 +171,31 @@ impl SiteRelation {
     }
 }
 
+/// Connection pool utilization, for figuring out a sensible max_connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PoolStats {
+    /// Open connections, both idle and in use
+    pub size: u32,
+    pub idle: usize,
+    /// Configured max_connections
+    pub max: u32,
+}
+
+pub fn pool_stats(pg: &PgPool) -> PoolStats {
+    PoolStats {
+        size: pg.size(),
+        idle: pg.num_idle(),
+        max: pg.options().get_max_connections(),
+    }
+}
+
+/// Check that the DB answers, returning how long it took
+pub async fn ping(pg: &PgPool) -> Result<Duration, Error> {
+    let start = Instant::now();
+    sqlx::query("select 1").execute(pg).await?;
+    Ok(start.elapsed())
+}
+
 // this signature is taken from https://github.com/launchbadge/sqlx/issues/419
 // Unfortunately it doesn't work to use the executor more than once within the same
 // function, since the value is moved.
@@ -88,20 +208,40 @@ where
     trace!(?key, "Searching for site relation...");
 
     let rel: SiteRelation = match key.level() {
+        SiteKeyLevel::Restaurant => {
+            sqlx::query_as(
+                r#"
+                    with co as (
+                        select country_id from country where url_id_key(url_id) = url_id_key($1)
+                    ), ci as (
+                        select city_id from city, co where city.country_id = co.country_id and url_id_key(url_id) = url_id_key($2)
+                    ), si as (
+                        select site_id from site, ci where site.city_id = ci.city_id and url_id_key(url_id) = url_id_key($3)
+                    )
+                    select co.country_id, ci.city_id, si.site_id, restaurant_id from co, ci, si, restaurant where restaurant.site_id = si.site_id and url_id_key(url_id) = url_id_key($4);
+                "#,
+            )
+            .bind(key.country_url_id.as_str())
+            .bind(key.city_url_id.as_str())
+            .bind(key.site_url_id.as_str())
+            .bind(key.restaurant_url_id.as_str())
+            .fetch_one(executor)
+            .await?
+        }
         SiteKeyLevel::Site => {
             sqlx::query_as(
                 r#"
                     with co as (
-                        select country_id from country where url_id = $1
+                        select country_id from country where url_id_key(url_id) = url_id_key($1)
                     ), ci as (
-                        select city_id from city, co where city.country_id = co.country_id and url_id = $2
+                        select city_id from city, co where city.country_id = co.country_id and url_id_key(url_id) = url_id_key($2)
                     )
-                    select co.country_id, ci.city_id, site_id from co, ci, site where site.city_id = ci.city_id and url_id = $3;
+                    select co.country_id, ci.city_id, site_id from co, ci, site where site.city_id = ci.city_id and url_id_key(url_id) = url_id_key($3);
                 "#,
             )
-            .bind(key.country_url_id)
-            .bind(key.city_url_id)
-            .bind(key.site_url_id)
+            .bind(key.country_url_id.as_str())
+            .bind(key.city_url_id.as_str())
+            .bind(key.site_url_id.as_str())
             .fetch_one(executor)
             .await?
         }
@@ -109,23 +249,23 @@ where
             sqlx::query_as(
                 r#"
                     with co as (
-                        select country_id from country where url_id = $1
+                        select country_id from country where url_id_key(url_id) = url_id_key($1)
                     )
-                    select co.country_id, city_id, '00000000-0000-0000-0000-000000000000' from co, city where city.country_id = co.country_id and url_id = $2
+                    select co.country_id, city_id, '00000000-0000-0000-0000-000000000000' from co, city where city.country_id = co.country_id and url_id_key(url_id) = url_id_key($2)
                 "#,
             )
-            .bind(key.country_url_id)
-            .bind(key.city_url_id)
+            .bind(key.country_url_id.as_str())
+            .bind(key.city_url_id.as_str())
             .fetch_one(executor)
             .await?
         }
         SiteKeyLevel::Country => {
             sqlx::query_as(
                 r#"
-                    select country_id, '00000000-0000-0000-0000-000000000000', '00000000-0000-0000-0000-000000000000' from country where url_id = $1
+                    select country_id, '00000000-0000-0000-0000-000000000000', '00000000-0000-0000-0000-000000000000' from country where url_id_key(url_id) = url_id_key($1)
                 "#,
             )
-            .bind(key.country_url_id)
+            .bind(key.country_url_id.as_str())
             .fetch_one(executor)
             .await?
         }
@@ -143,19 +283,233 @@ where
     Ok(rel)
 }
 
-pub async fn get_countries<'e, E>(ex: E) -> Result<Vec<Country>, Error>
+/// Like get_site_relation, but inserts any missing country, city or site rows for the given key
+/// first, so that a scraper can run against a fresh DB.
+/// Inserted rows get their url_id as name, which can be changed later with replace_country.
+pub async fn ensure_site_relation(pg: &PgPool, key: SiteKey<'_>) -> Result<SiteRelation> {
+    if !matches!(key.level(), SiteKeyLevel::Site) {
+        bail!("not a site level key: {key:?}");
+    }
+
+    let mut tx = pg.begin().await?;
+    let country_id = ensure_country(&mut tx, key.country_url_id).await?;
+    let city_id = ensure_city(&mut tx, country_id, key.city_url_id).await?;
+    let site_id = ensure_site(&mut tx, city_id, key.site_url_id).await?;
+    tx.commit().await?;
+
+    Ok(SiteRelation {
+        country_id,
+        city_id,
+        site_id,
+        ..Default::default()
+    })
+}
+
+/// Returns the id of the country with the given url_id, inserting it if missing
+async fn ensure_country(tx: &mut Transaction<'_>, url_id: CountryUrlId<'_>) -> Result<Uuid, Error> {
+    let id: Option<Uuid> = sqlx::query_scalar(
+        "select country_id from country where url_id_key(url_id) = url_id_key($1)",
+    )
+    .bind(url_id.as_str())
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    trace!(url_id = url_id.as_str(), "Inserting missing country");
+    sqlx::query_scalar(
+        r#"
+            insert into country (name, url_id) values ($1, $1) returning country_id
+        "#,
+    )
+    .bind(url_id.as_str())
+    .fetch_one(&mut **tx)
+    .await
+}
+
+/// Returns the id of the city with the given url_id within the country, inserting it if missing
+async fn ensure_city(
+    tx: &mut Transaction<'_>,
+    country_id: Uuid,
+    url_id: CityUrlId<'_>,
+) -> Result<Uuid, Error> {
+    let id: Option<Uuid> = sqlx::query_scalar(
+        "select city_id from city where country_id = $1 and url_id_key(url_id) = url_id_key($2)",
+    )
+    .bind(country_id)
+    .bind(url_id.as_str())
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    trace!(%country_id, url_id = url_id.as_str(), "Inserting missing city");
+    sqlx::query_scalar(
+        r#"
+            insert into city (country_id, name, url_id) values ($1, $2, $2) returning city_id
+        "#,
+    )
+    .bind(country_id)
+    .bind(url_id.as_str())
+    .fetch_one(&mut **tx)
+    .await
+}
+
+/// Whether there's already a row with the given url_id at the given level, under the given parent.
+/// The parent is the country for cities, the city for sites and the site for restaurants, and is
+/// ignored for countries.
+/// Lookups by key expect url_ids to be unique within their parent, so this should be checked
+/// before inserting anything with a url_id that isn't derived from an existing row.
+pub async fn url_id_exists<'e, E>(
+    ex: E,
+    level: SiteKeyLevel,
+    parent_id: Uuid,
+    url_id: &str,
+) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let query = match level {
+        // nothing can be looked up by an empty key, so there's nothing to collide with
+        SiteKeyLevel::Empty => return Ok(false),
+        SiteKeyLevel::Country => {
+            return sqlx::query_scalar("select exists (select 1 from country where url_id_key(url_id) = url_id_key($1))")
+                .bind(url_id)
+                .fetch_one(ex)
+                .await;
+        }
+        SiteKeyLevel::City => {
+            "select exists (select 1 from city where country_id = $1 and url_id_key(url_id) = url_id_key($2))"
+        }
+        SiteKeyLevel::Site => {
+            "select exists (select 1 from site where city_id = $1 and url_id_key(url_id) = url_id_key($2))"
+        }
+        SiteKeyLevel::Restaurant => {
+            "select exists (select 1 from restaurant where site_id = $1 and url_id_key(url_id) = url_id_key($2))"
+        }
+    };
+    sqlx::query_scalar(query)
+        .bind(parent_id)
+        .bind(url_id)
+        .fetch_one(ex)
+        .await
+}
+
+/// Insert a new city in the given country, failing if the url_id is already taken there.
+/// Returns the id of the inserted city.
+pub async fn insert_city(tx: &mut Transaction<'_>, country_id: Uuid, city: &City) -> Result<Uuid> {
+    if url_id_exists(&mut **tx, SiteKeyLevel::City, country_id, &city.url_id).await? {
+        return Err(UrlIdConflict {
+            what: "city",
+            url_id: city.url_id.clone(),
+        }
+        .into());
+    }
+    sqlx::query_scalar(
+        r#"
+            insert into city (country_id, name, url_id) values ($1, $2, $3) returning city_id
+        "#,
+    )
+    .bind(country_id)
+    .bind(&city.name)
+    .bind(&city.url_id)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "city", &city.url_id))
+}
+
+/// Insert a new site in the given city, failing if the url_id is already taken there.
+/// Returns the id of the inserted site.
+pub async fn insert_site(tx: &mut Transaction<'_>, city_id: Uuid, site: &Site) -> Result<Uuid> {
+    if url_id_exists(&mut **tx, SiteKeyLevel::Site, city_id, &site.url_id).await? {
+        return Err(UrlIdConflict {
+            what: "site",
+            url_id: site.url_id.clone(),
+        }
+        .into());
+    }
+    sqlx::query_scalar(
+        r#"
+            insert into site (city_id, name, url_id, comment, currency_suffix) values ($1, $2, $3, $4, $5) returning site_id
+        "#,
+    )
+    .bind(city_id)
+    .bind(&site.name)
+    .bind(&site.url_id)
+    .bind(&site.comment)
+    .bind(&site.currency_suffix)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "site", &site.url_id))
+}
+
+/// Returns the id of the site with the given url_id within the city, inserting it if missing
+async fn ensure_site(
+    tx: &mut Transaction<'_>,
+    city_id: Uuid,
+    url_id: SiteUrlId<'_>,
+) -> Result<Uuid, Error> {
+    let id: Option<Uuid> = sqlx::query_scalar(
+        "select site_id from site where city_id = $1 and url_id_key(url_id) = url_id_key($2)",
+    )
+    .bind(city_id)
+    .bind(url_id.as_str())
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    trace!(%city_id, url_id = url_id.as_str(), "Inserting missing site");
+    sqlx::query_scalar(
+        r#"
+            insert into site (city_id, name, url_id) values ($1, $2, $2) returning site_id
+        "#,
+    )
+    .bind(city_id)
+    .bind(url_id.as_str())
+    .fetch_one(&mut **tx)
+    .await
+}
+
+/// Postgres has no unsigned ints, so anything bigger is clamped instead of wrapping around to a
+/// negative, and invalid, limit or offset
+fn saturating_i64(n: usize) -> i64 {
+    i64::try_from(n).unwrap_or(i64::MAX)
+}
+
+/// Get countries by name, skipping the first `offset`, and at most `limit` of them, if given.
+/// The id breaks ties between equal names, keeping the order stable between pages.
+pub async fn get_countries<'e, E>(
+    ex: E,
+    limit: Option<usize>,
+    offset: usize,
+) -> Result<Vec<Country>, Error>
 where
     E: Executor<'e, Database = Postgres>,
 {
+    // a null limit is the same as no limit in Postgres
     sqlx::query_as(
         r#"
             select * from country
+                order by name, country_id
+                limit $1 offset $2
         "#,
     )
+    .bind(limit.map(saturating_i64))
+    .bind(saturating_i64(offset))
     .fetch_all(ex)
     .await
 }
 
+pub async fn count_countries<'e, E>(ex: E) -> Result<i64, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_scalar("select count(*) from country")
+        .fetch_one(ex)
+        .await
+}
+
 pub async fn get_country<'e, E>(ex: E, country_id: Uuid) -> Result<Country, Error>
 where
     E: Executor<'e, Database = Postgres>,
@@ -198,6 +552,25 @@ where
     .await
 }
 
+/// All cities, by name, without sites. If a country url_id is given, only cities in that country
+/// are included.
+pub async fn get_all_cities<'e, E>(ex: E, country: CountryUrlId<'_>) -> Result<Vec<City>, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as(
+        r#"
+            select ci.* from city ci
+                join country co on co.country_id = ci.country_id
+                where $1 = '' or url_id_key(co.url_id) = url_id_key($1)
+                order by ci.name
+        "#,
+    )
+    .bind(country.as_str())
+    .fetch_all(ex)
+    .await
+}
+
 pub async fn get_site<'e, E>(ex: E, site_id: Uuid) -> Result<Site, Error>
 where
     E: Executor<'e, Database = Postgres>,
@@ -283,10 +656,12 @@ where
                 dish_name,
                 description,
                 comment,
-                string_to_array(tags, ',') as tags,
+                tags,
                 price,
+                source_url,
                 created_at
                 from dish where restaurant_id = $1
+                and created_at = (select max(created_at) from dish d where d.restaurant_id = dish.restaurant_id)
                 group by dish_id
         "#,
     )
@@ -318,10 +693,12 @@ where
                 dish_name,
                 description,
                 comment,
-                string_to_array(tags, ',') as tags,
+                tags,
                 price,
+                source_url,
                 created_at
                 from dish where restaurant_id in (select unnest($1::uuid[]))
+                and created_at = (select max(created_at) from dish d where d.restaurant_id = dish.restaurant_id)
                 group by dish_id
         "#,
     )
@@ -330,9 +707,14 @@ where
     .await
 }
 
-pub async fn list_countries(pg: &PgPool) -> Result<LunchData, Error> {
+/// List countries without cities, see get_countries for the paging
+pub async fn list_countries(
+    pg: &PgPool,
+    limit: Option<usize>,
+    offset: usize,
+) -> Result<LunchData, Error> {
     // we don't need a transaction here, since we only make a single query
-    Ok(LunchData::new().with_countries(get_countries(pg).await?))
+    Ok(LunchData::new().with_countries(get_countries(pg, limit, offset).await?))
 }
 
 pub async fn list_cities_for_country_by_id(
@@ -372,28 +754,52 @@ pub async fn list_sites_for_city_by_key(
     list_sites_for_city_by_id(tx, city_id).await
 }
 
-pub async fn list_all_sites(tx: &mut Transaction<'_>) -> Result<LunchData, Error> {
+/// List all sites with their parents, but without restaurants.
+/// The country and city url_ids in the filter limit the result to that country or city, if set,
+/// while the site url_id is ignored. Sites are taken by name, skipping the first `offset`, and
+/// only `limit` of them are included, if given.
+pub async fn list_all_sites(
+    tx: &mut Transaction<'_>,
+    filter: SiteKey<'_>,
+    limit: Option<usize>,
+    offset: usize,
+) -> Result<LunchData, Error> {
+    // a null limit is the same as no limit in Postgres
     let sites: Vec<Site> = sqlx::query_as(
         r#"
-            select * from site
+            select si.* from site si
+                join city ci on ci.city_id = si.city_id
+                join country co on co.country_id = ci.country_id
+                where ($1 = '' or url_id_key(co.url_id) = url_id_key($1)) and ($2 = '' or url_id_key(ci.url_id) = url_id_key($2))
+                order by si.name, si.site_id
+                limit $3 offset $4
         "#,
     )
+    .bind(filter.country_url_id.as_str())
+    .bind(filter.city_url_id.as_str())
+    .bind(limit.map(saturating_i64))
+    .bind(saturating_i64(offset))
     .fetch_all(&mut **tx)
     .await?;
 
     let cities: Vec<City> = sqlx::query_as(
         r#"
-            select * from city
+            select ci.* from city ci
+                join country co on co.country_id = ci.country_id
+                where ($1 = '' or url_id_key(co.url_id) = url_id_key($1)) and ($2 = '' or url_id_key(ci.url_id) = url_id_key($2))
         "#,
     )
+    .bind(filter.country_url_id.as_str())
+    .bind(filter.city_url_id.as_str())
     .fetch_all(&mut **tx)
     .await?;
 
     let countries: Vec<Country> = sqlx::query_as(
         r#"
-            select * from country
+            select * from country where $1 = '' or url_id_key(url_id) = url_id_key($1)
         "#,
     )
+    .bind(filter.country_url_id.as_str())
     .fetch_all(&mut **tx)
     .await?;
 
@@ -406,6 +812,25 @@ pub async fn list_all_sites(tx: &mut Transaction<'_>) -> Result<LunchData, Error
     ))
 }
 
+/// Count the sites list_all_sites would return without a limit
+pub async fn count_sites<'e, E>(ex: E, filter: SiteKey<'_>) -> Result<i64, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_scalar(
+        r#"
+            select count(*) from site si
+                join city ci on ci.city_id = si.city_id
+                join country co on co.country_id = ci.country_id
+                where ($1 = '' or url_id_key(co.url_id) = url_id_key($1)) and ($2 = '' or url_id_key(ci.url_id) = url_id_key($2))
+        "#,
+    )
+    .bind(filter.country_url_id.as_str())
+    .bind(filter.city_url_id.as_str())
+    .fetch_one(ex)
+    .await
+}
+
 pub async fn list_restaurants_for_site_by_id(
     tx: &mut Transaction<'_>,
     site_id: Uuid,
@@ -442,13 +867,13 @@ pub async fn list_dishes_for_restaurant_by_id(
     ))
 }
 
-// We skip this implementation for now, since there's currently no support for levels below site in
-// SiteKey or SiteRelation
-// pub async fn list_dishes_for_restaurant_by_key(
-//     tx: &mut Transaction<'_>,
-//     key: SiteKey<'_>,
-// ) -> Result<LunchData> {
-// }
+pub async fn list_dishes_for_restaurant_by_key(
+    tx: &mut Transaction<'_>,
+    key: SiteKey<'_>,
+) -> Result<LunchData, Error> {
+    let restaurant_id = get_site_relation(&mut **tx, key).await?.restaurant_id;
+    list_dishes_for_restaurant_by_id(tx, restaurant_id).await
+}
 
 pub async fn list_dishes_for_site_by_id(
     tx: &mut Transaction<'_>,
@@ -465,6 +890,53 @@ pub async fn list_dishes_for_site_by_id(
     ))
 }
 
+/// Same result as list_dishes_for_site_by_id, but built by Postgres as JSON in a single query,
+/// instead of one query per level.
+/// Each level is a JSON object keyed by id, which is how UuidMap deserializes. Column names that
+/// differ from the field names are added under the field names, and the extra keys are ignored.
+pub async fn list_dishes_for_site_by_id_agg(
+    tx: &mut Transaction<'_>,
+    site_id: Uuid,
+) -> Result<LunchData, Error> {
+    let data: sqlx::types::Json<LunchData> = sqlx::query_scalar(
+        r#"
+            with dishes as (
+                select restaurant_id, jsonb_object_agg(dish_id, to_jsonb(dish) || jsonb_build_object(
+                    'name', dish_name
+                )) as dishes
+                from dish
+                where restaurant_id in (select restaurant_id from restaurant where site_id = $1)
+                    and created_at = (select max(created_at) from dish d where d.restaurant_id = dish.restaurant_id)
+                group by restaurant_id
+            ), restaurants as (
+                select jsonb_object_agg(r.restaurant_id, to_jsonb(r) || jsonb_build_object(
+                    'name', r.restaurant_name,
+                    'parsed_at', r.created_at,
+                    'dishes', coalesce(d.dishes, '{}')
+                )) as restaurants
+                from restaurant r left join dishes d using (restaurant_id)
+                where r.site_id = $1
+            )
+            select jsonb_build_object('countries', jsonb_build_object(co.country_id, to_jsonb(co) || jsonb_build_object(
+                'cities', jsonb_build_object(ci.city_id, to_jsonb(ci) || jsonb_build_object(
+                    'sites', jsonb_build_object(si.site_id, to_jsonb(si) || jsonb_build_object(
+                        'restaurants', coalesce(restaurants.restaurants, '{}')
+                    ))
+                ))
+            )))
+            from site si
+            join city ci on ci.city_id = si.city_id
+            join country co on co.country_id = ci.country_id
+            cross join restaurants
+            where si.site_id = $1
+        "#,
+    )
+    .bind(site_id)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(data.0)
+}
+
 pub async fn list_dishes_for_site_by_key(
     tx: &mut Transaction<'_>,
     key: SiteKey<'_>,
@@ -473,65 +945,1501 @@ pub async fn list_dishes_for_site_by_key(
     list_dishes_for_site_by_id(tx, site_id).await
 }
 
-// I'm evaluating if I should write a "list_all" function as well, to get everything in the DB into a
-// LunchData instance, but that might be a bad idea if the DB gets big.
-// Let's wait and see of there's any need for it at some point.
-
-pub async fn update_site(pg: &PgPool, update: ScrapeResult) -> Result<(), Error> {
-    trace!(site_id = %update.site_id, "Adding {} restaurants and {} dishes to DB", update.num_restaurants(), update.num_dishes());
-
-    let start = Instant::now();
-    // convert to format suitable for use with unnest
-    let rs = RestaurantRows::from(update.restaurants);
-    let duration = start.elapsed();
-    trace!("Conversion to DB format done in {:?}", duration);
-
-    // we need a transaction to ensure these operations are done atomically
-    let mut tx = pg.begin().await?;
+/// Get what prices should be rounded to for the country the given site is in, if set
+pub async fn get_price_rounding<'e, E>(ex: E, site_id: Uuid) -> Result<Option<f32>, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_scalar(
+        r#"
+            select country.price_rounding
+                from site
+                join city using (city_id)
+                join country using (country_id)
+                where site_id = $1
+        "#,
+    )
+    .bind(site_id)
+    .fetch_optional(ex)
+    .await
+    .map(Option::flatten)
+}
 
-    let start = Instant::now();
-    // first, clear out all restaurants and their dishes, so that we don't have any stale data
-    // lingering. We have "on delete cascade" for dishes, so we just need to delete the parent
-    // restaurants to get rid of all.
-    sqlx::query!("delete from restaurant where site_id = $1", update.site_id)
-        .execute(&mut *tx)
-        .await?;
+/// Count how many dishes have each tag, either for the given site, or for all sites if None
+pub async fn list_tags<'e, E>(ex: E, site_id: Option<Uuid>) -> Result<Vec<TagCount>, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as(
+        r#"
+            select tag, count(*) as count
+                from dish
+                join restaurant using (restaurant_id)
+                cross join lateral unnest(dish.tags) as t(tag)
+                where tag <> '' and ($1::uuid is null or restaurant.site_id = $1)
+                and dish.created_at = (select max(created_at) from dish d where d.restaurant_id = dish.restaurant_id)
+                group by tag
+                order by count desc, tag
+        "#,
+    )
+    .bind(site_id)
+    .fetch_all(ex)
+    .await
+}
 
-    // insert all restaurants
-    sqlx::query!(
+/// Search the current dishes, either for the given site, or for all sites if None.
+/// The result only has the matching dishes, along with their restaurants and parents, so empty
+/// levels are left out. Matching is full text over name, description and comment, ignoring case and
+/// accents, and all words in the query must be present. See migrations/14_dish_search.sql for the
+/// index, which the expression here must match in order to be used.
+pub async fn search_dishes(
+    tx: &mut Transaction<'_>,
+    query: &str,
+    site_id: Option<Uuid>,
+) -> Result<LunchData, Error> {
+    let dishes: Vec<Dish> = sqlx::query_as(
         r#"
-            insert into restaurant (site_id, restaurant_id, restaurant_name, comment, address, url, map_url, created_at)
-            select * from unnest($1::uuid[], $2::uuid[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[], $8::timestamptz[])
+            select
+                dish_id,
+                restaurant_id,
+                dish_name,
+                description,
+                comment,
+                tags,
+                price,
+                source_url,
+                created_at
+                from dish
+                where dish_search_vector(dish_name, description, comment)
+                    @@ plainto_tsquery('simple', immutable_unaccent($1))
+                and ($2::uuid is null or restaurant_id in (select restaurant_id from restaurant where site_id = $2))
+                and created_at = (select max(created_at) from dish d where d.restaurant_id = dish.restaurant_id)
         "#,
-        &rs.site_ids[..],
-        &rs.restaurant_ids[..],
-        &rs.names[..],
-        &rs.comments as &[Option<String>],
-        &rs.addresses as &[Option<String>],
-        &rs.urls as &[Option<String>],
-        &rs.map_urls as &[Option<String>],
-        &rs.parsed_ats[..],
     )
-    .execute(&mut *tx)
+    .bind(query)
+    .bind(site_id)
+    .fetch_all(&mut **tx)
     .await?;
 
-    // insert all dishes
-    sqlx::query!(
-        r#"
-            insert into dish (restaurant_id, dish_id, dish_name, description, comment, price, tags)
-            select * from unnest($1::uuid[], $2::uuid[], $3::text[], $4::text[], $5::text[], $6::real[], $7::text[])
-        "#,
-        &rs.dishes.restaurant_ids[..],
-        &rs.dishes.dish_ids[..],
-        &rs.dishes.names[..],
-        &rs.dishes.descriptions as &[Option<String>],
-        &rs.dishes.comments as &[Option<String>],
-        &rs.dishes.prices[..],
-        &rs.dishes.tags[..],
-    ).execute(&mut *tx).await?;
-    let duration = start.elapsed();
+    let restaurant_ids: Vec<Uuid> = dishes
+        .iter()
+        .map(|d| d.restaurant_id)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let restaurants: Vec<Restaurant> =
+        sqlx::query_as("select * from restaurant where restaurant_id = any($1)")
+            .bind(&restaurant_ids)
+            .fetch_all(&mut **tx)
+            .await?;
+    let sites: Vec<Site> = sqlx::query_as(
+        "select * from site where site_id in (select site_id from restaurant where restaurant_id = any($1))",
+    )
+    .bind(&restaurant_ids)
+    .fetch_all(&mut **tx)
+    .await?;
+    let site_ids = sites.iter().map(|s| s.site_id).collect::<Vec<_>>();
+    let cities: Vec<City> = sqlx::query_as(
+        "select * from city where city_id in (select city_id from site where site_id = any($1))",
+    )
+    .bind(&site_ids)
+    .fetch_all(&mut **tx)
+    .await?;
+    let city_ids = cities.iter().map(|c| c.city_id).collect::<Vec<_>>();
+    let countries: Vec<Country> = sqlx::query_as(
+        "select * from country where country_id in (select country_id from city where city_id = any($1))",
+    )
+    .bind(&city_ids)
+    .fetch_all(&mut **tx)
+    .await?;
 
-    trace!("DB update done in {:?}", duration);
+    Ok(LunchData::build(
+        countries,
+        cities,
+        sites,
+        restaurants,
+        dishes,
+    ))
+}
 
-    tx.commit().await
+/// List restaurants with known coordinates within radius_km of the given point, nearest first.
+/// A bounding box is used to narrow down the candidates before calculating the actual distance.
+pub async fn restaurants_near<'e, E>(
+    ex: E,
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+) -> Result<Vec<Restaurant>, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let dlat = (radius_km / util::EARTH_RADIUS_KM).to_degrees();
+    // the box gets wider the closer we are to the poles, so just include all longitudes there
+    let dlon = match lat.to_radians().cos() {
+        c if c > 0.01 => (dlat / c).min(180.0),
+        _ => 180.0,
+    };
+    sqlx::query_as(
+        r#"
+            select * from (
+                select *,
+                    2 * $6 * asin(sqrt(
+                        power(sin(radians(lat - $1) / 2), 2)
+                        + cos(radians($1)) * cos(radians(lat)) * power(sin(radians(lon - $2) / 2), 2)
+                    )) as distance_km
+                    from restaurant
+                    where lat between $1 - $4 and $1 + $4
+                    and lon between $2 - $5 and $2 + $5
+            ) as r
+                where distance_km <= $3
+                order by distance_km
+        "#,
+    )
+    .bind(lat)
+    .bind(lon)
+    .bind(radius_km)
+    .bind(dlat)
+    .bind(dlon)
+    .bind(util::EARTH_RADIUS_KM)
+    .fetch_all(ex)
+    .await
+}
+
+/// Get everything in the DB as a single tree, e.g. for backups.
+/// Since this might get big, the restaurants can be limited to the `limit` most recently parsed,
+/// and/or to those parsed after `since`. Countries, cities and sites are always included in full.
+pub async fn list_all(
+    tx: &mut Transaction<'_>,
+    limit: Option<usize>,
+    since: Option<DateTime<Local>>,
+) -> Result<LunchData, Error> {
+    let countries: Vec<Country> = sqlx::query_as("select * from country")
+        .fetch_all(&mut **tx)
+        .await?;
+    let cities: Vec<City> = sqlx::query_as("select * from city")
+        .fetch_all(&mut **tx)
+        .await?;
+    let sites: Vec<Site> = sqlx::query_as("select * from site")
+        .fetch_all(&mut **tx)
+        .await?;
+    // a null limit is the same as no limit in Postgres
+    let restaurants: Vec<Restaurant> = sqlx::query_as(
+        r#"
+            select * from restaurant
+                where $1::timestamptz is null or created_at >= $1
+                order by created_at desc
+                limit $2
+        "#,
+    )
+    .bind(since)
+    .bind(limit.map(saturating_i64))
+    .fetch_all(&mut **tx)
+    .await?;
+    let dishes = get_dishes_for_site(&mut **tx, get_restaurant_ids(&restaurants)).await?;
+
+    Ok(LunchData::build(
+        countries,
+        cities,
+        sites,
+        restaurants,
+        dishes,
+    ))
+}
+
+/// Write the scraped restaurants and dishes for a site, replacing the current dishes.
+/// Restaurants marked as manual in the DB are never touched, and scraped restaurants with the same
+/// name as a manual one are skipped.
+/// If the update is authoritative, all other restaurants for the site are replaced, so that
+/// restaurants that are no longer scraped don't linger. Otherwise, each restaurant is upserted by
+/// name, and restaurants not in the update are left alone.
+pub async fn update_site(pg: &PgPool, mut update: ScrapeResult) -> Result<(), Error> {
+    trace!(site_id = %update.site_id, authoritative = update.authoritative, "Adding {} restaurants and {} dishes to DB", update.num_restaurants(), update.num_dishes());
+
+    // we need a transaction to ensure these operations are done atomically
+    let mut tx = pg.begin().await?;
+    lock_site(&mut tx, update.site_id).await?;
+
+    let manual = get_manual_restaurant_names(&mut *tx, update.site_id).await?;
+    update.restaurants.retain(|r| {
+        let keep = !manual.contains(&r.name);
+        if !keep {
+            debug!(site_id = %update.site_id, name = r.name, "Skipping scraped restaurant, since there's a manual one with the same name");
+        }
+        keep
+    });
+
+    if !update.authoritative {
+        let start = Instant::now();
+        let mut dishes = UuidMap::default();
+        for r in update.restaurants {
+            // skipped restaurants have already been filtered out above
+            let Some(restaurant_id) = upsert_restaurant(&mut tx, &r).await? else {
+                continue;
+            };
+            sqlx::query("delete from dish where restaurant_id = $1")
+                .bind(restaurant_id)
+                .execute(&mut *tx)
+                .await?;
+            for (_, d) in r.dishes.0 {
+                dishes.add(d.for_restaurant(restaurant_id));
+            }
+        }
+        insert_dishes(&mut tx, &DishRows::from(dishes)).await?;
+        notify_update(&mut tx, update.site_id).await?;
+        trace!("DB update done in {:?}", start.elapsed());
+        return tx.commit().await;
+    }
+
+    let start = Instant::now();
+    // convert to format suitable for use with unnest
+    let rs = RestaurantRows::from(update.restaurants);
+    let duration = start.elapsed();
+    trace!("Conversion to DB format done in {:?}", duration);
+
+    let start = Instant::now();
+    // first, clear out all scraped restaurants and their dishes, so that we don't have any stale
+    // data lingering. We have "on delete cascade" for dishes, so we just need to delete the parent
+    // restaurants to get rid of all.
+    sqlx::query!(
+        "delete from restaurant where site_id = $1 and not manual",
+        update.site_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // insert all restaurants
+    sqlx::query!(
+        r#"
+            insert into restaurant (site_id, restaurant_id, restaurant_name, comment, address, url, map_url, lat, lon, created_at)
+            select * from unnest($1::uuid[], $2::uuid[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[], $8::float8[], $9::float8[], $10::timestamptz[])
+        "#,
+        &rs.site_ids[..],
+        &rs.restaurant_ids[..],
+        &rs.names[..],
+        &rs.comments as &[Option<String>],
+        &rs.addresses as &[Option<String>],
+        &rs.urls as &[Option<String>],
+        &rs.map_urls as &[Option<String>],
+        &rs.lats as &[Option<f64>],
+        &rs.lons as &[Option<f64>],
+        &rs.parsed_ats[..],
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // insert all dishes
+    insert_dishes(&mut tx, &rs.dishes).await?;
+
+    notify_update(&mut tx, update.site_id).await?;
+    let duration = start.elapsed();
+
+    trace!("DB update done in {:?}", duration);
+
+    tx.commit().await
+}
+
+async fn insert_dishes(tx: &mut Transaction<'_>, dishes: &DishRows) -> Result<(), Error> {
+    // Postgres arrays must be rectangular, and unnest would flatten them anyway, so the tags for
+    // each dish are passed as a JSON array of arrays instead, and matched up by position.
+    sqlx::query!(
+        r#"
+            insert into dish (restaurant_id, dish_id, dish_name, description, comment, price, tags, source_url)
+            select d.restaurant_id, d.dish_id, d.dish_name, d.description, d.comment, d.price, array(select jsonb_array_elements_text(t.tags)), d.source_url
+                from unnest($1::uuid[], $2::uuid[], $3::text[], $4::text[], $5::text[], $6::numeric[], $8::text[])
+                    with ordinality as d(restaurant_id, dish_id, dish_name, description, comment, price, source_url, n)
+                join jsonb_array_elements($7::jsonb) with ordinality as t(tags, n) using (n)
+        "#,
+        &dishes.restaurant_ids[..],
+        &dishes.dish_ids[..],
+        &dishes.names[..],
+        &dishes.descriptions as &[Option<String>],
+        &dishes.comments as &[Option<String>],
+        &dishes.prices as &[Price],
+        sqlx::types::Json(&dishes.tags) as _,
+        &dishes.source_urls as &[Option<String>],
+    ).execute(&mut **tx).await?;
+    Ok(())
+}
+
+/// Like update_site, but instead of replacing all restaurants and dishes for the site, restaurants
+/// are updated in place, matched by name, and dishes are added as new versions.
+/// Only the latest `keep` versions of each dish, matched by restaurant and name, are kept.
+/// All dishes inserted in the same transaction get the same created_at, which is what the read
+/// functions use to only return the latest version.
+pub async fn update_site_versioned(
+    pg: &PgPool,
+    update: ScrapeResult,
+    keep: usize,
+) -> Result<(), Error> {
+    trace!(site_id = %update.site_id, keep, "Adding {} restaurants and {} dishes to DB, keeping history", update.num_restaurants(), update.num_dishes());
+
+    let start = Instant::now();
+    let mut tx = pg.begin().await?;
+    lock_site(&mut tx, update.site_id).await?;
+
+    // restaurants that are no longer scraped, and all their dishes, only go away if we know the
+    // update covers the whole site
+    if update.authoritative {
+        let names: Vec<&str> = update.restaurants.iter().map(|r| r.name.as_str()).collect();
+        let res = sqlx::query(
+            r#"
+                delete from restaurant
+                    where site_id = $1 and not manual and restaurant_name <> all($2)
+            "#,
+        )
+        .bind(update.site_id)
+        .bind(&names)
+        .execute(&mut *tx)
+        .await?;
+        trace!(
+            "Removed {} restaurants no longer scraped",
+            res.rows_affected()
+        );
+    }
+
+    let mut dishes = UuidMap::default();
+    for r in update.restaurants {
+        let Some(restaurant_id) = upsert_restaurant(&mut tx, &r).await? else {
+            debug!(site_id = %update.site_id, name = r.name, "Skipping scraped restaurant, since there's a manual one with the same name");
+            continue;
+        };
+        for (_, d) in r.dishes.0 {
+            dishes.add(d.for_restaurant(restaurant_id));
+        }
+    }
+    insert_dishes(&mut tx, &DishRows::from(dishes)).await?;
+
+    // there's no point in keeping less than the version we just inserted
+    let keep = keep.max(1) as i64;
+    let res = sqlx::query(
+        r#"
+            delete from dish where dish_id in (
+                select dish_id from (
+                    select
+                        dish_id,
+                        row_number() over (partition by restaurant_id, dish_name order by dish.created_at desc) as version
+                        from dish join restaurant using (restaurant_id)
+                        where restaurant.site_id = $1
+                ) as v where version > $2
+            )
+        "#,
+    )
+    .bind(update.site_id)
+    .bind(keep)
+    .execute(&mut *tx)
+    .await?;
+    trace!("Pruned {} old dish versions", res.rows_affected());
+
+    notify_update(&mut tx, update.site_id).await?;
+    let duration = start.elapsed();
+
+    trace!("DB update done in {:?}", duration);
+
+    tx.commit().await
+}
+
+/// Update the scraped restaurant with the same name within its site, or insert it if missing.
+/// Returns the id of the restaurant in the DB, or None if there's a manual restaurant with the
+/// same name, which is left alone.
+async fn upsert_restaurant(
+    tx: &mut Transaction<'_>,
+    r: &Restaurant,
+) -> Result<Option<Uuid>, Error> {
+    let id: Option<Uuid> = sqlx::query_scalar(
+        r#"
+            update restaurant set comment = $3, address = $4, url = $5, map_url = $6, lat = $7, lon = $8, created_at = $9
+                where site_id = $1 and restaurant_name = $2 and not manual
+                returning restaurant_id
+        "#,
+    )
+    .bind(r.site_id)
+    .bind(&r.name)
+    .bind(&r.comment)
+    .bind(&r.address)
+    .bind(&r.url)
+    .bind(&r.map_url)
+    .bind(r.lat)
+    .bind(r.lon)
+    .bind(r.parsed_at)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if id.is_some() {
+        return Ok(id);
+    }
+    sqlx::query_scalar(
+        r#"
+            insert into restaurant (site_id, restaurant_id, restaurant_name, comment, address, url, map_url, lat, lon, created_at)
+                select $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
+                where not exists (select 1 from restaurant where site_id = $1 and restaurant_name = $3 and manual)
+                returning restaurant_id
+        "#,
+    )
+    .bind(r.site_id)
+    .bind(r.restaurant_id)
+    .bind(&r.name)
+    .bind(&r.comment)
+    .bind(&r.address)
+    .bind(&r.url)
+    .bind(&r.map_url)
+    .bind(r.lat)
+    .bind(r.lon)
+    .bind(r.parsed_at)
+    .fetch_optional(&mut **tx)
+    .await
+}
+
+/// Names of the restaurants for the site that are curated by hand, and should be left alone by
+/// scrapers
+async fn get_manual_restaurant_names<'e, E>(ex: E, site_id: Uuid) -> Result<Vec<String>, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_scalar("select restaurant_name from restaurant where site_id = $1 and manual")
+        .bind(site_id)
+        .fetch_all(ex)
+        .await
+}
+
+/// Record a successful run of the given scraper
+pub async fn record_scraper_success(
+    pg: &PgPool,
+    scraper_name: &str,
+    site_id: Uuid,
+    dish_count: usize,
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            insert into scraper_status (scraper_name, site_id, last_success_at, last_dish_count)
+                values ($1, $2, now(), $3)
+                on conflict (scraper_name) do update
+                set site_id = excluded.site_id,
+                    last_success_at = excluded.last_success_at,
+                    last_dish_count = excluded.last_dish_count
+        "#,
+    )
+    .bind(scraper_name)
+    .bind(site_id)
+    .bind(i32::try_from(dish_count).unwrap_or(i32::MAX))
+    .execute(pg)
+    .await?;
+    Ok(())
+}
+
+/// Record a failed run of the given scraper
+pub async fn record_scraper_failure(
+    pg: &PgPool,
+    scraper_name: &str,
+    error: &str,
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            insert into scraper_status (scraper_name, last_failure_at, last_error)
+                values ($1, now(), $2)
+                on conflict (scraper_name) do update
+                set last_failure_at = excluded.last_failure_at,
+                    last_error = excluded.last_error
+        "#,
+    )
+    .bind(scraper_name)
+    .bind(error)
+    .execute(pg)
+    .await?;
+    Ok(())
+}
+
+/// Get the status of all scrapers that have run at least once
+pub async fn get_scraper_statuses<'e, E>(ex: E) -> Result<Vec<ScraperStatus>, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as("select * from scraper_status order by scraper_name")
+        .fetch_all(ex)
+        .await
+}
+
+/// Check if the given scraper is enabled. Scrapers are enabled unless explicitly disabled.
+pub async fn is_scraper_enabled<'e, E>(ex: E, scraper_name: &str) -> Result<bool, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let enabled: Option<bool> =
+        sqlx::query_scalar("select enabled from scraper_enabled where scraper_name = $1")
+            .bind(scraper_name)
+            .fetch_optional(ex)
+            .await?;
+    Ok(enabled.unwrap_or(true))
+}
+
+/// Enable or disable the given scraper. Takes effect the next time scrapers are set up.
+pub async fn set_scraper_enabled<'e, E>(
+    ex: E,
+    scraper_name: &str,
+    enabled: bool,
+) -> Result<ScraperFlag, Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as(
+        r#"
+            insert into scraper_enabled (scraper_name, enabled)
+                values ($1, $2)
+                on conflict (scraper_name) do update
+                set enabled = excluded.enabled
+            returning scraper_name, enabled
+        "#,
+    )
+    .bind(scraper_name)
+    .bind(enabled)
+    .fetch_one(ex)
+    .await
+}
+
+/// Lock the site row for the rest of the transaction, so concurrent updates for the same site are
+/// serialized, while updates for other sites can go ahead.
+async fn lock_site(tx: &mut Transaction<'_>, site_id: Uuid) -> Result<(), Error> {
+    sqlx::query("select 1 from site where site_id = $1 for update")
+        .bind(site_id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Let any listeners know that they need to refresh, e.g. response caches.
+/// The notification is only delivered if the transaction commits.
+async fn notify_update(tx: &mut Transaction<'_>, id: Uuid) -> Result<(), Error> {
+    sqlx::query("select pg_notify($1, $2)")
+        .bind(UPDATE_CHANNEL)
+        .bind(id.to_string())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Replace the whole structure for the given country, matching existing rows by url_id.
+/// Cities and sites that exist in the DB, but not in the given country, will be deleted, together
+/// with all their restaurants and dishes. Ids for existing rows are kept, so restaurants for sites
+/// that are still present are left alone.
+/// Restaurants within the given country are ignored, as those are handled by update_site.
+pub async fn replace_country(pg: &PgPool, country: Country) -> Result<()> {
+    // A country without cities would delete everything below it, which is much more likely to be
+    // a mistake than what the caller wants.
+    if country.cities.is_empty() {
+        bail!(
+            "refusing to replace country {} with no cities",
+            country.url_id
+        );
+    }
+
+    // Duplicates would otherwise silently be merged into the same row, as existing rows are
+    // matched by url_id, ignoring case and accents
+    if let Some(url_id) = first_duplicate(country.cities.values().map(|ci| ci.url_id.as_str())) {
+        bail!(
+            "conflict: more than one city with url_id {url_id} in country {}",
+            country.url_id
+        );
+    }
+    for city in country.cities.values() {
+        if let Some(url_id) = first_duplicate(city.sites.values().map(|si| si.url_id.as_str())) {
+            bail!(
+                "conflict: more than one site with url_id {url_id} in city {}",
+                city.url_id
+            );
+        }
+    }
+
+    trace!(url_id = country.url_id, "Replacing country...");
+    let start = Instant::now();
+    let mut tx = pg.begin().await?;
+
+    let country_id: Uuid = sqlx::query_scalar(
+        r#"
+            insert into country (name, url_id, currency_suffix) values ($1, $2, $3)
+                on conflict ((url_id_key(url_id))) do update set name = excluded.name, currency_suffix = excluded.currency_suffix
+                returning country_id
+        "#,
+    )
+    .bind(&country.name)
+    .bind(&country.url_id)
+    .bind(&country.currency_suffix)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let mut city_ids = Vec::with_capacity(country.cities.len());
+    for city in country.cities.values() {
+        let city_id = upsert_city(&mut tx, country_id, city).await?;
+        city_ids.push(city_id);
+
+        let mut site_ids = Vec::with_capacity(city.sites.len());
+        for site in city.sites.values() {
+            site_ids.push(upsert_site(&mut tx, city_id, site).await?);
+        }
+        let res = sqlx::query("delete from site where city_id = $1 and site_id <> all($2)")
+            .bind(city_id)
+            .bind(&site_ids)
+            .execute(&mut *tx)
+            .await?;
+        trace!(%city_id, "Deleted {} sites", res.rows_affected());
+    }
+
+    let res = sqlx::query("delete from city where country_id = $1 and city_id <> all($2)")
+        .bind(country_id)
+        .bind(&city_ids)
+        .execute(&mut *tx)
+        .await?;
+    trace!(%country_id, "Deleted {} cities", res.rows_affected());
+
+    notify_update(&mut tx, country_id).await?;
+    tx.commit().await?;
+
+    trace!("Country replaced in {:?}", start.elapsed());
+
+    Ok(())
+}
+
+fn first_duplicate<'a>(mut url_ids: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let mut seen = HashSet::new();
+    url_ids.find(|&id| !seen.insert(util::slug(id)))
+}
+
+/// Update the city with the same url_id within the given country, or insert it if missing.
+/// Returns the id of the city in the DB.
+async fn upsert_city(tx: &mut Transaction<'_>, country_id: Uuid, city: &City) -> Result<Uuid> {
+    let id: Option<Uuid> = sqlx::query_scalar(
+        r#"
+            update city set name = $3
+                where country_id = $1 and url_id_key(url_id) = url_id_key($2)
+                returning city_id
+        "#,
+    )
+    .bind(country_id)
+    .bind(&city.url_id)
+    .bind(&city.name)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    insert_city(tx, country_id, city).await
+}
+
+/// Update the site with the same url_id within the given city, or insert it if missing.
+/// Returns the id of the site in the DB.
+async fn upsert_site(tx: &mut Transaction<'_>, city_id: Uuid, site: &Site) -> Result<Uuid> {
+    let id: Option<Uuid> = sqlx::query_scalar(
+        r#"
+            update site set name = $3, comment = $4, currency_suffix = $5
+                where city_id = $1 and url_id_key(url_id) = url_id_key($2)
+                returning site_id
+        "#,
+    )
+    .bind(city_id)
+    .bind(&site.url_id)
+    .bind(&site.name)
+    .bind(&site.comment)
+    .bind(&site.currency_suffix)
+    .fetch_optional(&mut **tx)
+    .await?;
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    insert_site(tx, city_id, site).await
+}
+
+// Single row changes for the admin server. They all notify about the update, but leave it to the
+// caller to commit the transaction.
+
+/// Insert a new country, failing if the url_id is already taken. Returns the id of the country.
+pub async fn insert_country(tx: &mut Transaction<'_>, country: &Country) -> Result<Uuid> {
+    if url_id_exists(
+        &mut **tx,
+        SiteKeyLevel::Country,
+        Uuid::nil(),
+        &country.url_id,
+    )
+    .await?
+    {
+        return Err(UrlIdConflict {
+            what: "country",
+            url_id: country.url_id.clone(),
+        }
+        .into());
+    }
+    let country_id = sqlx::query_scalar(
+        r#"
+            insert into country (name, url_id, currency_suffix) values ($1, $2, $3) returning country_id
+        "#,
+    )
+    .bind(&country.name)
+    .bind(&country.url_id)
+    .bind(&country.currency_suffix)
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "country", &country.url_id))?;
+    notify_update(tx, country_id).await?;
+    Ok(country_id)
+}
+
+/// Update name, url_id and currency of the country with the same id
+pub async fn update_country(tx: &mut Transaction<'_>, country: &Country) -> Result<()> {
+    let current = get_country(&mut **tx, country.country_id).await?;
+    // url_ids are compared by slug, so changing just the case or accents would collide with itself
+    if util::slug(&current.url_id) != util::slug(&country.url_id)
+        && url_id_exists(
+            &mut **tx,
+            SiteKeyLevel::Country,
+            Uuid::nil(),
+            &country.url_id,
+        )
+        .await?
+    {
+        return Err(UrlIdConflict {
+            what: "country",
+            url_id: country.url_id.clone(),
+        }
+        .into());
+    }
+    sqlx::query(
+        r#"
+            update country set name = $2, url_id = $3, currency_suffix = $4 where country_id = $1
+        "#,
+    )
+    .bind(country.country_id)
+    .bind(&country.name)
+    .bind(&country.url_id)
+    .bind(&country.currency_suffix)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "country", &country.url_id))?;
+    notify_update(tx, country.country_id).await?;
+    Ok(())
+}
+
+/// Delete the country, with everything in it. Fails with RowNotFound if there's no such country.
+pub async fn delete_country(tx: &mut Transaction<'_>, country_id: Uuid) -> Result<(), Error> {
+    delete_row(tx, "delete from country where country_id = $1", country_id).await
+}
+
+/// Update name and url_id of the city with the same id.
+/// The city can't be moved to another country this way.
+pub async fn update_city(tx: &mut Transaction<'_>, city: &City) -> Result<()> {
+    let current = get_city(&mut **tx, city.city_id).await?;
+    if util::slug(&current.url_id) != util::slug(&city.url_id)
+        && url_id_exists(
+            &mut **tx,
+            SiteKeyLevel::City,
+            current.country_id,
+            &city.url_id,
+        )
+        .await?
+    {
+        return Err(UrlIdConflict {
+            what: "city",
+            url_id: city.url_id.clone(),
+        }
+        .into());
+    }
+    sqlx::query("update city set name = $2, url_id = $3 where city_id = $1")
+        .bind(city.city_id)
+        .bind(&city.name)
+        .bind(&city.url_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| url_id_conflict(e, "city", &city.url_id))?;
+    notify_update(tx, city.city_id).await?;
+    Ok(())
+}
+
+/// Delete the city, with everything in it. Fails with RowNotFound if there's no such city.
+pub async fn delete_city(tx: &mut Transaction<'_>, city_id: Uuid) -> Result<(), Error> {
+    delete_row(tx, "delete from city where city_id = $1", city_id).await
+}
+
+/// Update name, url_id, comment and currency of the site with the same id.
+/// Not to be confused with update_site, which writes scrape results for a site.
+pub async fn update_site_details(tx: &mut Transaction<'_>, site: &Site) -> Result<()> {
+    let current = get_site(&mut **tx, site.site_id).await?;
+    if util::slug(&current.url_id) != util::slug(&site.url_id)
+        && url_id_exists(&mut **tx, SiteKeyLevel::Site, current.city_id, &site.url_id).await?
+    {
+        return Err(UrlIdConflict {
+            what: "site",
+            url_id: site.url_id.clone(),
+        }
+        .into());
+    }
+    sqlx::query(
+        r#"
+            update site set name = $2, url_id = $3, comment = $4, currency_suffix = $5 where site_id = $1
+        "#,
+    )
+    .bind(site.site_id)
+    .bind(&site.name)
+    .bind(&site.url_id)
+    .bind(&site.comment)
+    .bind(&site.currency_suffix)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "site", &site.url_id))?;
+    notify_update(tx, site.site_id).await?;
+    Ok(())
+}
+
+/// Delete the site, with everything in it. Fails with RowNotFound if there's no such site.
+pub async fn delete_site(tx: &mut Transaction<'_>, site_id: Uuid) -> Result<(), Error> {
+    delete_row(tx, "delete from site where site_id = $1", site_id).await
+}
+
+/// The url_id the given name would get, if another restaurant in the site already has it
+async fn restaurant_url_id_conflict(
+    tx: &mut Transaction<'_>,
+    site_id: Uuid,
+    restaurant_id: Uuid,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    sqlx::query_scalar(
+        r#"
+            select url_id from restaurant
+                where site_id = $1 and restaurant_id <> $2 and url_id_key(url_id) = url_id_key($3)
+                limit 1
+        "#,
+    )
+    .bind(site_id)
+    .bind(restaurant_id)
+    .bind(name)
+    .fetch_optional(&mut **tx)
+    .await
+}
+
+/// Insert a restaurant by hand in the given site, with its dishes, failing if another restaurant
+/// there would get the same url_id. It's marked as manual, so scrapers leave it alone, see
+/// update_site.
+pub async fn insert_restaurant(
+    tx: &mut Transaction<'_>,
+    site_id: Uuid,
+    r: Restaurant,
+) -> Result<()> {
+    if let Some(url_id) = restaurant_url_id_conflict(tx, site_id, Uuid::nil(), &r.name).await? {
+        return Err(UrlIdConflict {
+            what: "restaurant",
+            url_id,
+        }
+        .into());
+    }
+    sqlx::query(
+        r#"
+            insert into restaurant (restaurant_id, site_id, restaurant_name, comment, address, url, map_url, lat, lon, manual)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, true)
+        "#,
+    )
+    .bind(r.restaurant_id)
+    .bind(site_id)
+    .bind(&r.name)
+    .bind(&r.comment)
+    .bind(&r.address)
+    .bind(&r.url)
+    .bind(&r.map_url)
+    .bind(r.lat)
+    .bind(r.lon)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "restaurant", &util::slug(&r.name)))?;
+    insert_dishes(tx, &r.dishes.into()).await?;
+    notify_update(tx, site_id).await?;
+    Ok(())
+}
+
+/// Update the restaurant with the same id, replacing all its dishes.
+/// A scraped restaurant might get overwritten by the next scrape.
+pub async fn update_restaurant(tx: &mut Transaction<'_>, r: Restaurant) -> Result<()> {
+    let current = get_restaurant(&mut **tx, r.restaurant_id).await?;
+    if let Some(url_id) =
+        restaurant_url_id_conflict(tx, current.site_id, r.restaurant_id, &r.name).await?
+    {
+        return Err(UrlIdConflict {
+            what: "restaurant",
+            url_id,
+        }
+        .into());
+    }
+    sqlx::query(
+        r#"
+            update restaurant set restaurant_name = $2, comment = $3, address = $4, url = $5, map_url = $6, lat = $7, lon = $8
+                where restaurant_id = $1
+        "#,
+    )
+    .bind(r.restaurant_id)
+    .bind(&r.name)
+    .bind(&r.comment)
+    .bind(&r.address)
+    .bind(&r.url)
+    .bind(&r.map_url)
+    .bind(r.lat)
+    .bind(r.lon)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| url_id_conflict(e, "restaurant", &util::slug(&r.name)))?;
+    sqlx::query("delete from dish where restaurant_id = $1")
+        .bind(r.restaurant_id)
+        .execute(&mut **tx)
+        .await?;
+    insert_dishes(tx, &r.dishes.into()).await?;
+    notify_update(tx, current.site_id).await?;
+    Ok(())
+}
+
+/// Delete the restaurant and its dishes. Fails with RowNotFound if there's no such restaurant.
+pub async fn delete_restaurant(tx: &mut Transaction<'_>, restaurant_id: Uuid) -> Result<(), Error> {
+    let site_id: Uuid =
+        sqlx::query_scalar("delete from restaurant where restaurant_id = $1 returning site_id")
+            .bind(restaurant_id)
+            .fetch_one(&mut **tx)
+            .await?;
+    notify_update(tx, site_id).await
+}
+
+async fn delete_row(tx: &mut Transaction<'_>, query: &str, id: Uuid) -> Result<(), Error> {
+    let res = sqlx::query(query).bind(id).execute(&mut **tx).await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+    notify_update(tx, id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    /// Add a site with a couple of scraped restaurants, returning the site id
+    async fn seed(pg: &PgPool) -> Uuid {
+        let site_id = ensure_site_relation(
+            pg,
+            SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh")),
+        )
+        .await
+        .unwrap()
+        .site_id;
+        update_site(pg, scrape(site_id, &["Köttbullar", "Fisk"]))
+            .await
+            .unwrap();
+        site_id
+    }
+
+    fn scrape(site_id: Uuid, dishes: &[&str]) -> ScrapeResult {
+        let restaurants = ["Kårhuset", "Bistrot"]
+            .into_iter()
+            .map(|name| {
+                let r = Restaurant {
+                    comment: Some("lunch 11-14".into()),
+                    lat: Some(57.7),
+                    lon: Some(11.9),
+                    ..Restaurant::new_for_site(name, site_id)
+                };
+                let dishes = dishes
+                    .iter()
+                    .map(|&d| Dish {
+                        tags: vec!["vego".into()],
+                        price: Price::new(Decimal::new(12990, 2)),
+                        ..Dish::new(d).for_restaurant(r.restaurant_id)
+                    })
+                    .collect();
+                r.with_dishes(dishes)
+            })
+            .collect();
+        ScrapeResult {
+            site_id,
+            restaurants,
+            authoritative: true,
+        }
+    }
+
+    #[test]
+    fn site_key() {
+        // the url_ids go into the level their type says, whatever the conversion at the call site
+        let key = SiteKey::new("se".into(), "gbg".into(), SiteUrlId("lh"));
+        assert_eq!(key.country_url_id, CountryUrlId("se"));
+        assert_eq!(key.city_url_id, CityUrlId("gbg"));
+        assert_eq!(key.site_url_id.as_str(), "lh");
+        assert!(key.restaurant_url_id.is_empty());
+        assert_eq!(key.level(), SiteKeyLevel::Site);
+
+        let key = key.with_restaurant("bistrot".into());
+        assert_eq!(key.restaurant_url_id, RestaurantUrlId("bistrot"));
+        assert_eq!(key.level(), SiteKeyLevel::Restaurant);
+
+        let key = SiteKey::new(CountryUrlId("se"), CityUrlId(""), SiteUrlId(""));
+        assert_eq!(key.level(), SiteKeyLevel::Country);
+        let key = SiteKey::new(CountryUrlId(""), CityUrlId(""), SiteUrlId(""));
+        assert_eq!(key.level(), SiteKeyLevel::Empty);
+    }
+
+    #[sqlx::test]
+    async fn empty_tags_are_read_as_empty(pg: PgPool) {
+        let site_id = seed(&pg).await;
+        let mut update = scrape(site_id, &["Fisk"]);
+        for r in update.restaurants.iter_mut() {
+            for d in r.dishes.values_mut() {
+                d.tags.clear();
+            }
+        }
+        update_site(&pg, update).await.unwrap();
+
+        let mut tx = pg.begin().await.unwrap();
+        for data in [
+            list_dishes_for_site_by_id(&mut tx, site_id).await.unwrap(),
+            list_dishes_for_site_by_id_agg(&mut tx, site_id)
+                .await
+                .unwrap(),
+        ] {
+            let dishes: Vec<_> = data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .flat_map(|ci| ci.sites.values())
+                .flat_map(|si| si.restaurants.values())
+                .flat_map(|r| r.dishes.values())
+                .collect();
+            assert_eq!(dishes.len(), 2);
+            assert!(dishes.iter().all(|d| d.tags.is_empty()), "{dishes:?}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn source_url_round_trip(pg: PgPool) {
+        let site_id = seed(&pg).await;
+        let url = "https://example.com/lunch/fisk";
+        let mut update = scrape(site_id, &["Fisk", "Soppa"]);
+        for r in update.restaurants.iter_mut() {
+            for d in r.dishes.values_mut().filter(|d| d.name == "Fisk") {
+                d.source_url = Some(url.into());
+            }
+        }
+        update_site(&pg, update).await.unwrap();
+
+        let mut tx = pg.begin().await.unwrap();
+        for data in [
+            list_dishes_for_site_by_id(&mut tx, site_id).await.unwrap(),
+            list_dishes_for_site_by_id_agg(&mut tx, site_id)
+                .await
+                .unwrap(),
+        ] {
+            let dishes = data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .flat_map(|ci| ci.sites.values())
+                .flat_map(|si| si.restaurants.values())
+                .flat_map(|r| r.dishes.values())
+                .collect::<Vec<_>>();
+            assert_eq!(dishes.len(), 4);
+            for d in dishes {
+                let want = (d.name == "Fisk").then_some(url);
+                assert_eq!(d.source_url.as_deref(), want, "{d:?}");
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn list_all_limits_restaurants(pg: PgPool) {
+        seed(&pg).await;
+        sqlx::query("update restaurant set created_at = now() - interval '1 hour' where restaurant_name = 'Bistrot'")
+            .execute(&pg)
+            .await
+            .unwrap();
+        let mut tx = pg.begin().await.unwrap();
+        let names = |data: &LunchData| {
+            let mut names: Vec<_> = data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .flat_map(|ci| ci.sites.values())
+                .flat_map(|si| si.restaurants.values().map(|r| r.name.clone()))
+                .collect();
+            names.sort();
+            names
+        };
+
+        let all = list_all(&mut tx, None, None).await.unwrap();
+        assert_eq!(names(&all), ["Bistrot", "Kårhuset"]);
+        assert_eq!(all.counts().dishes, 4);
+
+        // the most recent ones, with their dishes, but always all of the hierarchy
+        for data in [
+            list_all(&mut tx, Some(1), None).await.unwrap(),
+            list_all(
+                &mut tx,
+                None,
+                Some(Local::now() - chrono::TimeDelta::minutes(30)),
+            )
+            .await
+            .unwrap(),
+        ] {
+            assert_eq!(names(&data), ["Kårhuset"]);
+            let (counts, all) = (data.counts(), all.counts());
+            assert_eq!(counts.dishes, 2);
+            assert_eq!(
+                (counts.countries, counts.cities, counts.sites),
+                (all.countries, all.cities, all.sites)
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn manual_restaurant_survives_authoritative_update(pg: PgPool) {
+        let site_id = seed(&pg).await;
+        let manual = Restaurant::new_for_site("Husmans", site_id);
+        let dagens = Dish::new("Dagens").for_restaurant(manual.restaurant_id);
+        let mut tx = pg.begin().await.unwrap();
+        insert_restaurant(&mut tx, site_id, manual.with_dish(dagens))
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // the update has a scraped restaurant with the same name, which is skipped
+        let update = || {
+            let mut update = scrape(site_id, &["Fisk"]);
+            let r = Restaurant::new_for_site("Husmans", site_id);
+            let pytt = Dish::new("Pytt").for_restaurant(r.restaurant_id);
+            update.restaurants.push(r.with_dish(pytt));
+            update
+        };
+        update_site(&pg, update()).await.unwrap();
+        update_site_versioned(&pg, update(), 2).await.unwrap();
+
+        let mut tx = pg.begin().await.unwrap();
+        let data = list_dishes_for_site_by_id(&mut tx, site_id).await.unwrap();
+        let mut menus: Vec<(String, Vec<String>)> = data
+            .countries
+            .values()
+            .flat_map(|co| co.cities.values())
+            .flat_map(|ci| ci.sites.values())
+            .flat_map(|si| si.restaurants.values())
+            .map(|r| {
+                let mut dishes: Vec<_> = r.dishes.values().map(|d| d.name.clone()).collect();
+                dishes.sort();
+                (r.name.clone(), dishes)
+            })
+            .collect();
+        menus.sort();
+        let menu = |name: &str, dishes: &[&str]| {
+            (
+                name.to_string(),
+                dishes.iter().map(|d| d.to_string()).collect(),
+            )
+        };
+        assert_eq!(
+            menus,
+            [
+                menu("Bistrot", &["Fisk"]),
+                menu("Husmans", &["Dagens"]),
+                menu("Kårhuset", &["Fisk"]),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn search_ignores_accents_and_case(pg: PgPool) {
+        let site_id = seed(&pg).await;
+        let mut update = scrape(site_id, &["Köttbullar", "Crème brûlée"]);
+        for d in update
+            .restaurants
+            .iter_mut()
+            .flat_map(|r| r.dishes.values_mut())
+        {
+            d.description = Some("med potatismos".into());
+        }
+        update_site(&pg, update).await.unwrap();
+
+        let mut tx = pg.begin().await.unwrap();
+        for (query, want) in [
+            ("kottbullar", vec!["Köttbullar"]),
+            ("KÖTTBULLAR", vec!["Köttbullar"]),
+            ("creme brulee", vec!["Crème brûlée"]),
+            ("brûlée", vec!["Crème brûlée"]),
+            ("POTATISMOS", vec!["Crème brûlée", "Köttbullar"]),
+            ("pasta", vec![]),
+        ] {
+            let data = search_dishes(&mut tx, query, Some(site_id)).await.unwrap();
+            let mut names: Vec<_> = data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .flat_map(|ci| ci.sites.values())
+                .flat_map(|si| si.restaurants.values())
+                .flat_map(|r| r.dishes.values().map(|d| d.name.clone()))
+                .collect();
+            names.sort();
+            names.dedup();
+            assert_eq!(names, want, "{query}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn list_dishes_for_site_agg_matches(pg: PgPool) {
+        let site_id = seed(&pg).await;
+        let mut tx = pg.begin().await.unwrap();
+
+        let want = list_dishes_for_site_by_id(&mut tx, site_id).await.unwrap();
+        let got = list_dishes_for_site_by_id_agg(&mut tx, site_id)
+            .await
+            .unwrap();
+        assert_eq!(got, want);
+        assert_eq!(got.countries.len(), 1);
+
+        // not much of a benchmark on a tiny DB, but shows the difference in round trips
+        const RUNS: u32 = 50;
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            list_dishes_for_site_by_id(&mut tx, site_id).await.unwrap();
+        }
+        let per_level = start.elapsed() / RUNS;
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            list_dishes_for_site_by_id_agg(&mut tx, site_id)
+                .await
+                .unwrap();
+        }
+        let agg = start.elapsed() / RUNS;
+        tracing::info!(
+            ?per_level,
+            ?agg,
+            "Time per call to list_dishes_for_site_by_id(_agg)"
+        );
+    }
+
+    #[sqlx::test]
+    async fn update_site_versioned_keeps_history(pg: PgPool) {
+        let site_id = seed(&pg).await;
+        let mut kept = Vec::new();
+        for _ in 0..3 {
+            let update = scrape(site_id, &["Köttbullar", "Fisk"]);
+            kept.push(
+                update
+                    .restaurants
+                    .iter()
+                    .flat_map(|r| r.dishes.keys().copied())
+                    .collect::<HashSet<Uuid>>(),
+            );
+            update_site_versioned(&pg, update, 2).await.unwrap();
+        }
+
+        let versions: Vec<(String, String, i64)> = sqlx::query_as(
+            r#"
+                select restaurant_name, dish_name, count(*)
+                    from dish join restaurant using (restaurant_id)
+                    where site_id = $1
+                    group by restaurant_name, dish_name
+            "#,
+        )
+        .bind(site_id)
+        .fetch_all(&pg)
+        .await
+        .unwrap();
+        assert_eq!(versions.len(), 4);
+        for (restaurant, dish, n) in versions {
+            assert_eq!(n, 2, "{restaurant}: {dish}");
+        }
+
+        // the two latest scrapes are the ones kept, with the dishes from the first one gone
+        let dish_ids: HashSet<Uuid> = sqlx::query_scalar(
+            "select dish_id from dish join restaurant using (restaurant_id) where site_id = $1",
+        )
+        .bind(site_id)
+        .fetch_all(&pg)
+        .await
+        .unwrap()
+        .into_iter()
+        .collect();
+        assert!(dish_ids.is_disjoint(&kept[0]));
+        assert!(kept[1].is_subset(&dish_ids));
+        assert!(kept[2].is_subset(&dish_ids));
+    }
+
+    #[sqlx::test]
+    async fn url_id_key_matches_slug(pg: PgPool) {
+        for s in [
+            "Göteborg",
+            "  Göteborg Centrum! ",
+            "Café 'Pier 11'",
+            "Smørrebrød",
+            "Æbleskiver",
+            "Straße",
+            "KÅRHUSET",
+        ] {
+            let key: String = sqlx::query_scalar("select url_id_key($1)")
+                .bind(s)
+                .fetch_one(&pg)
+                .await
+                .unwrap();
+            assert_eq!(key, util::slug(s), "{s}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn site_relation_ignores_case_and_accents(pg: PgPool) {
+        let key = |city| SiteKey::new(CountryUrlId("se"), CityUrlId(city), SiteUrlId("lh"));
+        let want = ensure_site_relation(&pg, key("göteborg")).await.unwrap();
+        for city in ["göteborg", "goteborg", "GÖTEBORG"] {
+            assert_eq!(get_site_relation(&pg, key(city)).await.unwrap(), want);
+            // and no duplicates are made when ensuring it again
+            assert_eq!(ensure_site_relation(&pg, key(city)).await.unwrap(), want);
+        }
+    }
+
+    #[sqlx::test]
+    async fn huge_offset_is_clamped(pg: PgPool) {
+        seed(&pg).await;
+        assert!(get_countries(&pg, Some(50), usize::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+        let all = list_all_sites(
+            &mut pg.begin().await.unwrap(),
+            SiteKey::new(CountryUrlId(""), CityUrlId(""), SiteUrlId("")),
+            None,
+            usize::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(all
+            .countries
+            .values()
+            .all(|co| co.cities.values().all(|ci| ci.sites.is_empty())));
+    }
+
+    #[sqlx::test]
+    async fn replace_country_matches_url_id_key(pg: PgPool) {
+        let before = ensure_site_relation(
+            &pg,
+            SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh")),
+        )
+        .await
+        .unwrap();
+        let country = Country {
+            url_id: "SE".into(),
+            ..Country::new("Sverige")
+        }
+        .with_city(
+            City {
+                url_id: "GBG".into(),
+                ..City::new("Göteborg")
+            }
+            .with_site(Site {
+                url_id: "lh".into(),
+                ..Site::new("Lindholmen")
+            }),
+        );
+        replace_country(&pg, country).await.unwrap();
+
+        let key = SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh"));
+        assert_eq!(get_site_relation(&pg, key).await.unwrap(), before);
+        let names: Vec<String> =
+            sqlx::query_scalar("select name from country where url_id_key(url_id) = 'se'")
+                .fetch_all(&pg)
+                .await
+                .unwrap();
+        assert_eq!(names, ["Sverige"]);
+    }
+
+    #[sqlx::test]
+    async fn duplicate_site_url_id_is_rejected(pg: PgPool) {
+        let city_id = ensure_site_relation(
+            &pg,
+            SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh")),
+        )
+        .await
+        .unwrap()
+        .city_id;
+        let site = |url_id: &str| Site {
+            url_id: url_id.into(),
+            ..Site::new("Eriksberg")
+        };
+        let is_conflict = |res: Result<Uuid>| {
+            res.unwrap_err()
+                .downcast_ref::<UrlIdConflict>()
+                .is_some_and(|c| c.what == "site")
+        };
+
+        let mut tx = pg.begin().await.unwrap();
+        assert!(is_conflict(
+            insert_site(&mut tx, city_id, &site("LH")).await
+        ));
+        drop(tx);
+
+        // both check before the other has inserted, so it takes the index to catch the second one
+        let mut first = pg.begin().await.unwrap();
+        insert_site(&mut first, city_id, &site("eriksberg"))
+            .await
+            .unwrap();
+        let second = tokio::spawn({
+            let pg = pg.clone();
+            async move {
+                let mut tx = pg.begin().await.unwrap();
+                insert_site(&mut tx, city_id, &site("Eriksberg")).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        first.commit().await.unwrap();
+        assert!(is_conflict(second.await.unwrap()));
+    }
+
+    #[sqlx::test]
+    async fn replace_country_removes_missing_cities(pg: PgPool) {
+        for (city, site) in [("gbg", "lh"), ("sthlm", "kista")] {
+            ensure_site_relation(
+                &pg,
+                SiteKey::new(CountryUrlId("se"), CityUrlId(city), SiteUrlId(site)),
+            )
+            .await
+            .unwrap();
+        }
+        let country = Country {
+            url_id: "se".into(),
+            ..Country::new("Sweden")
+        }
+        .with_city(
+            City {
+                url_id: "gbg".into(),
+                ..City::new("Göteborg")
+            }
+            .with_site(Site {
+                url_id: "eriksberg".into(),
+                ..Site::new("Eriksberg")
+            }),
+        );
+        replace_country(&pg, country).await.unwrap();
+
+        let sites: Vec<(String, String)> = sqlx::query_as(
+            r#"
+                select ci.url_id, si.url_id from site si
+                    join city ci using (city_id)
+                    join country co using (country_id)
+                    where co.url_id = 'se'
+            "#,
+        )
+        .fetch_all(&pg)
+        .await
+        .unwrap();
+        assert_eq!(sites, [("gbg".to_string(), "eriksberg".to_string())]);
+    }
+    #[sqlx::test]
+    async fn pool_stats_are_bounded_by_max_connections(
+        opts: PgPoolOptions,
+        connect: PgConnectOptions,
+    ) {
+        let pg = opts.max_connections(2).connect_with(connect).await.unwrap();
+        let a = pg.acquire().await.unwrap();
+        let b = pg.acquire().await.unwrap();
+        // a third connection would be over the limit
+        assert!(pg.try_acquire().is_none());
+        let stats = pool_stats(&pg);
+        assert_eq!(stats.max, 2);
+        assert_eq!((stats.size, stats.idle), (2, 0));
+
+        drop((a, b));
+        let stats = pool_stats(&pg);
+        assert!(stats.size <= stats.max);
+        assert!(stats.idle <= stats.size as usize);
+    }
 }
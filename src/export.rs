@@ -0,0 +1,336 @@
+// Exports of menus to formats other than JSON, for use in other tools, like spreadsheets
+
+use crate::models::api::{Dish, Restaurant, Site};
+use anyhow::Result;
+use chrono::{Local, NaiveDate, SecondsFormat, Utc};
+use std::fmt::Write;
+
+/// Columns of the CSV export, in order
+pub const CSV_HEADER: [&str; 6] = [
+    "restaurant",
+    "dish",
+    "description",
+    "comment",
+    "tags",
+    "price",
+];
+
+/// All dishes of the restaurants as CSV, with one row per dish, in the order they're given.
+/// Tags are joined with commas into a single column. The header row is always there, even if
+/// there are no dishes.
+pub fn to_csv(restaurants: &[Restaurant]) -> Result<Vec<u8>> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record(CSV_HEADER)?;
+    for r in restaurants {
+        for d in &r.dishes {
+            w.write_record([
+                r.name.as_str(),
+                d.name.as_str(),
+                d.description.as_deref().unwrap_or_default(),
+                d.comment.as_deref().unwrap_or_default(),
+                &d.tags.join(","),
+                &d.price.to_string(),
+            ])?;
+        }
+    }
+    Ok(w.into_inner()?)
+}
+
+// iCalendar content lines should be folded at 75 octets, with continuation lines starting with
+// a space
+const ICS_LINE_LIMIT: usize = 75;
+
+/// The site as an iCalendar, with one all-day event on the given day per restaurant, listing its
+/// dishes with prices. Restaurants without dishes get no event.
+/// Event UIDs are made from the restaurant id and the day, so calendar apps update the events of
+/// a day instead of adding duplicates when refreshing.
+pub fn to_ics(site: &Site, currency_suffix: &str, day: NaiveDate) -> String {
+    let mut out = String::new();
+    let mut line = |l: &str| fold_ics_line(&mut out, l);
+    line("BEGIN:VCALENDAR");
+    line("VERSION:2.0");
+    line("PRODID:-//rlunch//lunch menus//EN");
+    line("CALSCALE:GREGORIAN");
+    line(&format!("X-WR-CALNAME:{}", escape_ics(&site.name)));
+    let date = day.format("%Y%m%d");
+    let next = day.succ_opt().unwrap_or(day).format("%Y%m%d");
+    for r in site.restaurants.iter().filter(|r| !r.dishes.is_empty()) {
+        let description = r
+            .dishes
+            .iter()
+            .map(|d| escape_ics(&dish_line(d, currency_suffix)))
+            .collect::<Vec<_>>()
+            // an escaped newline, since the value has to be a single content line
+            .join("\\n");
+        line("BEGIN:VEVENT");
+        line(&format!("UID:{}-{date}@rlunch", r.restaurant_id));
+        line(&format!(
+            "DTSTAMP:{}",
+            r.parsed_at.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ")
+        ));
+        line(&format!("DTSTART;VALUE=DATE:{date}"));
+        line(&format!("DTEND;VALUE=DATE:{next}"));
+        line(&format!("SUMMARY:{}", escape_ics(&r.name)));
+        line(&format!("DESCRIPTION:{description}"));
+        if let Some(ref v) = r.address {
+            line(&format!("LOCATION:{}", escape_ics(v)));
+        }
+        if let Some(ref v) = r.url {
+            line(&format!("URL:{v}"));
+        }
+        line("END:VEVENT");
+    }
+    line("END:VCALENDAR");
+    out
+}
+
+/// A dish as a single line, like "Name: description, 129.9 kr"
+fn dish_line(d: &Dish, currency_suffix: &str) -> String {
+    let mut s = d.name.clone();
+    if let Some(ref v) = d.description {
+        let _ = write!(s, ": {v}");
+    }
+    let _ = write!(
+        s,
+        ", {} {currency_suffix}",
+        d.price.as_decimal().normalize()
+    );
+    s.trim_end().to_string()
+}
+
+/// Escape text values, see RFC 5545 section 3.3.11
+fn escape_ics(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Append the line to out, folded so no line is longer than ICS_LINE_LIMIT octets, without
+/// splitting any UTF-8 sequences
+fn fold_ics_line(out: &mut String, line: &str) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > ICS_LINE_LIMIT {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// The site as an Atom feed, with an entry per restaurant with dishes, listing them with prices.
+/// Entry ids are made from the restaurant id, so feed readers see a changed menu as an update of
+/// the same entry.
+pub fn to_atom(site: &Site, currency_suffix: &str) -> String {
+    let restaurants = site.restaurants.iter().filter(|r| !r.dishes.is_empty());
+    // the feed is as new as its newest entry, or now if there's none
+    let updated = restaurants
+        .clone()
+        .map(|r| r.parsed_at)
+        .max()
+        .unwrap_or_else(Local::now)
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    let _ = writeln!(out, "  <id>urn:uuid:{}</id>", site.site_id);
+    let _ = writeln!(out, "  <title>Lunch at {}</title>", escape_xml(&site.name));
+    let _ = writeln!(out, "  <updated>{updated}</updated>");
+    out.push_str("  <author><name>rlunch</name></author>\n");
+    for r in restaurants {
+        let content = r
+            .dishes
+            .iter()
+            .map(|d| dish_line(d, currency_suffix))
+            .collect::<Vec<_>>()
+            .join("\n");
+        out.push_str("  <entry>\n");
+        let _ = writeln!(out, "    <id>urn:uuid:{}</id>", r.restaurant_id);
+        let _ = writeln!(out, "    <title>{}</title>", escape_xml(&r.name));
+        let _ = writeln!(
+            out,
+            "    <updated>{}</updated>",
+            r.parsed_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        if let Some(ref v) = r.url {
+            let _ = writeln!(out, "    <link href=\"{}\"/>", escape_xml(v));
+        }
+        let _ = writeln!(
+            out,
+            "    <content type=\"text\">{}</content>",
+            escape_xml(&content)
+        );
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+fn escape_xml(v: &str) -> String {
+    let mut out = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Price;
+    use uuid::Uuid;
+
+    fn dish(name: &str, description: Option<&str>, price: &str) -> Dish {
+        Dish {
+            name: name.into(),
+            description: description.map(Into::into),
+            price: price.parse::<Price>().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn restaurant(name: &str, dishes: Vec<Dish>) -> Restaurant {
+        Restaurant {
+            restaurant_id: Uuid::new_v4(),
+            name: name.into(),
+            parsed_at: Local::now(),
+            dishes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let mut d = dish("Pasta, \"al dente\"", Some("med\nparmesan"), "129.90");
+        d.tags = vec!["vego".into(), "gluten".into()];
+        let csv = to_csv(&[restaurant("Kårhuset", vec![d, dish("Soppa", None, "85")])]).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            concat!(
+                "restaurant,dish,description,comment,tags,price\n",
+                "Kårhuset,\"Pasta, \"\"al dente\"\"\",\"med\nparmesan\",,\"vego,gluten\",129.90\n",
+                "Kårhuset,Soppa,,,,85\n",
+            )
+        );
+
+        let csv = to_csv(&[]).unwrap();
+        assert_eq!(csv, b"restaurant,dish,description,comment,tags,price\n");
+    }
+
+    fn site(restaurants: Vec<Restaurant>) -> Site {
+        Site {
+            site_id: Uuid::new_v4(),
+            name: "Lindholmen".into(),
+            restaurants,
+            ..Default::default()
+        }
+    }
+
+    /// Undo the line folding, see fold_ics_line
+    fn unfold(ics: &str) -> Vec<String> {
+        ics.replace("\r\n ", "")
+            .split("\r\n")
+            .map(Into::into)
+            .collect()
+    }
+
+    #[test]
+    fn ics_folding() {
+        let mut out = String::new();
+        fold_ics_line(&mut out, &"x".repeat(75));
+        assert_eq!(out, format!("{}\r\n", "x".repeat(75)));
+
+        let mut out = String::new();
+        fold_ics_line(&mut out, &"x".repeat(151));
+        assert_eq!(
+            out,
+            format!("{}\r\n {}\r\n {}\r\n", "x".repeat(75), "x".repeat(74), "xx")
+        );
+
+        // multi-byte characters are moved to the next line whole
+        let mut out = String::new();
+        fold_ics_line(&mut out, &"å".repeat(40));
+        assert_eq!(out, format!("{}\r\n {}\r\n", "å".repeat(37), "å".repeat(3)));
+    }
+
+    #[test]
+    fn ics_escaping() {
+        assert_eq!(escape_ics("a, b; c\\d\r\ne"), "a\\, b\\; c\\\\d\\ne");
+
+        let long = ["lång beskrivning"; 10].join(" ");
+        let r = restaurant(
+            "Kök, bar; & café",
+            vec![
+                dish("Köttbullar", Some(&long), "125"),
+                dish("Soppa", Some("tomat,\nbasilika"), "85"),
+            ],
+        );
+        let ics = to_ics(
+            &site(vec![r]),
+            "kr",
+            NaiveDate::from_ymd_opt(2024, 5, 17).unwrap(),
+        );
+        for line in ics.split("\r\n") {
+            assert!(line.len() <= ICS_LINE_LIMIT, "{line}");
+        }
+        let lines = unfold(&ics);
+        assert!(lines.contains(&"SUMMARY:Kök\\, bar\\; & café".to_string()));
+        assert!(lines.contains(&"DTSTART;VALUE=DATE:20240517".to_string()));
+        assert!(lines.contains(&"DTEND;VALUE=DATE:20240518".to_string()));
+        let description = lines
+            .iter()
+            .find_map(|l| l.strip_prefix("DESCRIPTION:"))
+            .unwrap();
+        assert_eq!(
+            description,
+            format!(
+                "Köttbullar: {}\\, 125 kr\\nSoppa: tomat\\,\\nbasilika\\, 85 kr",
+                long
+            )
+        );
+    }
+
+    #[test]
+    fn atom_escaping() {
+        let r = Restaurant {
+            url: Some("https://example.com/?a=1&b=2".into()),
+            ..restaurant(
+                "Fish & <Chips>",
+                vec![dish("Räksmörgås \"deluxe\" & 'extra' <stor>", None, "145")],
+            )
+        };
+        let empty = restaurant("Stängt", vec![]);
+        let feed = to_atom(&site(vec![r, empty]), "kr");
+
+        assert!(feed.contains("<title>Fish &amp; &lt;Chips&gt;</title>"));
+        assert!(feed.contains("<link href=\"https://example.com/?a=1&amp;b=2\"/>"));
+        assert!(feed.contains(
+            "<content type=\"text\">Räksmörgås &quot;deluxe&quot; &amp; &apos;extra&apos; \
+             &lt;stor&gt;, 145 kr</content>"
+        ));
+        // nothing unescaped left over, and no entry for the restaurant without dishes
+        assert!(!feed.contains("& "));
+        assert!(!feed.contains("<stor>"));
+        assert!(!feed.contains("Stängt"));
+        assert_eq!(feed.matches("<entry>").count(), 1);
+    }
+}
@@ -0,0 +1,86 @@
+// A throwaway HTTP server serving files from a directory, so that scrapers can be developed and
+// tested against saved copies of the pages they scrape, instead of having to run a separate local
+// web server and edit the scrape URLs by hand.
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::trace;
+
+#[derive(Debug)]
+pub struct FixtureServer {
+    addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl FixtureServer {
+    /// Start serving the files in dir on a random local port
+    pub async fn start(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = Arc::new(dir.into());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        trace!(%addr, ?dir, "Starting fixture server...");
+        let router = Router::new()
+            .route("/*path", get(serve_file))
+            .with_state(dir);
+        let handle = tokio::spawn(async move {
+            axum::serve(listener, router)
+                .await
+                .expect("fixture server failed");
+        });
+        Ok(Self { addr, handle })
+    }
+
+    /// Base URL of the server, without a trailing slash
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Full URL for the given fixture path
+    pub fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url(), path.trim_start_matches('/'))
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn serve_file(State(dir): State<Arc<PathBuf>>, Path(path): Path<String>) -> Response {
+    // don't let requests escape the fixture dir
+    if path.split('/').any(|p| p == "..") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let file = dir.join(&path);
+    match read(file.clone()).await {
+        Ok(body) => ([(header::CONTENT_TYPE, content_type(&path))], body).into_response(),
+        Err(err) => {
+            trace!(?file, %err, "Fixture not found");
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+async fn read(file: PathBuf) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || {
+        std::fs::read(&file).with_context(|| file.display().to_string())
+    })
+    .await?
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        _ => "text/plain; charset=utf-8",
+    }
+}
@@ -1,6 +1,10 @@
 pub mod cache;
 pub mod cli;
 pub mod db;
+pub mod export;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fixtures;
+pub mod metrics;
 pub mod models;
 pub mod scrape;
 pub mod scrapers;
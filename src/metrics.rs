@@ -0,0 +1,154 @@
+// Metrics are recorded with the macros from the metrics crate wherever it makes sense, and are
+// no-ops until a recorder is installed, which is only done by the servers that expose them. This
+// means scrape metrics are only collected when the scrape command is given an address to serve
+// them on, or when scraping runs in the same process as a server with metrics enabled.
+
+use crate::signals::shutdown_signal;
+use anyhow::{Context, Result};
+use axum::{http::header, routing::get, Router};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpListener;
+use tracing::trace;
+
+/// Buckets for all duration histograms, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Buckets for scraper run durations, which include all requests for a site, so they're a lot
+/// longer than the rest
+const SCRAPE_DURATION_BUCKETS: &[f64] =
+    &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+/// Content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// How often the recorder is told to clean up and compact its histograms
+pub const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Handle to the global Prometheus recorder, for rendering all recorded metrics
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    handle: PrometheusHandle,
+}
+
+impl Metrics {
+    /// Install the global recorder, if not already done, and return a handle to it.
+    /// There can only be one recorder per process, so this fails if some other recorder has
+    /// been installed.
+    pub fn install() -> Result<Self> {
+        if let Some(handle) = HANDLE.get() {
+            return Ok(Self {
+                handle: handle.clone(),
+            });
+        }
+        let handle = builder()?.install_recorder()?;
+        Ok(Self {
+            handle: HANDLE.get_or_init(|| handle).clone(),
+        })
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+
+    /// Run upkeep on the recorder every tick, until the process exits
+    pub async fn run_upkeep(self, every: Duration) {
+        let mut ticker = tokio::time::interval(every);
+        loop {
+            ticker.tick().await;
+            self.handle.run_upkeep();
+        }
+    }
+}
+
+/// Prometheus builder with the buckets for all histograms set
+fn builder() -> Result<PrometheusBuilder> {
+    // full matches take precedence over suffix matches
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("scrape_duration_seconds".into()),
+            SCRAPE_DURATION_BUCKETS,
+        )?
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), DURATION_BUCKETS)?)
+}
+
+/// Serve all metrics at /metrics on the given address, until shutdown.
+/// Used by the scrape command, which has no other server to expose them on.
+pub async fn serve(addr: &str) -> Result<()> {
+    trace!(addr, "Starting metrics server...");
+    let m = Metrics::install()?;
+    tokio::spawn(m.clone().run_upkeep(UPKEEP_INTERVAL));
+    let router = Router::new().route(
+        "/metrics",
+        get(|| async move { ([(header::CONTENT_TYPE, CONTENT_TYPE)], m.render()) }),
+    );
+    axum::serve(TcpListener::bind(addr).await?, router)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .context("failed to start metrics server")
+}
+
+/// Record a handled HTTP request. The path should be the matched route, not the actual path, to
+/// keep the number of label values down.
+pub fn record_request(method: &str, path: &str, status: u16, duration: Duration) {
+    let labels = [
+        ("method", method.to_string()),
+        ("path", path.to_string()),
+        ("status", status.to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels).record(duration);
+}
+
+/// Record the time taken by the DB calls for the named query, measured from start
+pub fn record_db_query(query: &'static str, start: Instant) {
+    metrics::histogram!("db_query_duration_seconds", "query" => query).record(start.elapsed());
+}
+
+/// Count a finished scrape for the given scraper
+pub fn record_scrape(scraper: &'static str, success: bool) {
+    let result = if success { "success" } else { "failure" };
+    metrics::counter!("scrapes_total", "scraper" => scraper, "result" => result).increment(1);
+}
+
+/// Record the time taken by a single run of the given scraper, including failed attempts
+pub fn record_scrape_duration(scraper: &'static str, success: bool, duration: Duration) {
+    let result = if success { "success" } else { "failure" };
+    metrics::histogram!("scrape_duration_seconds", "scraper" => scraper, "result" => result)
+        .record(duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_duration() {
+        // a local recorder, since the global one can only be installed once per process
+        let recorder = builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            record_scrape_duration("test", true, Duration::from_secs(3));
+        });
+        let out = handle.render();
+        let bucket = |le| {
+            format!(
+                r#"scrape_duration_seconds_bucket{{scraper="test",result="success",le="{le}"}}"#
+            )
+        };
+        assert!(out.contains(&format!("{} 0", bucket("2.5"))), "{out}");
+        assert!(out.contains(&format!("{} 1", bucket("5"))), "{out}");
+        assert!(
+            out.contains(r#"scrape_duration_seconds_count{scraper="test",result="success"} 1"#),
+            "{out}"
+        );
+    }
+}
@@ -4,11 +4,14 @@
 
 use anyhow::Result;
 use chrono::{DateTime, Local};
-use serde::{Deserialize, Serialize};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    collections::hash_map::HashMap,
+    collections::hash_map::{Entry, HashMap},
     convert::From,
+    fmt,
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 use uuid::Uuid;
 
@@ -47,6 +50,101 @@ impl<T: Id> UuidMap<T> {
     pub fn add(&mut self, v: T) -> Option<T> {
         self.insert(v.id(), v)
     }
+
+    /// Move all values from other into self, combining values with the same id using f
+    pub fn merge_with(&mut self, other: Self, f: impl Fn(&mut T, T)) {
+        for (id, v) in other.0 {
+            match self.entry(id) {
+                Entry::Occupied(mut e) => f(e.get_mut(), v),
+                Entry::Vacant(e) => {
+                    e.insert(v);
+                }
+            }
+        }
+    }
+}
+
+/// A price with exact decimals, so that e.g. 129.90 doesn't turn into 129.9000015 like it would as a
+/// float. Negative prices are clamped to zero, which is the same as a missing price.
+/// Serialized as a plain number, to keep the API output the same as before.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct Price(Decimal);
+
+impl Price {
+    pub const ZERO: Self = Self(Decimal::ZERO);
+
+    pub fn new(v: Decimal) -> Self {
+        Self(v.max(Decimal::ZERO))
+    }
+
+    pub fn as_decimal(&self) -> Decimal {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Round to the nearest multiple of `to`, e.g. 1 for whole krona, with halves rounded up.
+    /// The price is left as is if `to` is zero or negative.
+    pub fn round_to(self, to: Decimal) -> Self {
+        if to <= Decimal::ZERO {
+            return self;
+        }
+        // Decimal::round rounds halves to even, which would make 128.50 come out as 128
+        let n = (self.0 / to).round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero);
+        Self::new(n * to)
+    }
+}
+
+impl From<Decimal> for Price {
+    fn from(v: Decimal) -> Self {
+        Self::new(v)
+    }
+}
+
+impl FromStr for Price {
+    type Err = rust_decimal::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Decimal::from_str(s.trim()).map(Self::new)
+    }
+}
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Serialize for Price {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.0.to_f64().unwrap_or_default())
+    }
+}
+
+/// Accepts both numbers and strings, since external feeds often have the price as text, like
+/// "129,90 kr". Strings are parsed like scraped prices, see util::parse_price.
+impl<'de> Deserialize<'de> for Price {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(f64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(v) => Decimal::from_f64(v)
+                .map(Self::new)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid price: {v}"))),
+            // a comma is a thousands separator if there's also a decimal point, otherwise it's
+            // a decimal comma
+            Raw::Text(s) if s.contains('.') => Ok(crate::util::parse_price(&s.replace(',', ""))),
+            Raw::Text(s) => Ok(crate::util::parse_price(&s.replace(',', "."))),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, sqlx::FromRow)]
@@ -70,7 +168,10 @@ pub struct Dish {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub tags: Vec<String>,
     /// Price, in whatever currency is in use
-    pub price: f32,
+    pub price: Price,
+    /// The page the dish was scraped from, for tracing odd looking dishes back to the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
 }
 
 impl Dish {
@@ -88,6 +189,43 @@ impl Dish {
             ..self
         }
     }
+
+    /// Check if the dish contains all words in the query, in any of the name, description, comment
+    /// or tags, ignoring case and accents, so "kottbullar" matches "Köttbullar".
+    /// A query without any words matches nothing.
+    pub fn matches(&self, query: &str) -> bool {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(crate::util::fold_text)
+            .collect();
+        self.matches_words(&words)
+    }
+
+    /// Same as matches, but with the query already split and folded, to only do that once when
+    /// matching many dishes
+    fn matches_words(&self, words: &[String]) -> bool {
+        if words.is_empty() {
+            return false;
+        }
+        let mut text = crate::util::fold_text(&self.name);
+        for s in [&self.description, &self.comment].into_iter().flatten() {
+            text.push(' ');
+            text.push_str(&crate::util::fold_text(s));
+        }
+        for t in &self.tags {
+            text.push(' ');
+            text.push_str(&crate::util::fold_text(t));
+        }
+        words.iter().all(|w| text.contains(w.as_str()))
+    }
+
+    /// Round the price to the nearest multiple of `to`, e.g. 1.0 for whole krona.
+    /// The price is left as is if `to` is zero or negative.
+    pub fn round_price(&mut self, to: f32) {
+        if let Some(to) = Decimal::from_f32(to) {
+            self.price = self.price.round_to(to);
+        }
+    }
 }
 
 impl Id for Dish {
@@ -96,18 +234,31 @@ impl Id for Dish {
     }
 }
 
-// impl From<api::Dish> for Dish {
-//     fn from(dish: api::Dish) -> Self {
-//         Self {
-//             name: dish.name,
-//             description: dish.description,
-//             comment: dish.comment,
-//             tags: dish.tags,
-//             price: dish.price,
-//             ..Default::default()
-//         }
-//     }
-// }
+/// Converts from the API shape, e.g. for input to the admin server.
+/// A nil dish_id gets a new one, since that's what it would be if left out of the input.
+impl From<api::Dish> for Dish {
+    fn from(dish: api::Dish) -> Self {
+        Self {
+            dish_id: or_new_id(dish.dish_id),
+            restaurant_id: dish.restaurant_id,
+            name: dish.name,
+            description: dish.description,
+            comment: dish.comment,
+            tags: dish.tags,
+            price: dish.price,
+            source_url: dish.source_url,
+        }
+    }
+}
+
+/// Returns the given id, or a new one if it's nil
+fn or_new_id(id: Uuid) -> Uuid {
+    if id.is_nil() {
+        Uuid::new_v4()
+    } else {
+        id
+    }
+}
 
 /// DishRows maps a list of Dish into lists of all its fields.
 /// The intended use is together with Postgres' UNNEST, to be able to do batch insert of many
@@ -119,8 +270,9 @@ pub struct DishRows {
     pub names: Vec<String>,
     pub descriptions: Vec<Option<String>>,
     pub comments: Vec<Option<String>>,
-    pub tags: Vec<String>, // comma separated list
-    pub prices: Vec<f32>,
+    pub tags: Vec<Vec<String>>,
+    pub prices: Vec<Price>,
+    pub source_urls: Vec<Option<String>>,
 }
 
 impl DishRows {
@@ -133,6 +285,7 @@ impl DishRows {
             comments: Vec::with_capacity(cap),
             tags: Vec::with_capacity(cap),
             prices: Vec::with_capacity(cap),
+            source_urls: Vec::with_capacity(cap),
         }
     }
 
@@ -144,6 +297,7 @@ impl DishRows {
         self.comments.extend(other.comments);
         self.tags.extend(other.tags);
         self.prices.extend(other.prices);
+        self.source_urls.extend(other.source_urls);
     }
 }
 
@@ -157,8 +311,9 @@ impl From<UuidMap<Dish>> for DishRows {
             dr.names.push(v.name);
             dr.descriptions.push(v.description);
             dr.comments.push(v.comment);
-            dr.tags.push(v.tags.join(",")); // flatten the list to comma separated values
+            dr.tags.push(v.tags);
             dr.prices.push(v.price);
+            dr.source_urls.push(v.source_url);
         }
 
         dr
@@ -176,6 +331,9 @@ pub struct Restaurant {
     /// Name of restaurant
     #[sqlx(rename = "restaurant_name")]
     pub name: String,
+    /// Slug of the name, for looking up the restaurant by key. Set by the DB on insert.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub url_id: String,
     /// Extra info
     #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
@@ -188,6 +346,12 @@ pub struct Restaurant {
     /// Google maps URL
     #[serde(skip_serializing_if = "Option::is_none")]
     pub map_url: Option<String>,
+    /// Latitude, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    /// Longitude, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
     /// When the scraping was last done
     #[sqlx(rename = "created_at")]
     pub parsed_at: DateTime<Local>,
@@ -231,6 +395,23 @@ impl Restaurant {
         self.set_dishes(dishes);
         self
     }
+
+    /// Merge another version of this restaurant into this one.
+    /// All fields are taken from whichever was parsed most recently, and dishes are unioned by id,
+    /// with the most recent version winning if both have the same dish.
+    pub fn merge(&mut self, mut other: Self) {
+        let newer = other.parsed_at > self.parsed_at;
+        let dishes = std::mem::take(&mut other.dishes);
+        if newer {
+            other.dishes = std::mem::take(&mut self.dishes);
+            *self = other;
+        }
+        self.dishes.merge_with(dishes, |d, o| {
+            if newer {
+                *d = o;
+            }
+        });
+    }
 }
 
 impl Id for Restaurant {
@@ -239,20 +420,33 @@ impl Id for Restaurant {
     }
 }
 
-// impl From<api::Restaurant> for Restaurant {
-//     fn from(restaurant: api::Restaurant) -> Self {
-//         Self {
-//             name: restaurant.name,
-//             comment: restaurant.comment,
-//             address: restaurant.address,
-//             url: restaurant.url,
-//             map_url: restaurant.map_url,
-//             parsed_at: restaurant.parsed_at,
-//             dishes: restaurant.dishes.into(),
-//             ..Default::default()
-//         }
-//     }
-// }
+/// Converts from the API shape, e.g. for input to the admin server.
+/// A nil restaurant_id gets a new one, and all dishes are set to belong to the restaurant.
+impl From<api::Restaurant> for Restaurant {
+    fn from(restaurant: api::Restaurant) -> Self {
+        let restaurant_id = or_new_id(restaurant.restaurant_id);
+        // convert before building the map, so that dishes with new ids are keyed by those
+        let dishes: Vec<Dish> = restaurant
+            .dishes
+            .into_iter()
+            .map(|d| Dish::from(d).for_restaurant(restaurant_id))
+            .collect();
+        Self {
+            restaurant_id,
+            site_id: restaurant.site_id,
+            name: restaurant.name,
+            url_id: restaurant.url_id,
+            comment: restaurant.comment,
+            address: restaurant.address,
+            url: restaurant.url,
+            map_url: restaurant.map_url,
+            lat: restaurant.lat,
+            lon: restaurant.lon,
+            parsed_at: restaurant.parsed_at,
+            dishes: dishes.into(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct RestaurantRows {
@@ -263,6 +457,8 @@ pub struct RestaurantRows {
     pub addresses: Vec<Option<String>>,
     pub urls: Vec<Option<String>>,
     pub map_urls: Vec<Option<String>>,
+    pub lats: Vec<Option<f64>>,
+    pub lons: Vec<Option<f64>>,
     pub parsed_ats: Vec<DateTime<Local>>,
     pub dishes: DishRows,
 }
@@ -277,6 +473,8 @@ impl RestaurantRows {
             addresses: Vec::with_capacity(cap),
             urls: Vec::with_capacity(cap),
             map_urls: Vec::with_capacity(cap),
+            lats: Vec::with_capacity(cap),
+            lons: Vec::with_capacity(cap),
             parsed_ats: Vec::with_capacity(cap),
             dishes: DishRows::with_capacity(cap), // might be good to use a larger size here
         }
@@ -295,6 +493,8 @@ impl From<Vec<Restaurant>> for RestaurantRows {
             rr.addresses.push(r.address);
             rr.urls.push(r.url);
             rr.map_urls.push(r.map_url);
+            rr.lats.push(r.lat);
+            rr.lons.push(r.lon);
             rr.parsed_ats.push(r.parsed_at);
             rr.dishes.extend(r.dishes.into());
         }
@@ -315,6 +515,9 @@ pub struct Site {
     pub url_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
+    /// Overrides the currency_suffix of the country, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency_suffix: Option<String>,
     #[sqlx(skip)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub restaurants: UuidMap<Restaurant>,
@@ -367,6 +570,12 @@ impl Site {
         self.add_dishes(dishes);
         self
     }
+
+    /// Merge the restaurants of another version of this site into this one
+    pub fn merge(&mut self, other: Self) {
+        self.restaurants
+            .merge_with(other.restaurants, Restaurant::merge);
+    }
 }
 
 impl Id for Site {
@@ -423,6 +632,11 @@ impl City {
         self.set_sites(sites);
         self
     }
+
+    /// Merge the sites of another version of this city into this one
+    pub fn merge(&mut self, other: Self) {
+        self.sites.merge_with(other.sites, Site::merge);
+    }
 }
 
 impl Id for City {
@@ -441,6 +655,9 @@ pub struct Country {
     pub url_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub currency_suffix: Option<String>,
+    /// Whether dishes without a price are actually free here, rather than parse failures
+    #[serde(skip_serializing)]
+    pub allow_free_dishes: bool,
     #[sqlx(skip)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub cities: UuidMap<City>,
@@ -471,6 +688,11 @@ impl Country {
         self.set_cities(cities);
         self
     }
+
+    /// Merge the cities of another version of this country into this one
+    pub fn merge(&mut self, other: Self) {
+        self.cities.merge_with(other.cities, City::merge);
+    }
 }
 
 impl Id for Country {
@@ -511,6 +733,118 @@ impl LunchData {
         self
     }
 
+    /// Deep merge another tree into this one, e.g. to combine the results of several queries.
+    /// Items with the same id on each level get their children merged instead of replaced, see
+    /// Restaurant::merge for how collisions at the bottom are resolved.
+    pub fn merge(&mut self, other: Self) {
+        self.countries.merge_with(other.countries, Country::merge);
+    }
+
+    /// Count the items on each level of the tree
+    pub fn counts(&self) -> Counts {
+        let mut c = Counts {
+            countries: self.countries.len(),
+            ..Default::default()
+        };
+        for co in self.countries.values() {
+            c.cities += co.cities.len();
+            for ci in co.cities.values() {
+                c.sites += ci.sites.len();
+                for si in ci.sites.values() {
+                    c.restaurants += si.restaurants.len();
+                    for r in si.restaurants.values() {
+                        c.dishes += r.dishes.len();
+                    }
+                }
+            }
+        }
+        c
+    }
+
+    /// Only keep dishes with a price within the given range, and the restaurants that still have
+    /// any dishes left. Dishes without a price are removed if there's a lower bound.
+    pub fn retain_price_range(&mut self, min: Option<Price>, max: Option<Price>) {
+        let keep = |d: &Dish| match (min, max) {
+            (Some(_), _) if d.price.is_zero() => false,
+            (Some(min), _) if d.price < min => false,
+            (_, Some(max)) if d.price > max => false,
+            _ => true,
+        };
+        self.countries
+            .values_mut()
+            .flat_map(|co| co.cities.values_mut())
+            .flat_map(|ci| ci.sites.values_mut())
+            .for_each(|si| {
+                si.restaurants.retain(|_, r| {
+                    r.dishes.retain(|_, d| keep(d));
+                    !r.dishes.is_empty()
+                })
+            });
+    }
+
+    /// Only keep dishes that have all of the given tags, or any of them if `any` is set, and the
+    /// restaurants that still have any dishes left
+    pub fn retain_tags(&mut self, tags: &[&str], any: bool) {
+        let keep = |d: &Dish| {
+            let has = |t: &&str| d.tags.iter().any(|dt| dt == t);
+            if any {
+                tags.iter().any(has)
+            } else {
+                tags.iter().all(has)
+            }
+        };
+        self.countries
+            .values_mut()
+            .flat_map(|co| co.cities.values_mut())
+            .flat_map(|ci| ci.sites.values_mut())
+            .for_each(|si| {
+                si.restaurants.retain(|_, r| {
+                    r.dishes.retain(|_, d| keep(d));
+                    !r.dishes.is_empty()
+                })
+            });
+    }
+
+    /// Get a copy of the tree with only the dishes matching the query, see Dish::matches.
+    /// Restaurants, sites, cities and countries without any matching dishes are left out.
+    pub fn search(&self, query: &str) -> Self {
+        let words: Vec<String> = query
+            .split_whitespace()
+            .map(crate::util::fold_text)
+            .collect();
+        let mut res = self.clone();
+        res.countries.retain(|_, co| {
+            co.cities.retain(|_, ci| {
+                ci.sites.retain(|_, si| {
+                    si.restaurants.retain(|_, r| {
+                        r.dishes.retain(|_, d| d.matches_words(&words));
+                        !r.dishes.is_empty()
+                    });
+                    !si.restaurants.is_empty()
+                });
+                !ci.sites.is_empty()
+            });
+            !co.cities.is_empty()
+        });
+        res
+    }
+
+    /// Remove all dishes without a price, except in countries that allow free dishes, and the
+    /// restaurants that are left without any dishes
+    pub fn drop_zero_price(&mut self) {
+        self.countries
+            .values_mut()
+            .filter(|co| !co.allow_free_dishes)
+            .flat_map(|co| co.cities.values_mut())
+            .flat_map(|ci| ci.sites.values_mut())
+            .for_each(|si| {
+                si.restaurants.retain(|_, r| {
+                    r.dishes.retain(|_, d| !d.price.is_zero());
+                    !r.dishes.is_empty()
+                })
+            });
+    }
+
     pub fn build(
         countries: Vec<Country>,
         cities: Vec<City>,
@@ -574,6 +908,48 @@ impl LunchData {
     }
 }
 
+/// Number of items on each level of a LunchData tree
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub countries: usize,
+    pub cities: usize,
+    pub sites: usize,
+    pub restaurants: usize,
+    pub dishes: usize,
+}
+
+/// How many dishes are tagged with a given tag
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, sqlx::FromRow)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: i64,
+}
+
+/// Outcome of the last runs of a scraper
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, sqlx::FromRow)]
+#[serde(default)]
+#[sqlx(default)]
+pub struct ScraperStatus {
+    pub scraper_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success_at: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_failure_at: Option<DateTime<Local>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_dish_count: Option<i32>,
+}
+
+/// Whether a scraper is started. Scrapers are enabled unless set otherwise.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, sqlx::FromRow)]
+pub struct ScraperFlag {
+    pub scraper_name: String,
+    pub enabled: bool,
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
@@ -592,28 +968,56 @@ pub mod api {
     // This module contains the same structs as the parent,
     // where maps have been converted to vecs, for easier use in templates
     // and possibly elsewhere
+    use super::Price;
     use chrono::{DateTime, Local};
     use serde::{Deserialize, Serialize};
-    use std::convert::From;
+    use std::{cmp::Ordering, convert::From};
     use uuid::Uuid;
 
+    /// Order of dishes within each restaurant
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum DishSort {
+        #[default]
+        Name,
+        PriceAsc,
+        PriceDesc,
+    }
+
+    // The aliases on the fields below are for accepting JSON from external scrapers and feeds
+    // as is, since they tend to use other names for the same things. They only affect
+    // deserializing, so output always uses the field names.
+
     #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
     #[serde(default)]
     pub struct Dish {
         pub dish_id: Uuid,
         pub restaurant_id: Uuid,
-        /// Name of the dish, e.g. "meatballs"
+        /// Name of the dish, e.g. "meatballs".
+        /// Also accepted as "title" or "dish_name".
+        #[serde(alias = "title", alias = "dish_name")]
         pub name: String,
-        /// More details about the dish, e.g. "with spaghetti"
+        /// More details about the dish, e.g. "with spaghetti".
+        /// Also accepted as "desc" or "details".
+        #[serde(alias = "desc", alias = "details")]
         #[serde(skip_serializing_if = "Option::is_none")]
         pub description: Option<String>,
         // Extra info, e.g. "contains nuts"
         #[serde(skip_serializing_if = "Option::is_none")]
         pub comment: Option<String>,
-        /// Optionals tags for filtering, e.g. "vego,gluten,lactose"
+        /// Optionals tags for filtering, e.g. "vego,gluten,lactose".
+        /// Also accepted as "labels".
+        #[serde(alias = "labels")]
         pub tags: Vec<String>,
-        /// Price, in whatever currency is in use
-        pub price: f32,
+        /// Price, in whatever currency is in use, either as a number or a string like "129,90 kr".
+        /// Also accepted as "price_text" or "cost".
+        #[serde(alias = "price_text", alias = "cost")]
+        pub price: Price,
+        /// The page the dish was scraped from, for tracing odd looking dishes back to the source.
+        /// Also accepted as "url" or "source".
+        #[serde(alias = "url", alias = "source")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub source_url: Option<String>,
     }
 
     impl super::Id for Dish {
@@ -632,6 +1036,7 @@ pub mod api {
                 comment: dish.comment,
                 tags: dish.tags,
                 price: dish.price,
+                source_url: dish.source_url,
             }
         }
     }
@@ -641,23 +1046,44 @@ pub mod api {
     pub struct Restaurant {
         pub restaurant_id: Uuid,
         pub site_id: Uuid,
-        /// Name of restaurant
+        /// Name of restaurant.
+        /// Also accepted as "title" or "restaurant_name".
+        #[serde(alias = "title", alias = "restaurant_name")]
         pub name: String,
+        /// Slug of the name, for looking up the restaurant by key
+        #[serde(skip_serializing_if = "String::is_empty")]
+        pub url_id: String,
         /// Extra info
         #[serde(skip_serializing_if = "Option::is_none")]
         pub comment: Option<String>,
         /// Street address
         #[serde(skip_serializing_if = "Option::is_none")]
         pub address: Option<String>,
-        /// Homepage
+        /// Homepage.
+        /// Also accepted as "homepage" or "website".
+        #[serde(alias = "homepage", alias = "website")]
         #[serde(skip_serializing_if = "Option::is_none")]
         pub url: Option<String>,
         /// Google maps URL
         #[serde(skip_serializing_if = "Option::is_none")]
         pub map_url: Option<String>,
-        /// When the scraping was last done
+        /// Latitude, if known.
+        /// Also accepted as "latitude".
+        #[serde(alias = "latitude")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub lat: Option<f64>,
+        /// Longitude, if known.
+        /// Also accepted as "longitude" or "lng".
+        #[serde(alias = "longitude", alias = "lng")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub lon: Option<f64>,
+        /// When the scraping was last done.
+        /// Also accepted as "scraped_at".
+        #[serde(alias = "scraped_at")]
         pub parsed_at: DateTime<Local>,
-        /// List of current dishes
+        /// List of current dishes.
+        /// Also accepted as "menu" or "items".
+        #[serde(alias = "menu", alias = "items")]
         pub dishes: Vec<Dish>,
     }
 
@@ -675,10 +1101,13 @@ pub mod api {
                 restaurant_id: restaurant.restaurant_id,
                 site_id: restaurant.site_id,
                 name: restaurant.name,
+                url_id: restaurant.url_id,
                 comment: restaurant.comment,
                 address: restaurant.address,
                 url: restaurant.url,
                 map_url: restaurant.map_url,
+                lat: restaurant.lat,
+                lon: restaurant.lon,
                 parsed_at: restaurant.parsed_at,
                 dishes,
             }
@@ -694,6 +1123,9 @@ pub mod api {
         pub url_id: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub comment: Option<String>,
+        /// Overrides the currency_suffix of the country, if set
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub currency_suffix: Option<String>,
         pub restaurants: Vec<Restaurant>,
     }
 
@@ -713,6 +1145,7 @@ pub mod api {
                 name: s.name,
                 url_id: s.url_id,
                 comment: s.comment,
+                currency_suffix: s.currency_suffix,
                 restaurants,
             }
         }
@@ -789,6 +1222,147 @@ pub mod api {
         pub fn new() -> Self {
             Default::default()
         }
+
+        /// Flatten the tree into a list of all cities, dropping the parent countries
+        pub fn into_cities(self) -> Vec<City> {
+            self.countries.into_iter().flat_map(|c| c.cities).collect()
+        }
+
+        /// Flatten the tree into a list of all sites, dropping all parents
+        pub fn into_sites(self) -> Vec<Site> {
+            self.into_cities()
+                .into_iter()
+                .flat_map(|c| c.sites)
+                .collect()
+        }
+
+        /// Flatten the tree into a list of all restaurants, dropping all parents
+        pub fn into_restaurants(self) -> Vec<Restaurant> {
+            self.into_sites()
+                .into_iter()
+                .flat_map(|s| s.restaurants)
+                .collect()
+        }
+
+        /// Flatten the tree into a list of all dishes, dropping all parents
+        pub fn into_dishes(self) -> Vec<Dish> {
+            self.into_restaurants()
+                .into_iter()
+                .flat_map(|r| r.dishes)
+                .collect()
+        }
+
+        /// Sort the dishes of each restaurant in the given order.
+        /// Dishes are sorted by name on conversion, so that's a no-op.
+        pub fn sort_dishes(&mut self, sort: DishSort) {
+            let cmp: fn(&Dish, &Dish) -> Ordering = match sort {
+                DishSort::Name => return,
+                DishSort::PriceAsc => |a, b| a.price.cmp(&b.price),
+                DishSort::PriceDesc => |a, b| b.price.cmp(&a.price),
+            };
+            self.countries
+                .iter_mut()
+                .flat_map(|co| co.cities.iter_mut())
+                .flat_map(|ci| ci.sites.iter_mut())
+                .flat_map(|si| si.restaurants.iter_mut())
+                .for_each(|r| {
+                    r.dishes
+                        .sort_by(|a, b| cmp(a, b).then_with(|| a.name.cmp(&b.name)))
+                });
+        }
+
+        /// Rough estimate of the size of the tree serialized as JSON, without actually serializing
+        /// it. Meant for spotting responses that grow too big, not for setting Content-Length.
+        pub fn estimated_bytes(&self) -> usize {
+            array_bytes(self.countries.iter().map(country_bytes)) + field_bytes("countries", 0) + 2
+        }
+    }
+
+    // Helpers for LunchData::estimated_bytes.
+    // Each field is counted as "key":value, plus a separating comma.
+
+    const UUID_BYTES: usize = 38; // 36 plus quotes
+    const DATETIME_BYTES: usize = 35; // e.g. "2024-10-01T11:30:00.123456+02:00"
+    const FLOAT_BYTES: usize = 8;
+
+    fn field_bytes(key: &str, value: usize) -> usize {
+        key.len() + value + 4
+    }
+
+    fn str_bytes(key: &str, value: &str) -> usize {
+        field_bytes(key, value.len() + 2)
+    }
+
+    fn opt_str_bytes(key: &str, value: &Option<String>) -> usize {
+        value.as_deref().map_or(0, |v| str_bytes(key, v))
+    }
+
+    fn array_bytes(items: impl Iterator<Item = usize>) -> usize {
+        items.map(|v| v + 1).sum::<usize>() + 2
+    }
+
+    fn dish_bytes(d: &Dish) -> usize {
+        field_bytes("dish_id", UUID_BYTES)
+            + field_bytes("restaurant_id", UUID_BYTES)
+            + str_bytes("name", &d.name)
+            + opt_str_bytes("description", &d.description)
+            + opt_str_bytes("comment", &d.comment)
+            + field_bytes("tags", array_bytes(d.tags.iter().map(|t| t.len() + 2)))
+            + field_bytes("price", FLOAT_BYTES)
+            + opt_str_bytes("source_url", &d.source_url)
+            + 2
+    }
+
+    fn restaurant_bytes(r: &Restaurant) -> usize {
+        field_bytes("restaurant_id", UUID_BYTES)
+            + field_bytes("site_id", UUID_BYTES)
+            + str_bytes("name", &r.name)
+            + if r.url_id.is_empty() {
+                0
+            } else {
+                str_bytes("url_id", &r.url_id)
+            }
+            + opt_str_bytes("comment", &r.comment)
+            + opt_str_bytes("address", &r.address)
+            + opt_str_bytes("url", &r.url)
+            + opt_str_bytes("map_url", &r.map_url)
+            + r.lat.map_or(0, |_| field_bytes("lat", 2 * FLOAT_BYTES))
+            + r.lon.map_or(0, |_| field_bytes("lon", 2 * FLOAT_BYTES))
+            + field_bytes("parsed_at", DATETIME_BYTES)
+            + field_bytes("dishes", array_bytes(r.dishes.iter().map(dish_bytes)))
+            + 2
+    }
+
+    fn site_bytes(s: &Site) -> usize {
+        field_bytes("site_id", UUID_BYTES)
+            + field_bytes("city_id", UUID_BYTES)
+            + str_bytes("name", &s.name)
+            + str_bytes("url_id", &s.url_id)
+            + opt_str_bytes("comment", &s.comment)
+            + opt_str_bytes("currency_suffix", &s.currency_suffix)
+            + field_bytes(
+                "restaurants",
+                array_bytes(s.restaurants.iter().map(restaurant_bytes)),
+            )
+            + 2
+    }
+
+    fn city_bytes(c: &City) -> usize {
+        field_bytes("city_id", UUID_BYTES)
+            + field_bytes("country_id", UUID_BYTES)
+            + str_bytes("name", &c.name)
+            + str_bytes("url_id", &c.url_id)
+            + field_bytes("sites", array_bytes(c.sites.iter().map(site_bytes)))
+            + 2
+    }
+
+    fn country_bytes(c: &Country) -> usize {
+        field_bytes("country_id", UUID_BYTES)
+            + str_bytes("name", &c.name)
+            + str_bytes("url_id", &c.url_id)
+            + opt_str_bytes("currency_suffix", &c.currency_suffix)
+            + field_bytes("cities", array_bytes(c.cities.iter().map(city_bytes)))
+            + 2
     }
 
     impl From<super::LunchData> for LunchData {
@@ -799,3 +1373,469 @@ pub mod api {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_dish(restaurant_id: Uuid) -> Dish {
+        Dish {
+            description: Some("med lingonsylt".into()),
+            comment: Some("innehåller mjölk".into()),
+            tags: vec!["kött".into(), "laktos".into()],
+            price: Price::new(Decimal::new(12990, 2)),
+            source_url: Some("https://example.com/meny".into()),
+            ..Dish::new("Köttbullar").for_restaurant(restaurant_id)
+        }
+    }
+
+    fn full_restaurant() -> Restaurant {
+        let r = Restaurant {
+            url_id: "karhuset".into(),
+            comment: Some("stängt fredagar".into()),
+            address: Some("Hörsalsvägen 9".into()),
+            url: Some("https://example.com".into()),
+            map_url: Some("https://maps.google.com/?q=57.69,11.97".into()),
+            lat: Some(57.69),
+            lon: Some(11.97),
+            ..Restaurant::new_for_site("Kårhuset", Uuid::new_v4())
+        };
+        let dishes = vec![
+            full_dish(r.restaurant_id),
+            Dish::new("Fisk").for_restaurant(r.restaurant_id),
+        ];
+        r.with_dishes(dishes)
+    }
+
+    #[test]
+    fn dish_api_round_trip() {
+        let dish = full_dish(Uuid::new_v4());
+        assert_eq!(Dish::from(api::Dish::from(dish.clone())), dish);
+    }
+
+    #[test]
+    fn restaurant_api_round_trip() {
+        let restaurant = full_restaurant();
+        assert_eq!(
+            Restaurant::from(api::Restaurant::from(restaurant.clone())),
+            restaurant
+        );
+    }
+
+    #[test]
+    fn estimated_bytes() {
+        let site =
+            Site::new("Lindholmen").with_restaurants(vec![full_restaurant(), full_restaurant()]);
+        let data = api::LunchData::from(
+            LunchData::new().with_country(
+                Country {
+                    country_id: Uuid::new_v4(),
+                    currency_suffix: Some("kr".into()),
+                    ..Country::new("Sverige")
+                }
+                .with_city(City::new("Göteborg").with_site(site)),
+            ),
+        );
+        let actual = serde_json::to_vec(&data).unwrap().len();
+        let estimate = data.estimated_bytes();
+        // Only a rough estimate, but it should land within 10% of the real thing
+        assert!(
+            estimate.abs_diff(actual) * 10 <= actual,
+            "estimate {estimate}, actual {actual}"
+        );
+    }
+
+    #[test]
+    fn nil_ids_from_api_get_new_ones() {
+        let dish = Dish::from(api::Dish {
+            name: "Fisk".into(),
+            ..Default::default()
+        });
+        assert!(!dish.dish_id.is_nil());
+
+        let restaurant = Restaurant::from(api::Restaurant {
+            name: "Kårhuset".into(),
+            dishes: vec![
+                api::Dish {
+                    name: "Fisk".into(),
+                    ..Default::default()
+                },
+                api::Dish {
+                    name: "Soppa".into(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+        assert!(!restaurant.restaurant_id.is_nil());
+        assert_eq!(restaurant.dishes.len(), 2);
+        for (id, dish) in restaurant.dishes.iter() {
+            assert!(!id.is_nil());
+            assert_eq!(*id, dish.dish_id);
+            assert_eq!(dish.restaurant_id, restaurant.restaurant_id);
+        }
+    }
+
+    fn price(s: &str) -> Price {
+        s.parse().unwrap()
+    }
+
+    /// A tree with a single site, with the given restaurants and their dishes
+    fn site_with(restaurants: &[(&str, &[Dish])]) -> LunchData {
+        let site = Site::new("Lindholmen");
+        let restaurants = restaurants
+            .iter()
+            .map(|(name, dishes)| {
+                let r = Restaurant::new_for_site(name, site.site_id);
+                let dishes = dishes
+                    .iter()
+                    .map(|d| d.clone().for_restaurant(r.restaurant_id))
+                    .collect();
+                r.with_dishes(dishes)
+            })
+            .collect();
+        LunchData::new().with_country(
+            Country {
+                country_id: Uuid::new_v4(),
+                ..Country::new("Sverige")
+            }
+            .with_city(City::new("Göteborg").with_site(site.with_restaurants(restaurants))),
+        )
+    }
+
+    fn dish_names(data: &LunchData) -> Vec<String> {
+        let mut names: Vec<String> = data
+            .countries
+            .values()
+            .flat_map(|co| co.cities.values())
+            .flat_map(|ci| ci.sites.values())
+            .flat_map(|si| si.restaurants.values())
+            .flat_map(|r| r.dishes.values().map(|d| d.name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn restaurant_names(data: &LunchData) -> Vec<String> {
+        let mut names: Vec<String> = data
+            .countries
+            .values()
+            .flat_map(|co| co.cities.values())
+            .flat_map(|ci| ci.sites.values())
+            .flat_map(|si| si.restaurants.values().map(|r| r.name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn priced(name: &str, p: &str) -> Dish {
+        Dish {
+            price: price(p),
+            ..Dish::new(name)
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let mut data = site_with(&[
+            ("Kårhuset", &[Dish::new("Fisk"), Dish::new("Soppa")]),
+            ("Bistrot", &[Dish::new("Pasta")]),
+        ]);
+        let country = data.countries.values_mut().next().unwrap();
+        country.add(City::new("Stockholm").with_site(Site::new("Kista")));
+        assert_eq!(
+            data.counts(),
+            Counts {
+                countries: 1,
+                cities: 2,
+                sites: 2,
+                restaurants: 2,
+                dishes: 3,
+            }
+        );
+        assert_eq!(LunchData::new().counts(), Counts::default());
+    }
+
+    #[test]
+    fn sort_dishes() {
+        let data = site_with(&[(
+            "Kårhuset",
+            &[
+                priced("Soppa", "89"),
+                priced("Fisk", "125"),
+                priced("Pasta", "89"),
+            ],
+        )]);
+        let sorted = |sort| {
+            let mut data = api::LunchData::from(data.clone());
+            data.sort_dishes(sort);
+            data.countries[0].cities[0].sites[0].restaurants[0]
+                .dishes
+                .iter()
+                .map(|d| d.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(api::DishSort::Name), ["Fisk", "Pasta", "Soppa"]);
+        // same price is sorted by name
+        assert_eq!(sorted(api::DishSort::PriceAsc), ["Pasta", "Soppa", "Fisk"]);
+        assert_eq!(sorted(api::DishSort::PriceDesc), ["Fisk", "Pasta", "Soppa"]);
+    }
+
+    #[test]
+    fn merge() {
+        let mut data = site_with(&[("Kårhuset", &[Dish::new("Fisk")])]);
+        let mut other = data.clone();
+        let country = other.countries.values_mut().next().unwrap();
+        country.add(City::new("Stockholm"));
+        let site = country
+            .cities
+            .values_mut()
+            .flat_map(|ci| ci.sites.values_mut())
+            .next()
+            .unwrap();
+        let bistrot = Restaurant::new_for_site("Bistrot", site.site_id);
+        let pasta = Dish::new("Pasta").for_restaurant(bistrot.restaurant_id);
+        site.set_restaurants(vec![bistrot.with_dish(pasta)]);
+
+        data.merge(other);
+        let counts = data.counts();
+        assert_eq!((counts.countries, counts.cities, counts.sites), (1, 2, 1));
+        assert_eq!(restaurant_names(&data), ["Bistrot", "Kårhuset"]);
+        assert_eq!(dish_names(&data), ["Fisk", "Pasta"]);
+    }
+
+    #[test]
+    fn restaurant_merge() {
+        let old = Restaurant {
+            comment: Some("gammal".into()),
+            ..Restaurant::new_for_site("Kårhuset", Uuid::new_v4())
+        };
+        let fisk = Dish::new("Fisk").for_restaurant(old.restaurant_id);
+        let soppa = Dish::new("Soppa").for_restaurant(old.restaurant_id);
+        let old = old.with_dishes(vec![fisk.clone(), soppa]);
+        let new = Restaurant {
+            comment: Some("ny".into()),
+            parsed_at: old.parsed_at + chrono::TimeDelta::minutes(1),
+            dishes: Default::default(),
+            ..old.clone()
+        }
+        .with_dishes(vec![
+            Dish {
+                price: price("125"),
+                ..fisk.clone()
+            },
+            Dish::new("Pasta").for_restaurant(old.restaurant_id),
+        ]);
+        let dish_names = |r: &Restaurant| {
+            let mut names: Vec<_> = r.dishes.values().map(|d| d.name.clone()).collect();
+            names.sort();
+            names
+        };
+
+        // whichever way around, the newest fields and dishes win, and no dish is lost
+        for (mut a, b) in [(old.clone(), new.clone()), (new, old)] {
+            a.merge(b);
+            assert_eq!(a.comment.as_deref(), Some("ny"));
+            assert_eq!(a.dishes[&fisk.dish_id].price, price("125"));
+            assert_eq!(dish_names(&a), ["Fisk", "Pasta", "Soppa"]);
+        }
+    }
+
+    #[test]
+    fn round_price() {
+        let rounded = |p: &str, to: f32| {
+            let mut d = priced("Fisk", p);
+            d.round_price(to);
+            d.price
+        };
+        assert_eq!(rounded("128.5", 1.0), price("129"));
+        assert_eq!(rounded("129.4", 1.0), price("129"));
+        assert_eq!(rounded("129.4", 5.0), price("130"));
+        assert_eq!(rounded("127.4", 5.0), price("125"));
+        assert_eq!(rounded("129.45", 0.1), price("129.5"));
+        // nothing to round to
+        assert_eq!(rounded("129.4", 0.0), price("129.4"));
+        assert_eq!(rounded("129.4", -1.0), price("129.4"));
+    }
+
+    #[test]
+    fn drop_zero_price() {
+        let data = site_with(&[
+            ("Kårhuset", &[priced("Soppa", "0"), priced("Fisk", "135")]),
+            ("Bistrot", &[priced("Gratis", "0")]),
+        ]);
+
+        let mut d = data.clone();
+        d.drop_zero_price();
+        assert_eq!(dish_names(&d), ["Fisk"]);
+        assert_eq!(restaurant_names(&d), ["Kårhuset"]);
+
+        let mut d = data.clone();
+        d.countries
+            .values_mut()
+            .for_each(|co| co.allow_free_dishes = true);
+        d.drop_zero_price();
+        assert_eq!(dish_names(&d), ["Fisk", "Gratis", "Soppa"]);
+    }
+
+    #[test]
+    fn retain_price_range() {
+        let data = site_with(&[
+            ("Kårhuset", &[priced("Soppa", "85"), priced("Fisk", "135")]),
+            (
+                "Bistrot",
+                &[priced("Gratis", "0"), priced("Biff", "189.50")],
+            ),
+        ]);
+
+        let mut d = data.clone();
+        d.retain_price_range(Some(price("100")), None);
+        assert_eq!(dish_names(&d), ["Biff", "Fisk"]);
+
+        // no min, so free dishes are kept
+        let mut d = data.clone();
+        d.retain_price_range(None, Some(price("135")));
+        assert_eq!(dish_names(&d), ["Fisk", "Gratis", "Soppa"]);
+
+        // both ends are inclusive
+        let mut d = data.clone();
+        d.retain_price_range(Some(price("85")), Some(price("135")));
+        assert_eq!(dish_names(&d), ["Fisk", "Soppa"]);
+        assert_eq!(restaurant_names(&d), ["Kårhuset"]);
+
+        let mut d = data.clone();
+        d.retain_price_range(Some(price("200")), None);
+        assert!(dish_names(&d).is_empty());
+        assert!(restaurant_names(&d).is_empty());
+    }
+
+    #[test]
+    fn price_deserialize() {
+        let de = |json: &str| serde_json::from_str::<Price>(json);
+        assert_eq!(de("129.9").unwrap(), price("129.90"));
+        assert_eq!(de("95").unwrap(), price("95"));
+        assert_eq!(de(r#""129,90 kr""#).unwrap(), price("129.90"));
+        assert_eq!(de(r#""1,295.50""#).unwrap(), price("1295.50"));
+        assert_eq!(de(r#""95:-""#).unwrap(), price("95"));
+        // negative prices are clamped, and text without a number is no price at all
+        assert_eq!(de("-10").unwrap(), Price::ZERO);
+        assert_eq!(de(r#""-10 kr""#).unwrap(), Price::ZERO);
+        assert_eq!(de(r#""gratis""#).unwrap(), Price::ZERO);
+        assert!(de("true").is_err());
+    }
+
+    fn tagged(name: &str, tags: &[&str]) -> Dish {
+        Dish {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Dish::new(name)
+        }
+    }
+
+    #[test]
+    fn retain_tags() {
+        let data = site_with(&[
+            (
+                "Kårhuset",
+                &[
+                    tagged("Falafel", &["vego", "gluten"]),
+                    tagged("Sallad", &["vego"]),
+                ],
+            ),
+            (
+                "Bistrot",
+                &[tagged("Biff", &[]), tagged("Pasta", &["gluten"])],
+            ),
+        ]);
+
+        let mut d = data.clone();
+        d.retain_tags(&["vego", "gluten"], false);
+        assert_eq!(dish_names(&d), ["Falafel"]);
+        assert_eq!(restaurant_names(&d), ["Kårhuset"]);
+
+        let mut d = data.clone();
+        d.retain_tags(&["vego", "gluten"], true);
+        assert_eq!(dish_names(&d), ["Falafel", "Pasta", "Sallad"]);
+        assert_eq!(restaurant_names(&d), ["Bistrot", "Kårhuset"]);
+
+        let mut d = data.clone();
+        d.retain_tags(&["vego"], false);
+        assert_eq!(restaurant_names(&d), ["Kårhuset"]);
+
+        let mut d = data.clone();
+        d.retain_tags(&["fisk"], true);
+        assert!(restaurant_names(&d).is_empty());
+    }
+
+    #[test]
+    fn dish_matches() {
+        let dish = Dish {
+            description: Some("med potatismos och LINGON".into()),
+            comment: Some("Innehåller mjölk".into()),
+            tags: vec!["Kött".into()],
+            ..Dish::new("Köttbullar")
+        };
+        for q in [
+            "köttbullar",
+            "KOTTBULLAR",
+            "kott",
+            "lingon potatis",
+            "mjolk",
+            "kött köttbullar",
+        ] {
+            assert!(dish.matches(q), "{q}");
+        }
+        for q in ["fisk", "köttbullar fisk", "", "   "] {
+            assert!(!dish.matches(q), "{q:?}");
+        }
+    }
+
+    #[test]
+    fn search() {
+        let city = City::new("Göteborg");
+        let lh = Site::new_for_city("Lindholmen", city.city_id);
+        let kh = Restaurant::new_for_site("Kårhuset", lh.site_id);
+        let bistrot = Restaurant::new_for_site("Bistrot", lh.site_id);
+        let dishes = vec![
+            Dish::new("Köttbullar").for_restaurant(kh.restaurant_id),
+            Dish::new("Fisk").for_restaurant(kh.restaurant_id),
+            Dish::new("Pasta").for_restaurant(bistrot.restaurant_id),
+        ];
+        let lh = lh.with_restaurants(vec![kh, bistrot]).with_dishes(dishes);
+        let other = Site::new_for_city("Centrum", city.city_id)
+            .with_restaurant(Restaurant::new("Pizzeria").with_dish(Dish::new("Kebabpizza")));
+        let country = Country {
+            country_id: Uuid::new_v4(),
+            ..Country::new("Sverige")
+        };
+        let empty_country = Country {
+            country_id: Uuid::new_v4(),
+            ..Country::new("Norge")
+        };
+        let data = LunchData::new().with_countries(vec![
+            country.with_city(city.with_sites(vec![lh, other])),
+            empty_country,
+        ]);
+
+        let res = data.search("KÖTTBULLAR");
+        assert_eq!(dish_names(&res), ["Köttbullar"]);
+        assert_eq!(restaurant_names(&res), ["Kårhuset"]);
+        // everything on the way down to the match is kept, and nothing else
+        assert_eq!(res.countries.len(), 1);
+        let sites: Vec<&str> = res
+            .countries
+            .values()
+            .flat_map(|co| co.cities.values())
+            .flat_map(|ci| ci.sites.values().map(|si| si.name.as_str()))
+            .collect();
+        assert_eq!(sites, ["Lindholmen"]);
+
+        let res = data.search("pizza");
+        assert_eq!(dish_names(&res), ["Kebabpizza"]);
+        assert_eq!(restaurant_names(&res), ["Pizzeria"]);
+
+        assert!(data.search("sushi").countries.is_empty());
+        assert!(data.search("").countries.is_empty());
+        // the original is left as is
+        assert_eq!(dish_names(&data).len(), 4);
+    }
+}
@@ -1,18 +1,28 @@
 use crate::{
     cache,
     cache::{Client, Opts},
-    db, models, scrapers,
+    db, metrics, models, scrapers,
+    scrapers::se::gbg::lh::LHScraper,
+    util,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono_tz::Tz;
 use compact_str::CompactString;
 // use reqwest::{Client, IntoUrl};
+use scraper::{Html, Selector};
 use sqlx::PgPool;
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch, Semaphore},
     task,
 };
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{debug, error, trace};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
 use uuid::Uuid;
 
 // Name your user agent after your app?
@@ -24,12 +34,33 @@ pub trait RestaurantScraper {
     async fn run(&self) -> Result<ScrapeResult>;
 
     fn name(&self) -> &'static str;
+
+    /// CSS selectors the scraper depends on, which should each match at least once on every page
+    /// in validation_urls. Used for detecting changes to the layout of a site before they show up
+    /// as empty or broken scrape results.
+    fn required_selectors(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Pages to check the required selectors against
+    fn validation_urls(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Cron spec for when to run this scraper, for sites that update on another schedule than
+    /// most. None uses the global schedule.
+    fn schedule(&self) -> Option<&str> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct ScrapeResult {
     pub site_id: Uuid,
     pub restaurants: Vec<models::Restaurant>,
+    /// Whether the result covers all restaurants for the site, so that any scraped restaurants
+    /// missing from it can be removed. Otherwise, only the restaurants in the result are updated.
+    pub authoritative: bool,
 }
 
 impl ScrapeResult {
@@ -37,6 +68,14 @@ impl ScrapeResult {
         self.restaurants.len()
     }
 
+    /// Round the prices of all dishes, see Dish::round_price
+    pub fn round_prices(&mut self, to: f32) {
+        self.restaurants
+            .iter_mut()
+            .flat_map(|r| r.dishes.values_mut())
+            .for_each(|d| d.round_price(to));
+    }
+
     pub fn num_dishes(&self) -> usize {
         let mut sum: usize = 0;
         for r in &self.restaurants {
@@ -44,32 +83,490 @@ impl ScrapeResult {
         }
         sum
     }
+
+    /// Remove restaurants with more than max dishes, which is most likely a parser gone wrong
+    /// rather than a real menu, and return their names and dish counts. If any are removed, the
+    /// result is no longer authoritative, so that those restaurants keep what they already have
+    /// in the DB instead of being deleted.
+    pub fn drop_oversized(&mut self, max: usize) -> Vec<(String, usize)> {
+        let mut dropped = Vec::new();
+        self.restaurants.retain(|r| {
+            if r.dishes.len() <= max {
+                return true;
+            }
+            dropped.push((r.name.clone(), r.dishes.len()));
+            false
+        });
+        if !dropped.is_empty() {
+            self.authoritative = false;
+        }
+        dropped
+    }
+}
+
+/// Default for the max number of dishes a scraped restaurant may have before it's ignored
+pub const DEFAULT_MAX_DISHES_PER_RESTAURANT: usize = 200;
+
+/// A scrape result, together with the name of the scraper and the span of the scrape that produced
+/// it, so that logging further down the line, like from the DB update, can be tied back to the
+/// scraper and site
+struct ScrapeMsg {
+    scraper: &'static str,
+    span: Span,
+    res: Result<ScrapeResult>,
 }
 
 #[derive(Debug, Clone)]
 enum ScrapeCommand {
-    Run,
+    /// Run the scraper with the given name, or all scrapers if None
+    Run(Option<&'static str>),
     Shutdown,
 }
 
-pub async fn run(pg: PgPool, schedule: Option<CompactString>, cache_opts: Opts) -> Result<()> {
+/// The schedule of a scraper that has been set up, for registering its cron job
+#[derive(Debug, Clone)]
+struct ScraperSchedule {
+    scraper: &'static str,
+    /// Cron spec overriding the global one, if set
+    cron: Option<CompactString>,
+}
+
+impl ScraperSchedule {
+    fn of(scraper: &impl RestaurantScraper) -> Self {
+        Self {
+            scraper: scraper.name(),
+            cron: scraper.schedule().map(CompactString::from),
+        }
+    }
+}
+
+/// Check that each selector matches at least once in the given HTML.
+/// Returns an error listing all selectors that didn't match.
+pub fn check_selectors(html: &str, selectors: &[&str]) -> Result<()> {
+    let html = Html::parse_document(html);
+    let mut missing = Vec::new();
+    for s in selectors {
+        let sel = Selector::parse(s).map_err(|e| anyhow!("invalid selector {s:?}: {e}"))?;
+        if html.select(&sel).next().is_none() {
+            missing.push(*s);
+        }
+    }
+    if !missing.is_empty() {
+        bail!("no match for selectors: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Fetch all validation pages for the scraper, and check its required selectors against them
+pub async fn validate_scraper(client: &Client, scraper: &impl RestaurantScraper) -> Result<()> {
+    for url in scraper.validation_urls() {
+        let html = client.get_as_string(&url).await?;
+        check_selectors(&html, scraper.required_selectors())
+            .with_context(|| format!("validation failed for {url}"))?;
+    }
+    Ok(())
+}
+
+/// Validate all scrapers set up in setup_scrapers against the live sites, without touching the DB.
+/// Returns an error if any of them failed.
+pub async fn validate(cache_opts: Opts) -> Result<()> {
+    let client = Client::build(cache_opts).await?;
+    // the site id is only used in scrape results, so any id will do here
+    let scrapers = [scrapers::se::gbg::lh::LHScraper::new(
+        client.clone(),
+        Uuid::nil(),
+    )];
+    let mut failed = 0;
+    for scraper in &scrapers {
+        match validate_scraper(&client, scraper).await {
+            Ok(_) => info!(scraper = scraper.name(), "Validation OK"),
+            Err(e) => {
+                failed += 1;
+                error!(
+                    scraper = scraper.name(),
+                    err = format!("{e:#}"),
+                    "Validation failed"
+                );
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of {} scrapers failed validation",
+            failed,
+            scrapers.len()
+        );
+    }
+    Ok(())
+}
+
+/// Returned when there are no scrapers to run, which is most likely a misconfiguration
+#[derive(Debug, thiserror::Error)]
+#[error("no scrapers configured")]
+pub struct NoScrapers;
+
+/// A site given as "country/city/site" url_ids, e.g. "se/gbg/lh", for only running the scraper for
+/// that site
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SitePath(String);
+
+impl FromStr for SitePath {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let path = s.trim_matches('/');
+        match path.split('/').collect::<Vec<_>>()[..] {
+            [country, city, site]
+                if !country.is_empty() && !city.is_empty() && !site.is_empty() =>
+            {
+                Ok(Self(path.into()))
+            }
+            _ => Err(format!(
+                "expected COUNTRY/CITY/SITE, e.g. se/gbg/lh, got {s:?}"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SitePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl SitePath {
+    /// Whether the key is for this site, comparing url_ids like lookups in the DB do
+    fn matches(&self, key: &db::SiteKey<'_>) -> bool {
+        let want = [
+            key.country_url_id.as_str(),
+            key.city_url_id.as_str(),
+            key.site_url_id.as_str(),
+        ];
+        // the parts are checked when parsing, so there are always three
+        self.0
+            .split('/')
+            .zip(want)
+            .all(|(a, b)| util::slug(a) == util::slug(b))
+    }
+}
+
+/// Names and site keys of all scrapers set up in setup_scrapers
+fn all_scrapers() -> Vec<(&'static str, db::SiteKey<'static>)> {
+    vec![(LHScraper::NAME, LHScraper::site_key())]
+}
+
+/// Whether the scraper for the given key should be set up, when only running the one for `only`
+fn wanted(only: Option<&SitePath>, key: &db::SiteKey<'_>) -> bool {
+    only.is_none_or(|site| site.matches(key))
+}
+
+/// Fail with a list of the available scrapers if none of them is for the given site
+fn check_site(site: &SitePath) -> Result<()> {
+    let all = all_scrapers();
+    if all.iter().any(|(_, key)| site.matches(key)) {
+        return Ok(());
+    }
+    let available = all
+        .iter()
+        .map(|(name, key)| {
+            format!(
+                "{}/{}/{} ({name})",
+                key.country_url_id.as_str(),
+                key.city_url_id.as_str(),
+                key.site_url_id.as_str()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    bail!("no scraper for site {site}, available: {available}")
+}
+
+/// Options for how each scraper is run
+#[derive(Debug, Clone, Copy)]
+pub struct ScraperOpts {
+    /// Fail a run taking longer than this, no matter how many requests it makes, so that a
+    /// hanging site can't hold up the others. Applies to each attempt separately.
+    pub timeout: Duration,
+    /// How many times to retry a failed run before reporting the failure
+    pub retries: u32,
+}
+
+/// Options for how scrape results are written to the DB
+#[derive(Debug, Clone)]
+pub struct DbOpts {
+    /// Keep this many versions of each dish instead of replacing them, if set
+    pub dish_history: Option<usize>,
+    /// Max number of concurrent DB writes
+    pub writers: usize,
+    /// How many scrape results may be queued up waiting to be written
+    pub result_buffer: usize,
+    /// Insert any missing country, city and site rows for the scrapers before starting them
+    pub ensure_hierarchy: bool,
+    /// Restaurants with more dishes than this are left out of the write, see
+    /// ScrapeResult::drop_oversized
+    pub max_dishes_per_restaurant: usize,
+}
+
+/// Writes scrape results to the DB in background tasks, with a bounded number of writes in flight,
+/// so a slow write for one site doesn't hold up results for other sites.
+/// Concurrent writes for the same site are serialized by the DB functions locking the site row.
+struct DbWriter {
+    pg: PgPool,
+    dish_history: Option<usize>,
+    max_dishes_per_restaurant: usize,
+    permits: Arc<Semaphore>,
+    /// Each task tells whether it succeeded
+    tasks: task::JoinSet<bool>,
+    /// Number of scrape results that couldn't be written so far
+    failed: usize,
+}
+
+impl DbWriter {
+    fn new(pg: PgPool, opts: &DbOpts) -> Self {
+        Self {
+            pg,
+            dish_history: opts.dish_history,
+            max_dishes_per_restaurant: opts.max_dishes_per_restaurant,
+            permits: Arc::new(Semaphore::new(opts.writers.max(1))),
+            tasks: task::JoinSet::new(),
+            failed: 0,
+        }
+    }
+
+    fn count(&mut self, res: Result<bool, task::JoinError>) {
+        match res {
+            Ok(true) => {}
+            Ok(false) => self.failed += 1,
+            Err(err) => {
+                error!(%err, "DB write task died");
+                self.failed += 1;
+            }
+        }
+    }
+
+    /// Waits for a free writer, then writes the result in the background
+    async fn write(&mut self, scraper: &'static str, mut res: ScrapeResult, span: Span) {
+        // reap finished writes, so the set doesn't grow forever when running in a loop
+        while let Some(res) = self.tasks.try_join_next() {
+            self.count(res);
+        }
+
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("DB writer semaphore closed");
+        let pg = self.pg.clone();
+        let dish_history = self.dish_history;
+        let max_dishes = self.max_dishes_per_restaurant;
+        self.tasks.spawn(
+            async move {
+                // we need to copy the id, since update_site will consume res
+                let site_id = res.site_id;
+                for (restaurant, dishes) in res.drop_oversized(max_dishes) {
+                    warn!(
+                        %site_id,
+                        restaurant,
+                        dishes,
+                        max = max_dishes,
+                        "Ignoring restaurant with too many dishes"
+                    );
+                }
+                let num_dishes = res.num_dishes();
+                match db::get_price_rounding(&pg, site_id).await {
+                    Ok(Some(to)) => res.round_prices(to),
+                    Ok(None) => {}
+                    Err(e) => error!(%site_id, err = %e, "Failed to get price rounding"),
+                }
+                let res = match dish_history {
+                    Some(keep) => db::update_site_versioned(&pg, res, keep).await,
+                    None => db::update_site(&pg, res).await,
+                };
+                let ok = match res {
+                    Ok(_) => {
+                        debug!(%site_id, "DB update OK");
+                        if let Err(e) =
+                            db::record_scraper_success(&pg, scraper, site_id, num_dishes).await
+                        {
+                            error!(err = %e, "Failed to record scraper status");
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        error!(%site_id, err = %e, "Failed to update DB");
+                        if let Err(e) =
+                            db::record_scraper_failure(&pg, scraper, &e.to_string()).await
+                        {
+                            error!(err = %e, "Failed to record scraper status");
+                        }
+                        false
+                    }
+                };
+                drop(permit);
+                ok
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Record a failed scrape in the background
+    fn record_failure(&mut self, scraper: &'static str, err: String, span: Span) {
+        let pg = self.pg.clone();
+        self.tasks.spawn(
+            async move {
+                if let Err(e) = db::record_scraper_failure(&pg, scraper, &err).await {
+                    error!(err = %e, "Failed to record scraper status");
+                }
+                // the scrape is already counted as failed, and the status is just for monitoring
+                true
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Waits for all pending writes to finish, returning the number of scrape results that
+    /// couldn't be written
+    async fn wait(&mut self) -> usize {
+        while let Some(res) = self.tasks.join_next().await {
+            self.count(res);
+        }
+        self.failed
+    }
+}
+
+/// Tally of scrape results, used to decide the outcome of a one-shot run
+#[derive(Debug, Clone, Copy, Default)]
+struct RunStats {
+    ok: usize,
+    failed: usize,
+    empty: usize,
+    /// Results that were counted above, but then couldn't be stored in the DB
+    not_stored: usize,
+}
+
+impl RunStats {
+    /// Returns an error if not all of the expected scrapers succeeded with at least one dish, and
+    /// had their results written
+    fn check(&self, expected: usize) -> Result<()> {
+        let missing = expected.saturating_sub(self.ok + self.failed + self.empty);
+        if self.failed > 0 || self.empty > 0 || missing > 0 || self.not_stored > 0 {
+            return Err(anyhow!(
+                "{} of {} scrapers failed, {} returned no dishes, {} did not report, {} not stored",
+                self.failed,
+                expected,
+                self.empty,
+                missing,
+                self.not_stored
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// If exit_code is set, a one-shot run returns an error if any scraper failed or returned no
+/// dishes, so that it can be used as a smoke test.
+/// On shutdown, runs in progress get shutdown_grace to finish before being aborted, see
+/// drain_scrapers.
+/// If a site is given, only the scraper for that site is set up, even if it's disabled in the DB.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    pg: PgPool,
+    schedule: Option<CompactString>,
+    timezone: Option<Tz>,
+    exit_code: bool,
+    allow_no_scrapers: bool,
+    shutdown_grace: Duration,
+    site: Option<SitePath>,
+    scraper_opts: ScraperOpts,
+    db_opts: DbOpts,
+    cache_opts: Opts,
+) -> Result<()> {
     let shutdown = crate::signals::shutdown_channel().await?;
     let (cmd_tx, _) = broadcast::channel(8); // don't know optimal buffer size yet
-    let (res_tx, res_rx) = mpsc::channel::<Result<ScrapeResult>>(8); // same here
+    let (res_tx, res_rx) = mpsc::channel::<ScrapeMsg>(db_opts.result_buffer.max(1));
+
+    if let Some(ref site) = site {
+        check_site(site)?;
+    }
+    if db_opts.ensure_hierarchy {
+        ensure_hierarchy(&pg, site.as_ref()).await?;
+    }
 
     let client = cache::Client::build(cache_opts).await?;
+    let mut writer = DbWriter::new(pg.clone(), &db_opts);
     // we don't use ? in calls here, since we want to first close the PgPool before returning the
     // result
-    let res = match start_scheduler(schedule, cmd_tx.clone()).await {
-        Ok(sched) => run_loop(&pg, client.clone(), sched, shutdown, cmd_tx, res_tx, res_rx).await,
-        Err(e) => {
-            trace!("{}: running one-shot scrape", e);
-            run_oneshot(&pg, client.clone(), shutdown, cmd_tx, res_tx, res_rx).await
+    let paused = crate::signals::pause_channel()?;
+    let res = match schedule {
+        Some(cron) => {
+            run_loop(
+                &pg,
+                &mut writer,
+                client.clone(),
+                Schedule {
+                    cron,
+                    timezone,
+                    paused,
+                },
+                scraper_opts,
+                site.as_ref(),
+                shutdown_grace,
+                shutdown,
+                cmd_tx,
+                res_tx,
+                res_rx,
+            )
+            .await
+        }
+        None => {
+            trace!("No cron spec, running one-shot scrape");
+            let res = run_oneshot(
+                &pg,
+                &mut writer,
+                client.clone(),
+                scraper_opts,
+                site.as_ref(),
+                shutdown_grace,
+                shutdown,
+                cmd_tx,
+                res_tx,
+                res_rx,
+            )
+            .await;
+            match res {
+                Ok((mut stats, expected)) if exit_code => {
+                    // results are counted when received, so the writes have to finish before we
+                    // know if they made it to the DB
+                    stats.not_stored = writer.wait().await;
+                    stats.check(expected)
+                }
+                res => res.map(|_| ()),
+            }
         }
     };
 
+    let res = match res {
+        Err(e) if allow_no_scrapers && e.is::<NoScrapers>() => {
+            info!("No scrapers configured, nothing to do");
+            Ok(())
+        }
+        res => res,
+    };
+
     // cleanup
+    trace!("Waiting for pending DB writes...");
+    writer.wait().await;
     pg.close().await;
+    let stats = client.stats();
+    info!(
+        hits = stats.hits,
+        misses = stats.misses,
+        hit_ratio = stats.hit_ratio(),
+        entries = stats.entries,
+        size_bytes = stats.size_bytes,
+        "HTTP cache stats for this run"
+    );
     if let Err(err) = client.save().await {
         error!(%err, "Failed to save HTTP cache");
     }
@@ -77,142 +574,308 @@ pub async fn run(pg: PgPool, schedule: Option<CompactString>, cache_opts: Opts)
     res
 }
 
+/// Settings for running scrapers on a schedule
+struct Schedule {
+    /// Cron spec for all scrapers without one of their own
+    cron: CompactString,
+    timezone: Option<Tz>,
+    /// Scheduled runs are skipped while this is set
+    paused: watch::Receiver<bool>,
+}
+
+/// Register one cron job per scraper, each notifying just that scraper to run
 async fn start_scheduler(
-    schedule: Option<CompactString>,
+    schedule: Schedule,
+    scrapers: &[ScraperSchedule],
     tx: broadcast::Sender<ScrapeCommand>,
 ) -> Result<JobScheduler> {
-    match schedule {
-        Some(s) => {
-            let sched = JobScheduler::new().await?;
-            trace!("Setting up cron job with schedule: {s}");
-            sched
-                .add(Job::new_tz(
-                    s.as_str(),
-                    chrono::Local,
-                    move |uid, _lock| {
-                        trace!(%uid, "Notifying all scrapers to run");
-                        tx.send(ScrapeCommand::Run)
-                            .expect("Failed to send scheduled run command");
-                    },
-                )?)
-                .await?;
-            trace!("Starting cron scheduler");
-            sched.start().await?;
-            Ok(sched)
-        }
-        None => Err(anyhow!("empty cron spec")),
+    let sched = JobScheduler::new().await?;
+    for s in scrapers {
+        let cron = s.cron.as_deref().unwrap_or(&schedule.cron);
+        let scraper = s.scraper;
+        trace!(scraper, timezone = ?schedule.timezone, "Setting up cron job with schedule: {cron}");
+        let paused = schedule.paused.clone();
+        let tx = tx.clone();
+        let notify = move |uid, _lock| notify_scheduled(uid, scraper, &paused, &tx);
+        let job = match schedule.timezone {
+            Some(tz) => Job::new_tz(cron, tz, notify),
+            None => Job::new_tz(cron, chrono::Local, notify),
+        }
+        .with_context(|| format!("invalid cron spec for {scraper}: {cron}"))?;
+        sched.add(job).await?;
+    }
+    trace!("Starting cron scheduler");
+    sched.start().await?;
+    Ok(sched)
+}
+
+/// A scheduled run of the given scraper, run by its cron job with the id of the job.
+/// Skipped while scraping is paused.
+fn notify_scheduled(
+    uid: Uuid,
+    scraper: &'static str,
+    paused: &watch::Receiver<bool>,
+    tx: &broadcast::Sender<ScrapeCommand>,
+) {
+    if *paused.borrow() {
+        debug!(%uid, scraper, "Scraping is paused, skipping scheduled run");
+        return;
     }
+    trace!(%uid, scraper, "Notifying scraper to run");
+    tx.send(ScrapeCommand::Run(Some(scraper)))
+        .expect("Failed to send scheduled run command");
 }
 
 /// returns false if the call site should break out of containing loop.
-/// res_rx will be closed when false is returned.
+/// res_rx is left open on shutdown, so results from runs in progress can still be drained.
 async fn handle_result(
-    pg: &PgPool,
+    writer: &mut DbWriter,
+    stats: &mut RunStats,
     shutdown: &mut broadcast::Receiver<()>,
-    res_rx: &mut mpsc::Receiver<Result<ScrapeResult>>,
+    res_rx: &mut mpsc::Receiver<ScrapeMsg>,
 ) -> bool {
+    match next_result(shutdown, res_rx).await {
+        Some(msg) => {
+            handle_msg(writer, stats, msg).await;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Wait for the next scrape result, or None on shutdown or if all senders are gone.
+/// Cancel safe, so it can be used in select.
+async fn next_result(
+    shutdown: &mut broadcast::Receiver<()>,
+    res_rx: &mut mpsc::Receiver<ScrapeMsg>,
+) -> Option<ScrapeMsg> {
     tokio::select! {
         _ = shutdown.recv() => {
             trace!("Got shutdown signal");
-            res_rx.close();
-            return false;
+            None
         },
-        res = res_rx.recv() => match res {
-            Some(v) => match v {
-                Ok(v) => {
-                    // we need to copy the id, since update_site will consume v
-                    let site_id = v.site_id;
-                    debug!(%site_id, "Got scrape result, updating DB...");
-                    if let Err(e) = db::update_site(pg, v).await {
-                        error!(err = %e, "Failed to update DB");
-                    }
-                    debug!(%site_id, "DB update OK");
-                },
-                Err(e) => {
-                    error!(err = %e, "Scraping failed");
-                },
-            },
-            None => {
+        res = res_rx.recv() => {
+            if res.is_none() {
                 trace!("Channel closed, quitting");
                 res_rx.close(); // we close here in case None is due to the sender being dropped
-                return false;
             }
+            res
         },
     }
-    true
 }
 
+async fn handle_msg(writer: &mut DbWriter, stats: &mut RunStats, msg: ScrapeMsg) {
+    let ScrapeMsg {
+        scraper,
+        span,
+        res: v,
+    } = msg;
+    match v {
+        Ok(v) => {
+            metrics::record_scrape(scraper, true);
+            span.in_scope(|| {
+                if v.num_dishes() == 0 {
+                    warn!("Scrape returned no dishes");
+                    stats.empty += 1;
+                } else {
+                    stats.ok += 1;
+                }
+                debug!("Got scrape result, updating DB...");
+            });
+            writer.write(scraper, v, span).await;
+        }
+        Err(e) => {
+            metrics::record_scrape(scraper, false);
+            stats.failed += 1;
+            span.in_scope(|| error!(err = %e, "Scraping failed"));
+            writer.record_failure(scraper, e.to_string(), span);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run_oneshot(
     pg: &PgPool,
+    writer: &mut DbWriter,
     client: Client,
+    scraper_opts: ScraperOpts,
+    only: Option<&SitePath>,
+    shutdown_grace: Duration,
     mut shutdown: broadcast::Receiver<()>,
     cmd_tx: broadcast::Sender<ScrapeCommand>,
-    res_tx: mpsc::Sender<Result<ScrapeResult>>,
-    mut res_rx: mpsc::Receiver<Result<ScrapeResult>>,
-) -> Result<()> {
-    let tasks = setup_scrapers(pg, client.clone(), cmd_tx.clone(), res_tx).await?;
+    res_tx: mpsc::Sender<ScrapeMsg>,
+    mut res_rx: mpsc::Receiver<ScrapeMsg>,
+) -> Result<(RunStats, usize)> {
+    // scrapers with their own schedules are run along with all the others
+    let (mut tasks, _) = setup_scrapers(
+        pg,
+        client.clone(),
+        scraper_opts,
+        only,
+        cmd_tx.clone(),
+        res_tx,
+    )
+    .await?;
 
     trace!("Triggering scrapers once...");
-    cmd_tx.send(ScrapeCommand::Run)?;
+    cmd_tx.send(ScrapeCommand::Run(None))?;
 
-    for _ in 0..tasks.len() {
-        if !handle_result(pg, &mut shutdown, &mut res_rx).await {
-            break;
-        }
-    }
+    let expected = tasks.len();
+    let mut stats = collect_results(writer, &mut tasks, &mut shutdown, &mut res_rx).await;
 
-    stop_scrapers(cmd_tx, tasks).await?;
+    drain_scrapers(
+        writer,
+        &mut stats,
+        cmd_tx,
+        tasks,
+        &mut res_rx,
+        shutdown_grace,
+    )
+    .await;
 
-    Ok(())
+    Ok((stats, expected))
+}
+
+/// Handle one result from each of the scraper tasks, or until shutdown.
+/// Scraper tasks only stop when told to, so one stopping before that died without sending a
+/// result, e.g. from a panic, and waiting for its result would hang forever. Such tasks are only
+/// logged, leaving them missing from the stats.
+async fn collect_results(
+    writer: &mut DbWriter,
+    tasks: &mut task::JoinSet<()>,
+    shutdown: &mut broadcast::Receiver<()>,
+    res_rx: &mut mpsc::Receiver<ScrapeMsg>,
+) -> RunStats {
+    let expected = tasks.len();
+    let mut stats = RunStats::default();
+    let mut dead = 0;
+    while stats.ok + stats.failed + stats.empty + dead < expected {
+        tokio::select! {
+            msg = next_result(shutdown, res_rx) => match msg {
+                Some(msg) => handle_msg(writer, &mut stats, msg).await,
+                None => break,
+            },
+            Some(res) = tasks.join_next() => {
+                dead += 1;
+                match res {
+                    Err(err) => error!(%err, "Scraper task died without sending a result"),
+                    Ok(()) => warn!("Scraper task stopped without sending a result"),
+                }
+            }
+        }
+    }
+    stats
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_loop(
     pg: &PgPool,
+    writer: &mut DbWriter,
     client: Client,
-    mut sched: JobScheduler,
+    schedule: Schedule,
+    scraper_opts: ScraperOpts,
+    only: Option<&SitePath>,
+    shutdown_grace: Duration,
     mut shutdown: broadcast::Receiver<()>,
     cmd_tx: broadcast::Sender<ScrapeCommand>,
-    res_tx: mpsc::Sender<Result<ScrapeResult>>,
-    mut res_rx: mpsc::Receiver<Result<ScrapeResult>>,
+    res_tx: mpsc::Sender<ScrapeMsg>,
+    mut res_rx: mpsc::Receiver<ScrapeMsg>,
 ) -> Result<()> {
-    let tasks = setup_scrapers(pg, client, cmd_tx.clone(), res_tx).await?;
+    let (tasks, schedules) =
+        setup_scrapers(pg, client, scraper_opts, only, cmd_tx.clone(), res_tx).await?;
+    let mut sched = match start_scheduler(schedule, &schedules, cmd_tx.clone()).await {
+        Ok(sched) => sched,
+        Err(e) => {
+            stop_scrapers(cmd_tx, tasks).await?;
+            return Err(e);
+        }
+    };
 
+    // we don't act on the stats when running continuously, failures are just logged
+    let mut stats = RunStats::default();
     loop {
-        if !handle_result(pg, &mut shutdown, &mut res_rx).await {
+        if !handle_result(writer, &mut stats, &mut shutdown, &mut res_rx).await {
             break;
         }
     }
 
+    // no new runs may be scheduled while draining
     sched.shutdown().await?;
-    stop_scrapers(cmd_tx, tasks).await?;
+    drain_scrapers(
+        writer,
+        &mut stats,
+        cmd_tx,
+        tasks,
+        &mut res_rx,
+        shutdown_grace,
+    )
+    .await;
+
+    Ok(())
+}
 
+/// Insert any missing country, city and site rows for all scrapers set up in setup_scrapers, or
+/// just the one for `only`
+async fn ensure_hierarchy(pg: &PgPool, only: Option<&SitePath>) -> Result<()> {
+    for (_, key) in all_scrapers()
+        .into_iter()
+        .filter(|(_, key)| wanted(only, key))
+    {
+        trace!(?key, "Ensuring site hierarchy");
+        db::ensure_site_relation(pg, key).await?;
+    }
     Ok(())
 }
 
-// manual add/remove scraper implementations
+// manual add/remove scraper implementations, and keep all_scrapers in sync
 async fn setup_scrapers(
     pg: &PgPool,
     client: cache::Client,
+    opts: ScraperOpts,
+    only: Option<&SitePath>,
     cmds: broadcast::Sender<ScrapeCommand>,
-    results: mpsc::Sender<Result<ScrapeResult>>,
-) -> Result<task::JoinSet<()>> {
+    results: mpsc::Sender<ScrapeMsg>,
+) -> Result<(task::JoinSet<()>, Vec<ScraperSchedule>)> {
     let mut set = task::JoinSet::new();
+    let mut schedules = Vec::new();
+    // failed runs are retried at a pace related to how hard we're willing to hit the sites
+    let backoff = client.request_delay();
+
+    // a scraper asked for by site is run even if disabled, since that's most likely on purpose
+    let enabled = |name: &'static str| async move {
+        if only.is_some() {
+            return Ok(true);
+        }
+        db::is_scraper_enabled(pg, name).await
+    };
 
-    set.spawn(run_scraper(
-        scrapers::se::gbg::lh::LHScraper::new(
-            client.clone(),
-            db::get_site_relation(pg, db::SiteKey::new("se", "gbg", "lh"))
-                .await?
-                .site_id,
-        ),
-        cmds.subscribe(),
-        results.clone(),
-    ));
+    if !wanted(only, &LHScraper::site_key()) {
+        trace!(
+            scraper = LHScraper::NAME,
+            "Not the requested site, skipping"
+        );
+    } else if let Some(site_id) =
+        resolve_scraper_site(pg, LHScraper::NAME, LHScraper::site_key()).await?
+    {
+        let lh = LHScraper::new(client.clone(), site_id);
+        if enabled(lh.name()).await? {
+            schedules.push(ScraperSchedule::of(&lh));
+            set.spawn(run_scraper(
+                lh,
+                opts,
+                backoff,
+                cmds.subscribe(),
+                results.clone(),
+            ));
+        } else {
+            info!(scraper = lh.name(), "Scraper disabled, skipping");
+        }
+    }
     // Disabled until scraping architechture has been redesigned
     // set.spawn(run_scraper(
     //     scrapers::se::gbg::majorna::MajornaScraper::new(
     //         client.clone(),
-    //         db::get_site_relation(pg, db::SiteKey::new("se", "gbg", "maj"))
+    //         db::get_site_relation(pg, db::SiteKey::new(db::CountryUrlId("se"), db::CityUrlId("gbg"), db::SiteUrlId("maj")))
     //             .await?
     //             .site_id,
     //         request_delay,
@@ -221,7 +884,38 @@ async fn setup_scrapers(
     //     results.clone(),
     // ));
 
-    Ok(set)
+    // running without scrapers would otherwise look just like a successful run
+    if set.is_empty() {
+        return Err(NoScrapers.into());
+    }
+
+    Ok((set, schedules))
+}
+
+/// Look up the site a scraper writes to. A site missing from the DB only stops that scraper, so
+/// it's logged and skipped, letting the rest start, e.g. when the DB is only partially set up.
+/// Other errors are returned, since they would most likely hit every scraper.
+async fn resolve_scraper_site(
+    pg: &PgPool,
+    scraper: &'static str,
+    key: db::SiteKey<'_>,
+) -> Result<Option<Uuid>> {
+    let (country, city, site) = (
+        key.country_url_id.as_str(),
+        key.city_url_id.as_str(),
+        key.site_url_id.as_str(),
+    );
+    match db::get_site_relation(pg, key).await {
+        Ok(rel) => Ok(Some(rel.site_id)),
+        Err(sqlx::Error::RowNotFound) => {
+            warn!(
+                scraper,
+                country, city, site, "Site not found in DB, skipping scraper"
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 
 async fn stop_scrapers(
@@ -241,18 +935,141 @@ async fn stop_scrapers(
     Ok(())
 }
 
+/// Stop the scrapers, letting runs in progress finish and have their results handed to the writer,
+/// for at most `grace`, after which the scrapers still running are aborted.
+/// Scheduling must be stopped before this, or new runs might start while draining.
+async fn drain_scrapers(
+    writer: &mut DbWriter,
+    stats: &mut RunStats,
+    cmd_tx: broadcast::Sender<ScrapeCommand>,
+    mut tasks: task::JoinSet<()>,
+    res_rx: &mut mpsc::Receiver<ScrapeMsg>,
+    grace: Duration,
+) {
+    // idle scrapers stop right away, and running ones after sending their result, while those
+    // waiting to retry give up. Failing to send just means they have all stopped already.
+    let _ = cmd_tx.send(ScrapeCommand::Shutdown);
+
+    let mut drained = 0;
+    let drain = async {
+        // the scraper tasks hold the only senders, so this ends when all of them have stopped
+        while let Some(msg) = res_rx.recv().await {
+            handle_msg(writer, stats, msg).await;
+            drained += 1;
+        }
+    };
+    let timed_out = tokio::time::timeout(grace, drain).await.is_err();
+
+    tasks.abort_all();
+    let mut aborted = 0;
+    while let Some(res) = tasks.join_next().await {
+        if res.is_err_and(|e| e.is_cancelled()) {
+            aborted += 1;
+        }
+    }
+    // results sent between the timeout and the abort
+    res_rx.close();
+    while let Ok(msg) = res_rx.try_recv() {
+        handle_msg(writer, stats, msg).await;
+        drained += 1;
+    }
+
+    if timed_out {
+        warn!(
+            drained,
+            aborted,
+            ?grace,
+            "Scrapers still running after the shutdown grace period were aborted"
+        );
+    } else if drained > 0 {
+        info!(drained, "Drained results from scrapers running at shutdown");
+    } else {
+        trace!("No scrapers running at shutdown");
+    }
+}
+
+/// Run the scraper, retrying failed runs with exponential backoff, see backoff_delay.
+/// Returns None if told to shut down while waiting to retry.
+async fn run_with_retries(
+    scraper: &impl RestaurantScraper,
+    opts: ScraperOpts,
+    backoff: Duration,
+    cmds: &mut broadcast::Receiver<ScrapeCommand>,
+) -> Option<Result<ScrapeResult>> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        trace!(attempt, "Starting scrape...");
+        // a timeout is sent on as an error, so it's still accounted for
+        let start = Instant::now();
+        let res = tokio::time::timeout(opts.timeout, scraper.run()).await;
+        metrics::record_scrape_duration(scraper.name(), matches!(res, Ok(Ok(_))), start.elapsed());
+        let err = match res {
+            Ok(Ok(res)) => return Some(Ok(res)),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("scrape timed out after {:?}", opts.timeout),
+        };
+        if attempt > opts.retries {
+            return Some(Err(err));
+        }
+        let delay = backoff_delay(backoff, attempt);
+        trace!(attempt, err = %err, ?delay, "Scrape failed, retrying after delay");
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                cmd = cmds.recv() => match cmd {
+                    Ok(ScrapeCommand::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                        return None
+                    }
+                    // any run requested in the meantime is covered by the retry
+                    _ => continue,
+                },
+            }
+        }
+    }
+}
+
+/// The delay before retrying after the given attempt, doubling from base for each attempt, plus
+/// up to 50% random jitter, so that scrapers failing at the same time don't retry in lockstep
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    let exp = base.saturating_mul(1 << attempt.saturating_sub(1).min(10));
+    exp.mul_f64(1.0 + rand::random::<f64>() * 0.5)
+}
+
 async fn run_scraper(
     scraper: impl RestaurantScraper,
+    opts: ScraperOpts,
+    backoff: Duration,
     mut cmds: broadcast::Receiver<ScrapeCommand>,
-    results: mpsc::Sender<Result<ScrapeResult>>,
+    results: mpsc::Sender<ScrapeMsg>,
 ) {
     let name = scraper.name();
     loop {
         match cmds.recv().await {
             Ok(c) => match c {
-                ScrapeCommand::Run => {
-                    trace!(scraper = name, "Starting scrape...");
-                    if let Err(e) = results.send(scraper.run().await).await {
+                ScrapeCommand::Run(Some(target)) if target != name => continue,
+                ScrapeCommand::Run(_) => {
+                    let span = info_span!("scrape", scraper = name, site_id = field::Empty);
+                    let Some(res) = run_with_retries(&scraper, opts, backoff, &mut cmds)
+                        .instrument(span.clone())
+                        .await
+                    else {
+                        trace!(scraper = name, "Stopping due to shutdown command");
+                        break;
+                    };
+                    if let Ok(ref v) = res {
+                        span.record("site_id", field::display(v.site_id));
+                    }
+                    if let Err(e) = results
+                        .send(ScrapeMsg {
+                            scraper: name,
+                            span,
+                            res,
+                        })
+                        .await
+                    {
                         error!(scraper = name, err = %e, "Results channel closed, quitting");
                         break;
                     }
@@ -275,3 +1092,378 @@ async fn run_scraper(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_stats_check() {
+        let ok = RunStats {
+            ok: 2,
+            ..Default::default()
+        };
+        assert!(ok.check(2).is_ok());
+        assert!(ok.check(3).is_err(), "one did not report");
+
+        for stats in [
+            RunStats { failed: 1, ..ok },
+            RunStats { empty: 1, ..ok },
+            RunStats {
+                not_stored: 1,
+                ..ok
+            },
+        ] {
+            assert!(stats.check(2).is_err(), "{stats:?}");
+        }
+        assert!(RunStats::default().check(0).is_ok());
+    }
+
+    #[test]
+    fn site_path() {
+        for bad in ["", "se", "se/gbg", "se//lh", "se/gbg/lh/x", "/"] {
+            assert!(bad.parse::<SitePath>().is_err(), "{bad:?}");
+        }
+        let site: SitePath = "/se/gbg/lh/".parse().unwrap();
+        assert_eq!(site.to_string(), "se/gbg/lh");
+        assert!(check_site(&site).is_ok());
+
+        let err = check_site(&"se/gbg/majorna".parse().unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "no scraper for site se/gbg/majorna, available: se/gbg/lh ({})",
+                LHScraper::NAME
+            )
+        );
+    }
+
+    #[test]
+    fn check_selectors() {
+        let html = r#"<html><body><div class="menu"><h2>Fisk</h2></div></body></html>"#;
+        assert!(super::check_selectors(html, &["div.menu", "h2"]).is_ok());
+        let err = super::check_selectors(html, &["div.menu", "span.price", "h3"]).unwrap_err();
+        assert_eq!(err.to_string(), "no match for selectors: span.price, h3");
+        assert!(super::check_selectors(html, &["div..menu"]).is_err());
+    }
+
+    #[test]
+    fn drop_oversized() {
+        let site_id = Uuid::new_v4();
+        let restaurant = |name: &str, dishes: usize| {
+            let r = models::Restaurant::new_for_site(name, site_id);
+            let dishes = (0..dishes)
+                .map(|n| models::Dish::new(&format!("Dish {n}")).for_restaurant(r.restaurant_id))
+                .collect();
+            r.with_dishes(dishes)
+        };
+        let mut res = ScrapeResult {
+            site_id,
+            restaurants: vec![restaurant("Kårhuset", 3), restaurant("Bistrot", 2)],
+            authoritative: true,
+        };
+        assert!(res.drop_oversized(3).is_empty());
+        assert!(res.authoritative);
+
+        assert_eq!(res.drop_oversized(2), [("Kårhuset".to_string(), 3)]);
+        assert_eq!(res.num_restaurants(), 1);
+        assert_eq!(res.restaurants[0].name, "Bistrot");
+        // so that Kårhuset keeps what it has in the DB
+        assert!(!res.authoritative);
+    }
+
+    fn writer(pg: PgPool) -> DbWriter {
+        DbWriter::new(
+            pg,
+            &DbOpts {
+                dish_history: None,
+                writers: 2,
+                result_buffer: 1,
+                ensure_hierarchy: false,
+                max_dishes_per_restaurant: 100,
+            },
+        )
+    }
+
+    fn msg(res: Result<ScrapeResult>) -> ScrapeMsg {
+        ScrapeMsg {
+            scraper: "test",
+            span: Span::none(),
+            res,
+        }
+    }
+
+    #[sqlx::test]
+    async fn exit_code_reflects_failures(pg: PgPool) {
+        let site_id = db::ensure_site_relation(
+            &pg,
+            db::SiteKey::new(
+                db::CountryUrlId("se"),
+                db::CityUrlId("gbg"),
+                db::SiteUrlId("lh"),
+            ),
+        )
+        .await
+        .unwrap()
+        .site_id;
+        let result = |site_id| {
+            let r = models::Restaurant::new_for_site("Kårhuset", site_id);
+            let d = models::Dish::new("Fisk").for_restaurant(r.restaurant_id);
+            Ok(ScrapeResult {
+                site_id,
+                restaurants: vec![r.with_dish(d)],
+                authoritative: true,
+            })
+        };
+
+        let mut w = writer(pg.clone());
+        let mut stats = RunStats::default();
+        handle_msg(&mut w, &mut stats, msg(result(site_id))).await;
+        stats.not_stored = w.wait().await;
+        assert!(stats.check(1).is_ok(), "{stats:?}");
+
+        // a failing scraper
+        let mut w = writer(pg.clone());
+        let mut stats = RunStats::default();
+        handle_msg(&mut w, &mut stats, msg(result(site_id))).await;
+        handle_msg(&mut w, &mut stats, msg(Err(anyhow!("boom")))).await;
+        stats.not_stored = w.wait().await;
+        assert_eq!(stats.failed, 1);
+        assert!(stats.check(2).is_err());
+
+        // a result for a site that doesn't exist can't be stored
+        let mut w = writer(pg.clone());
+        let mut stats = RunStats::default();
+        handle_msg(&mut w, &mut stats, msg(result(Uuid::new_v4()))).await;
+        stats.not_stored = w.wait().await;
+        assert_eq!((stats.ok, stats.not_stored), (1, 1));
+        assert!(stats.check(1).is_err());
+    }
+
+    #[sqlx::test]
+    async fn missing_scraper_site_is_skipped(pg: PgPool) {
+        let key = |site| {
+            db::SiteKey::new(
+                db::CountryUrlId("se"),
+                db::CityUrlId("gbg"),
+                db::SiteUrlId(site),
+            )
+        };
+        let res = resolve_scraper_site(&pg, "test", key("nowhere")).await;
+        assert_eq!(res.unwrap(), None);
+
+        let site_id = db::ensure_site_relation(&pg, key("lh"))
+            .await
+            .unwrap()
+            .site_id;
+        let res = resolve_scraper_site(&pg, "test", key("lh")).await;
+        assert_eq!(res.unwrap(), Some(site_id));
+
+        // anything else than a missing site is still an error
+        pg.close().await;
+        assert!(resolve_scraper_site(&pg, "test", key("lh")).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn writes_to_the_same_site_are_serialized(pg: PgPool) {
+        let site_id = db::ensure_site_relation(
+            &pg,
+            db::SiteKey::new(
+                db::CountryUrlId("se"),
+                db::CityUrlId("gbg"),
+                db::SiteUrlId("lh"),
+            ),
+        )
+        .await
+        .unwrap()
+        .site_id;
+        let mut w = DbWriter::new(
+            pg.clone(),
+            &DbOpts {
+                dish_history: None,
+                writers: 8,
+                result_buffer: 8,
+                ensure_hierarchy: false,
+                max_dishes_per_restaurant: 100,
+            },
+        );
+        // each result replaces everything for the site with its own restaurant, so if any two
+        // writes overlapped, both of their restaurants would be left
+        for i in 0..16 {
+            let r = models::Restaurant::new_for_site(&format!("Restaurant {i}"), site_id);
+            let dishes = (0..50)
+                .map(|n| models::Dish::new(&format!("Dish {n}")).for_restaurant(r.restaurant_id))
+                .collect();
+            let res = ScrapeResult {
+                site_id,
+                restaurants: vec![r.with_dishes(dishes)],
+                authoritative: true,
+            };
+            w.write("test", res, Span::none()).await;
+        }
+        assert_eq!(w.wait().await, 0);
+
+        let data = db::list_dishes_for_site_by_id(&mut pg.begin().await.unwrap(), site_id)
+            .await
+            .unwrap();
+        let counts = data.counts();
+        assert_eq!((counts.restaurants, counts.dishes), (1, 50));
+    }
+    /// A scraper whose runs never finish, like one stuck on a site that never answers
+    struct Hanging;
+
+    impl RestaurantScraper for Hanging {
+        async fn run(&self) -> Result<ScrapeResult> {
+            std::future::pending().await
+        }
+
+        fn name(&self) -> &'static str {
+            "hanging"
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hanging_scrape_times_out() {
+        let opts = ScraperOpts {
+            timeout: Duration::from_secs(60),
+            retries: 1,
+        };
+        let (_tx, mut cmds) = broadcast::channel(1);
+        let start = tokio::time::Instant::now();
+        let res = run_with_retries(&Hanging, opts, Duration::from_secs(1), &mut cmds).await;
+        let err = res.expect("not shut down").unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        // both attempts timed out, with a backoff of 1-1.5s in between
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(121), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(121_500), "{elapsed:?}");
+    }
+    /// How many scrapers setup_scrapers spawns. They're aborted again right away.
+    async fn spawned(pg: &PgPool, only: Option<&SitePath>) -> Result<usize> {
+        let client = Client::build(Opts::default()).await?;
+        let opts = ScraperOpts {
+            timeout: Duration::from_secs(60),
+            retries: 0,
+        };
+        let (cmds, _) = broadcast::channel(1);
+        let (results, _) = mpsc::channel(1);
+        let (set, schedules) = setup_scrapers(pg, client, opts, only, cmds, results).await?;
+        assert_eq!(set.len(), schedules.len());
+        Ok(set.len())
+    }
+
+    #[sqlx::test]
+    async fn disabled_scraper_is_not_spawned(pg: PgPool) {
+        db::ensure_site_relation(&pg, LHScraper::site_key())
+            .await
+            .unwrap();
+        assert_eq!(spawned(&pg, None).await.unwrap(), 1);
+
+        db::set_scraper_enabled(&pg, LHScraper::NAME, false)
+            .await
+            .unwrap();
+        let err = spawned(&pg, None).await.unwrap_err();
+        assert!(err.is::<NoScrapers>(), "{err}");
+        // unless asked for by site
+        let only: SitePath = "se/gbg/lh".parse().unwrap();
+        assert_eq!(spawned(&pg, Some(&only)).await.unwrap(), 1);
+    }
+
+    #[sqlx::test]
+    async fn no_scrapers_is_an_error(pg: PgPool) {
+        // a site without a scraper leaves nothing to run, which must not look like success
+        let only: SitePath = "se/gbg/majorna".parse().unwrap();
+        let err = spawned(&pg, Some(&only)).await.unwrap_err();
+        assert!(err.is::<NoScrapers>(), "{err}");
+        assert_eq!(err.to_string(), "no scrapers configured");
+    }
+    #[sqlx::test]
+    async fn dead_scraper_tasks_are_not_waited_for(pg: PgPool) {
+        let (res_tx, mut res_rx) = mpsc::channel(4);
+        let (_shutdown_tx, mut shutdown) = broadcast::channel(1);
+        let mut tasks = task::JoinSet::new();
+        tasks.spawn(async { panic!("scraper bug") });
+        tasks.spawn(std::future::pending::<()>()).abort();
+        tasks.spawn(async move {
+            res_tx.send(msg(Err(anyhow!("boom")))).await.unwrap();
+            // live scrapers keep running until told to stop
+            std::future::pending::<()>().await
+        });
+
+        let mut w = writer(pg);
+        let stats = collect_results(&mut w, &mut tasks, &mut shutdown, &mut res_rx).await;
+        assert_eq!(stats.failed, 1);
+        // the dead ones are missing, so the run still fails
+        assert!(stats.check(3).is_err());
+    }
+    #[sqlx::test]
+    async fn db_write_logs_carry_the_scrape_span(pg: PgPool) {
+        let site_id = db::ensure_site_relation(&pg, LHScraper::site_key())
+            .await
+            .unwrap()
+            .site_id;
+        let r = models::Restaurant::new_for_site("Kårhuset", site_id);
+        let d = models::Dish::new("Fisk").for_restaurant(r.restaurant_id);
+        let res = ScrapeResult {
+            site_id,
+            restaurants: vec![r.with_dish(d)],
+            authoritative: true,
+        };
+
+        let logs = util::LogCapture::start();
+        let mut w = writer(pg);
+        let span = info_span!("scrape", scraper = "test", site_id = %site_id);
+        w.write("test", res, span).await;
+        assert_eq!(w.wait().await, 0);
+
+        let out = logs.output();
+        let line = out
+            .lines()
+            .find(|l| l.contains("DB update done"))
+            .unwrap_or_else(|| panic!("no DB update logged: {out}"));
+        assert!(
+            line.contains(&format!("scrape{{scraper=\"test\" site_id={site_id}}}")),
+            "{line}"
+        );
+    }
+    #[sqlx::test]
+    async fn ensure_hierarchy_inserts_missing_rows(pg: PgPool) {
+        // start from an empty DB, without the seeded sites
+        sqlx::query("delete from country")
+            .execute(&pg)
+            .await
+            .unwrap();
+        let lh = || db::get_site_relation(&pg, LHScraper::site_key());
+        assert!(matches!(lh().await, Err(sqlx::Error::RowNotFound)));
+
+        // nothing for other sites
+        let only: SitePath = "se/gbg/majorna".parse().unwrap();
+        ensure_hierarchy(&pg, Some(&only)).await.unwrap();
+        assert!(matches!(lh().await, Err(sqlx::Error::RowNotFound)));
+
+        ensure_hierarchy(&pg, None).await.unwrap();
+        let ids = lh().await.unwrap();
+        // and nothing new the second time
+        ensure_hierarchy(&pg, None).await.unwrap();
+        assert_eq!(lh().await.unwrap(), ids);
+        let countries: i64 = sqlx::query_scalar("select count(*) from country")
+            .fetch_one(&pg)
+            .await
+            .unwrap();
+        assert_eq!(countries, 1);
+    }
+    #[test]
+    fn paused_schedule_skips_runs() {
+        let (pause, paused) = watch::channel(true);
+        let (tx, mut cmds) = broadcast::channel(4);
+        notify_scheduled(Uuid::new_v4(), "lh", &paused, &tx);
+        assert!(cmds.try_recv().is_err());
+
+        pause.send_replace(false);
+        notify_scheduled(Uuid::new_v4(), "lh", &paused, &tx);
+        assert!(matches!(
+            cmds.try_recv(),
+            Ok(ScrapeCommand::Run(Some("lh")))
+        ));
+    }
+}
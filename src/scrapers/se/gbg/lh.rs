@@ -5,7 +5,8 @@
 ///
 use crate::{
     cache::Client,
-    models::{Dish, Restaurant},
+    db::{CityUrlId, CountryUrlId, SiteKey, SiteUrlId},
+    models::{Dish, Price, Restaurant},
     scrape::{RestaurantScraper, ScrapeResult},
     util::*,
 };
@@ -27,12 +28,18 @@ static ATTR_HREF: &str = "href";
 static MAPS_DOMAIN: &str = "maps.google.com";
 static ERR_INVALID_HTML: &str = "Invalid HTML";
 
+static CSS_VIEW_CONTENT: &str = "div.view-content";
+static CSS_DISH: &str = "span.dish-name";
+static CSS_DISH_PRICE: &str = "div.table-list__column--price";
+// selectors for the menu page, not the restaurant pages where addresses are looked up
+static REQUIRED_SELECTORS: &[&str] = &[CSS_VIEW_CONTENT, CSS_DISH, CSS_DISH_PRICE];
+
 lazy_static! {
     static ref SEL_CONTENT: Selector = sel("div.content");
-    static ref SEL_VIEW_CONTENT: Selector = sel("div.view-content");
-    static ref SEL_DISH: Selector = sel("span.dish-name");
+    static ref SEL_VIEW_CONTENT: Selector = sel(CSS_VIEW_CONTENT);
+    static ref SEL_DISH: Selector = sel(CSS_DISH);
     static ref SEL_DISH_TYPE: Selector = sel("div.icon-dish");
-    static ref SEL_DISH_PRICE: Selector = sel("div.table-list__column--price");
+    static ref SEL_DISH_PRICE: Selector = sel(CSS_DISH_PRICE);
     static ref SEL_LINK: Selector = sel("p > a");
     static ref SEL_ADDR: Selector = sel("div > h3 + p");
 }
@@ -40,7 +47,8 @@ lazy_static! {
 #[derive(Clone)]
 pub struct LHScraper {
     client: Client,
-    url: &'static str,
+    url: String,
+    url_prefix: String,
     site_id: Uuid,
 }
 
@@ -53,14 +61,39 @@ struct AddrInfo {
 }
 
 impl LHScraper {
+    pub const NAME: &'static str = "SE::GBG::LH::Scraper";
+
+    /// Where in the country/city/site hierarchy this scraper's restaurants belong
+    pub fn site_key() -> SiteKey<'static> {
+        SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh"))
+    }
+
     pub fn new(client: Client, site_id: Uuid) -> Self {
         Self {
-            url: SCRAPE_URL, // TODO: evaluate if this should rather be passed in
+            url: SCRAPE_URL.into(),
+            url_prefix: URL_PREFIX.into(),
             client,
             site_id,
         }
     }
 
+    /// Scrape from another URL than the default, e.g. a fixture server when testing
+    pub fn with_url(self, url: &str) -> Self {
+        Self {
+            url: url.into(),
+            ..self
+        }
+    }
+
+    /// Look up restaurant pages under another URL than the default, e.g. a fixture server when
+    /// testing
+    pub fn with_url_prefix(self, url_prefix: &str) -> Self {
+        Self {
+            url_prefix: url_prefix.into(),
+            ..self
+        }
+    }
+
     async fn get(&self, url: &str) -> Result<String> {
         self.client.get_as_string(url).await
     }
@@ -121,6 +154,10 @@ impl LHScraper {
             }
             let info = info.unwrap();
             v.address = info.address;
+            if let Some((lat, lon)) = info.map_url.as_deref().and_then(parse_latlon_from_maps_url) {
+                v.lat = Some(lat);
+                v.lon = Some(lon);
+            }
             v.map_url = info.map_url;
         }
         restaurants
@@ -129,7 +166,15 @@ impl LHScraper {
 
 impl RestaurantScraper for LHScraper {
     fn name(&self) -> &'static str {
-        "SE::GBG::LH::Scraper"
+        Self::NAME
+    }
+
+    fn required_selectors(&self) -> &[&'static str] {
+        REQUIRED_SELECTORS
+    }
+
+    fn validation_urls(&self) -> Vec<String> {
+        vec![self.url.clone()]
     }
 
     async fn run(&self) -> Result<ScrapeResult> {
@@ -138,7 +183,7 @@ impl RestaurantScraper for LHScraper {
         // Due to some rust bug/weirdness, we need to wrap this in a scope, otherwise the compiler
         // will complain about the selection being non-Send, held across an await point
         {
-            let html = Html::parse_document(&self.get(self.url).await?);
+            let html = Html::parse_document(&self.get(&self.url).await?);
             let vc = match html.select(&SEL_VIEW_CONTENT).next() {
                 Some(vc) => vc,
                 None => bail!(ERR_INVALID_HTML),
@@ -154,12 +199,13 @@ impl RestaurantScraper for LHScraper {
                             if let Some(name) = e.text().next().map(|v| v.trim().into()) {
                                 cur_restaurant_name = name;
                             }
-                        } else if let Some(d) = parse_dish(&e) {
+                        } else if let Some(mut d) = parse_dish(&e) {
                             if cur_restaurant_name.is_empty() {
                                 continue;
                             }
+                            d.source_url = Some(self.url.clone());
                             let restaurant = restaurants
-                                .entry(get_restaurant_link(&cur_restaurant_name))
+                                .entry(get_restaurant_link(&self.url_prefix, &cur_restaurant_name))
                                 .or_insert_with(|| {
                                     Restaurant::new_for_site(&cur_restaurant_name, self.site_id)
                                 });
@@ -179,6 +225,7 @@ impl RestaurantScraper for LHScraper {
         Ok(ScrapeResult {
             site_id: self.site_id,
             restaurants: restaurants.into_values().collect(),
+            authoritative: true,
         })
     }
 }
@@ -192,8 +239,8 @@ fn update_restaurant_links(mut r: HashMap<String, Restaurant>) -> HashMap<String
 fn parse_dish(e: &ElementRef) -> Option<Dish> {
     let (name, description) = get_dish_name_and_desc(e);
     let price = match get_text(e, &SEL_DISH_PRICE) {
-        None => 0.0,
-        Some(v) => parse_float(v.trim()),
+        None => Price::ZERO,
+        Some(v) => parse_price(v.trim()),
     };
     let mut dish = Dish {
         dish_id: Uuid::new_v4(), // very important when creating a Dish manually!
@@ -220,7 +267,7 @@ fn get_dish_name_and_desc(e: &ElementRef) -> (Option<String>, Option<String>) {
     }
 }
 
-fn get_restaurant_link(name: &str) -> String {
+fn get_restaurant_link(url_prefix: &str, name: &str) -> String {
     // Local dev version
     // format!(
     //     "{}/{}",
@@ -233,7 +280,71 @@ fn get_restaurant_link(name: &str) -> String {
     // They also seem to remove certain words, like "by" and "of", so we strip those as well.
     format!(
         "{}{}",
-        URL_PREFIX,
+        url_prefix,
         slugify!(&str::replace(name, "'", ""), stop_words = "by,of")
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::Opts, fixtures::FixtureServer};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn scrape_saved_page() {
+        let server =
+            FixtureServer::start(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/se/gbg/lh"))
+                .await
+                .unwrap();
+        let client = Client::build(Opts {
+            request_timeout: Duration::from_secs(10),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let site_id = Uuid::new_v4();
+        let scraper = LHScraper::new(client, site_id)
+            .with_url(&server.url("dagens-lunch.html"))
+            .with_url_prefix(&server.url("sv/"));
+
+        let res = scraper.run().await.unwrap();
+        assert_eq!(res.site_id, site_id);
+        assert_eq!(res.num_restaurants(), 2);
+        assert_eq!(res.num_dishes(), 3);
+
+        let bistrot = res
+            .restaurants
+            .iter()
+            .find(|r| r.name == "Bistrot")
+            .unwrap();
+        assert_eq!(bistrot.site_id, site_id);
+        assert_eq!(
+            bistrot.url.as_deref(),
+            Some(server.url("sv/bistrot").as_str())
+        );
+        assert_eq!(bistrot.address.as_deref(), Some("57.7065,11.9384"));
+        assert_eq!((bistrot.lat, bistrot.lon), (Some(57.7065), Some(11.9384)));
+        let dish = bistrot
+            .dishes
+            .values()
+            .find(|d| d.name == "Köttbullar")
+            .unwrap();
+        assert_eq!(dish.restaurant_id, bistrot.restaurant_id);
+        assert_eq!(
+            dish.description.as_deref(),
+            Some("med potatismos och lingon")
+        );
+        assert_eq!(dish.price, Price::new(125.into()));
+        assert_eq!(dish.tags, ["Kött"]);
+        assert_eq!(dish.source_url.as_deref(), Some(scraper.url.as_str()));
+
+        let kooperativet = res
+            .restaurants
+            .iter()
+            .find(|r| r.name == "Kooperativet")
+            .unwrap();
+        assert_eq!(kooperativet.address.as_deref(), Some("Lindholmsplatsen 1"));
+        assert_eq!(kooperativet.map_url, None);
+    }
+}
@@ -42,7 +42,11 @@ impl RestaurantScraper for MajornaScraper {
 
     async fn run(&self) -> Result<ScrapeResult> {
         let ot_scraper = OldTownScraper::new(self.client.clone(), self.site_id, self.request_delay);
-        ot_scraper.run().await
+        // Old Town is the only restaurant on the site so far
+        Ok(ScrapeResult {
+            authoritative: true,
+            ..ot_scraper.run().await?
+        })
     }
 }
 
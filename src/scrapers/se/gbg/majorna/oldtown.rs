@@ -20,13 +20,21 @@ static EP_TALLRIK: &str = "/ot_tallrik.html"; // "/tandoori-kitchen/" irl
 static EP_PITA: &str = "/ot_pita.html"; // "/chicken-dishes/" irl
 static ERR_INVALID_HTML: &str = "Invalid HTML";
 
+static CSS_DISH_CONTAINER: &str = "div.mt-i-c.cf.mt-border.line-color";
+static CSS_DISH_NAME: &str = "h3";
+static CSS_DISH_PRICE: &str = "h3 > strong";
+// the description is in either of these, so neither is required
+static CSS_DISH_DESC_P: &str = "h3 + p";
+static CSS_DISH_DESC_D: &str = "h3 + div";
+static REQUIRED_SELECTORS: &[&str] = &[CSS_DISH_CONTAINER, CSS_DISH_NAME, CSS_DISH_PRICE];
+
 //
 lazy_static! {
-    static ref SEL_DISH_CONTAINER: Selector = sel("div.mt-i-c.cf.mt-border.line-color");
-    static ref SEL_DISH_NAME: Selector = sel("h3");
-    static ref SEL_DISH_PRICE: Selector = sel("h3 > strong");
-    static ref SEL_DISH_DESC_P: Selector = sel("h3 + p");
-    static ref SEL_DISH_DESC_D: Selector = sel("h3 + div");
+    static ref SEL_DISH_CONTAINER: Selector = sel(CSS_DISH_CONTAINER);
+    static ref SEL_DISH_NAME: Selector = sel(CSS_DISH_NAME);
+    static ref SEL_DISH_PRICE: Selector = sel(CSS_DISH_PRICE);
+    static ref SEL_DISH_DESC_P: Selector = sel(CSS_DISH_DESC_P);
+    static ref SEL_DISH_DESC_D: Selector = sel(CSS_DISH_DESC_D);
 }
 
 #[derive(Clone, Debug)]
@@ -69,6 +77,16 @@ impl RestaurantScraper for OldTownScraper {
         "SE::GBG::Majorna::OldTown::Scraper"
     }
 
+    fn required_selectors(&self) -> &[&'static str] {
+        REQUIRED_SELECTORS
+    }
+
+    fn validation_urls(&self) -> Vec<String> {
+        [EP_PITA, EP_TALLRIK]
+            .map(|ep| format!("{}/{}", URL_PREFIX, ep))
+            .to_vec()
+    }
+
     async fn run(&self) -> Result<ScrapeResult> {
         let ot = Restaurant::new_for_site("Old Town", self.site_id);
         let mut dishes = Vec::new();
@@ -83,6 +101,7 @@ impl RestaurantScraper for OldTownScraper {
         Ok(ScrapeResult {
             site_id: self.site_id,
             restaurants: vec![ot.with_dishes(dishes)],
+            authoritative: false,
         })
     }
 }
@@ -116,7 +135,7 @@ fn parse_dish(e: &ElementRef) -> Option<Dish> {
             dish_id: Uuid::new_v4(),
             name: dn.into(),
             description: dish_desc,
-            price: parse_float(dp),
+            price: parse_price(dp),
             ..Default::default()
         });
     }
@@ -3,7 +3,8 @@ use tokio::signal::{
     self,
     unix::{signal, SignalKind},
 };
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
+use tracing::warn;
 
 pub async fn shutdown_channel() -> Result<broadcast::Receiver<()>> {
     let (tx, rx) = broadcast::channel(4);
@@ -17,6 +18,26 @@ pub async fn shutdown_channel() -> Result<broadcast::Receiver<()>> {
     Ok(rx)
 }
 
+/// Returns a receiver for whether scraping is paused, which is toggled on each SIGUSR1.
+/// Useful for backing off from a site without stopping the process.
+pub fn pause_channel() -> Result<watch::Receiver<bool>> {
+    let (tx, rx) = watch::channel(false);
+    let mut sig_usr1 = signal(SignalKind::user_defined1())?;
+
+    tokio::spawn(async move {
+        while sig_usr1.recv().await.is_some() {
+            tx.send_modify(|paused| *paused = !*paused);
+            if *tx.borrow() {
+                warn!("Scraping paused, send SIGUSR1 again to resume");
+            } else {
+                warn!("Scraping resumed");
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
 // based on:
 // https://github.com/davidpdrsn/realworld-axum-sqlx/blob/main/src/http/mod.rs
 pub async fn shutdown_signal() {
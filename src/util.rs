@@ -1,5 +1,10 @@
+use crate::models::Price;
+use chrono::{DateTime, Datelike, Local, Utc, Weekday};
+use chrono_tz::Tz;
 use nom::number::complete;
 use scraper::{ElementRef, Selector};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use url::Url;
 
 pub fn sel(selector: &str) -> Selector {
     Selector::parse(selector).unwrap()
@@ -19,10 +24,98 @@ pub fn parse_float(s: &str) -> f32 {
     }
 }
 
+/// Parse a price from the start of the given string, e.g. "129.90 kr", keeping the exact decimals.
+/// Empty or unparseable strings give a zero price, and negative prices are clamped to zero.
+pub fn parse_price(s: &str) -> Price {
+    match complete::recognize_float::<_, ()>(s.trim()) {
+        Ok((_, v)) => v.parse().unwrap_or(Price::ZERO),
+        _ => Price::ZERO,
+    }
+}
+
+/// Get the current weekday in the given timezone, falling back to the local timezone if none is
+/// given. Useful for scrapers that need to pick out the menu for today.
+pub fn get_weekday(timezone: Option<Tz>) -> Weekday {
+    weekday_at(Utc::now(), timezone)
+}
+
+/// The weekday at the given instant in the given timezone, or the local one if none is given
+pub fn weekday_at(now: DateTime<Utc>, timezone: Option<Tz>) -> Weekday {
+    match timezone {
+        Some(tz) => now.with_timezone(&tz).weekday(),
+        None => now.with_timezone(&Local).weekday(),
+    }
+}
+
+/// Mean radius of the earth, in km
+pub const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Great-circle distance in km between two coordinates, using the Haversine formula
+pub fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Get the coordinates from a Google Maps URL, if it has them in the query, as in either of:
+///   https://maps.google.com/?q=57.7065,11.9384
+///   https://www.google.com/maps/search/?api=1&query=57.7065,11.9384
+/// Returns None if the query is just an address, or if the values are out of range.
+pub fn parse_latlon_from_maps_url(url: &str) -> Option<(f64, f64)> {
+    let url = Url::parse(url).ok()?;
+    let (_, v) = url.query_pairs().find(|(k, _)| k == "q" || k == "query")?;
+    let (lat, lon) = v.split_once(',')?;
+    let lat: f64 = lat.trim().parse().ok()?;
+    let lon: f64 = lon.trim().parse().ok()?;
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return None;
+    }
+    Some((lat, lon))
+}
+
 pub fn reduce_whitespace(s: &str) -> String {
     s.split_whitespace().collect::<Vec<&str>>().join(" ")
 }
 
+/// Make a url_id out of the given text, as the url_id_key function in the DB does when comparing
+/// them: lowercase, without accents, and with anything but letters and digits as single dashes,
+/// e.g. "Göteborg Centrum" becomes "goteborg-centrum".
+/// Letters without a decomposed form are spelled out the way unaccent in Postgres does it. A full
+/// transliteration, as with deunicode, would also spell out e.g. Cyrillic or CJK, which unaccent
+/// leaves alone, so the two would no longer agree.
+pub fn slug(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in fold_text(s).chars() {
+        match c {
+            'æ' => out.push_str("ae"),
+            'ø' => out.push('o'),
+            'œ' => out.push_str("oe"),
+            'ß' => out.push_str("ss"),
+            'đ' | 'ð' => out.push('d'),
+            'ł' => out.push('l'),
+            'þ' => out.push_str("th"),
+            c if c.is_ascii_alphanumeric() => out.push(c),
+            _ => out.push('-'),
+        }
+    }
+    out.split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+/// Lowercase and strip accents, for comparing text the way people type it, e.g. "Köttbullar"
+/// becomes "kottbullar". Letters that aren't made up of a base letter and an accent, like "ø" or
+/// "æ", are kept as is.
+pub fn fold_text(s: &str) -> String {
+    s.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
 // we need to have this split into a separate function, so that thread_rng is dropped before the
 // call to sleep, since ThreadRng is not Send
 // fn get_random_ms(min: u64, max: u64) -> u64 {
@@ -32,3 +125,121 @@ pub fn reduce_whitespace(s: &str) -> String {
 // pub async fn wait_random_range_ms(min: u64, max: u64) {
 //     sleep(Duration::from_millis(get_random_ms(min, max))).await
 // }
+
+/// Collects everything logged on the current thread while alive, for asserting on log output in
+/// tests. Tasks spawned on a current-thread runtime log here as well.
+#[cfg(test)]
+pub struct LogCapture {
+    buf: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    _guard: tracing::subscriber::DefaultGuard,
+}
+
+#[cfg(test)]
+impl LogCapture {
+    pub fn start() -> Self {
+        let buf = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || CaptureWriter(writer.clone()))
+            .finish();
+        Self {
+            buf,
+            _guard: tracing::subscriber::set_default(subscriber),
+        }
+    }
+
+    /// All output so far
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.buf.lock().unwrap()).into_owned()
+    }
+}
+
+#[cfg(test)]
+struct CaptureWriter(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl std::io::Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weekday_in_timezone() {
+        // late Monday evening in Stockholm is already Tuesday in Tokyo
+        let now = "2024-11-25T22:30:00Z".parse().unwrap();
+        assert_eq!(
+            weekday_at(now, Some(chrono_tz::Europe::Stockholm)),
+            Weekday::Mon
+        );
+        assert_eq!(weekday_at(now, Some(chrono_tz::Asia::Tokyo)), Weekday::Tue);
+    }
+
+    #[test]
+    fn haversine() {
+        assert_eq!(haversine_km(57.7, 11.9, 57.7, 11.9), 0.0);
+        // Göteborg to Stockholm is about 398 km as the crow flies
+        let d = haversine_km(57.7089, 11.9746, 59.3293, 18.0686);
+        assert!((d - 398.0).abs() < 2.0, "{d}");
+        // one degree of latitude is about 111 km anywhere
+        let d = haversine_km(0.0, 0.0, 1.0, 0.0);
+        assert!((d - 111.19).abs() < 0.01, "{d}");
+        assert_eq!(
+            haversine_km(57.7, 11.9, 59.3, 18.1),
+            haversine_km(59.3, 18.1, 57.7, 11.9)
+        );
+    }
+
+    #[test]
+    fn latlon_from_maps_url() {
+        assert_eq!(
+            parse_latlon_from_maps_url("https://maps.google.com/?q=57.7065,11.9384"),
+            Some((57.7065, 11.9384))
+        );
+        assert_eq!(
+            parse_latlon_from_maps_url(
+                "https://www.google.com/maps/search/?api=1&query=57.7065,%2011.9384"
+            ),
+            Some((57.7065, 11.9384))
+        );
+        for url in [
+            "https://maps.google.com/?q=Lindholmspiren+5,+G%C3%B6teborg",
+            "https://maps.google.com/?q=91,11.9",
+            "https://maps.google.com/?q=57.7,181",
+            "https://maps.google.com/",
+            "not a url",
+        ] {
+            assert_eq!(parse_latlon_from_maps_url(url), None, "{url}");
+        }
+    }
+
+    #[test]
+    fn fold() {
+        assert_eq!(fold_text("Köttbullar"), "kottbullar");
+        assert_eq!(fold_text("CRÈME BRÛLÉE"), "creme brulee");
+        assert_eq!(fold_text("Smørrebrød"), "smørrebrød");
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("Göteborg"), "goteborg");
+        assert_eq!(slug("göteborg"), slug("GOTEBORG"));
+        assert_eq!(slug("  Göteborg Centrum! "), "goteborg-centrum");
+        assert_eq!(slug("Café 'Pier 11'"), "cafe-pier-11");
+        assert_eq!(slug("Smørrebrød"), "smorrebrod");
+        assert_eq!(slug("Æbleskiver"), "aebleskiver");
+        assert_eq!(slug("Straße"), "strasse");
+        assert_eq!(slug("---"), "");
+    }
+}
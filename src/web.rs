@@ -1,25 +1,136 @@
-use crate::db;
+use crate::{
+    db,
+    metrics::Metrics,
+    models::{
+        api::{City, Dish, DishSort, LunchData, Restaurant, Site},
+        Price,
+    },
+    scrape,
+};
 use axum::{
-    http::StatusCode,
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
 };
 use compact_str::CompactString;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, NoneAsEmptyString};
+use serde_with::{formats::CommaSeparator, serde_as, NoneAsEmptyString, StringWithSeparator};
 use sqlx::PgPool;
-use tracing::error;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use store::LunchStore;
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
+pub mod admin;
 pub mod api;
 pub mod html;
+pub mod store;
 
 #[derive(Debug, Clone)]
 pub struct ApiContext {
     pub db: PgPool,
+    pub store: Arc<dyn LunchStore>,
+    pub list_cache: api::ListCache,
+    pub sites_cache: html::SitesCache,
+    pub fallback_cache: api::FallbackCache,
     pub gtag: CompactString,
+    /// How old the newest data for a site can be before it's flagged as stale in the HTML view
+    pub stale_after: Duration,
+    /// Only list sites in the country with this url_id on the HTML index pages, if set
+    pub index_country: Option<CompactString>,
+    /// Bearer token for the /internal/ routes, which are disabled if unset
+    pub internal_token: Option<CompactString>,
+    /// Timeout for API routes that may return whole sites or more
+    pub heavy_timeout: Duration,
+    /// Handle for rendering metrics at /metrics, which is disabled if unset
+    pub metrics: Option<Metrics>,
+    /// Bearer token for all routes in the admin server, which rejects everything if unset
+    pub admin_token: Option<CompactString>,
+    /// Origins allowed to call the JSON API from a browser. No CORS headers are sent if empty.
+    pub cors_origins: Vec<HeaderValue>,
+    /// Scrape results posted to the API with more dishes than this for a restaurant are rejected
+    pub max_dishes_per_restaurant: usize,
 }
 
 impl ApiContext {
+    /// Create a context where the store is backed by the given pool
+    pub fn new(db: PgPool, gtag: CompactString) -> Self {
+        Self {
+            store: Arc::new(db.clone()),
+            list_cache: api::build_list_cache(),
+            sites_cache: html::build_sites_cache(),
+            fallback_cache: api::build_fallback_cache(),
+            db,
+            gtag,
+            stale_after: html::DEFAULT_STALE_AFTER,
+            index_country: None,
+            internal_token: None,
+            heavy_timeout: api::DEFAULT_HEAVY_TIMEOUT,
+            metrics: None,
+            admin_token: None,
+            cors_origins: Vec::new(),
+            max_dishes_per_restaurant: scrape::DEFAULT_MAX_DISHES_PER_RESTAURANT,
+        }
+    }
+
+    pub fn with_max_dishes_per_restaurant(self, max_dishes_per_restaurant: usize) -> Self {
+        Self {
+            max_dishes_per_restaurant,
+            ..self
+        }
+    }
+
+    pub fn with_cors_origins(self, cors_origins: Vec<HeaderValue>) -> Self {
+        Self {
+            cors_origins,
+            ..self
+        }
+    }
+
+    pub fn with_admin_token(self, admin_token: Option<CompactString>) -> Self {
+        Self {
+            admin_token,
+            ..self
+        }
+    }
+
+    pub fn with_metrics(self, metrics: Option<Metrics>) -> Self {
+        Self { metrics, ..self }
+    }
+
+    pub fn with_heavy_timeout(self, heavy_timeout: Duration) -> Self {
+        Self {
+            heavy_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_internal_token(self, internal_token: Option<CompactString>) -> Self {
+        Self {
+            internal_token,
+            ..self
+        }
+    }
+
+    pub fn with_index_country(self, index_country: Option<CompactString>) -> Self {
+        Self {
+            index_country,
+            ..self
+        }
+    }
+
+    pub fn with_stale_after(self, stale_after: Duration) -> Self {
+        Self {
+            stale_after,
+            ..self
+        }
+    }
+
     pub async fn get_tx(&self) -> Result<db::Transaction<'_>> {
         self.db.begin().await.map_err(Error::from)
     }
@@ -27,7 +138,86 @@ impl ApiContext {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Debug)]
+/// How often the servers log DB pool utilization
+pub const POOL_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Log the DB pool utilization at the given interval, until the pool is closed
+pub async fn sample_pool_stats(pg: PgPool, every: Duration) {
+    let mut ticker = tokio::time::interval(every);
+    while !pg.is_closed() {
+        ticker.tick().await;
+        let stats = db::pool_stats(&pg);
+        debug!(
+            size = stats.size,
+            idle = stats.idle,
+            max = stats.max,
+            "DB pool utilization"
+        );
+    }
+}
+
+/// How long /readyz waits for the DB, so probes get an answer before they time out themselves
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Body of the health probes
+#[derive(Debug, Clone, Serialize)]
+struct Health {
+    status: &'static str,
+    /// Round trip time for a trivial query, only for /readyz
+    #[serde(skip_serializing_if = "Option::is_none")]
+    db_latency_ms: Option<f64>,
+}
+
+/// Liveness and readiness probes, for both the API and the HTML server.
+/// These should be merged outside of the timeout, trace and fallback cache layers, so that probes
+/// stay cheap, don't flood the logs, and never get a stale answer.
+fn probe_router() -> Router<ApiContext> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+/// Always OK, as long as the process is up and serving requests
+async fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        db_latency_ms: None,
+    })
+}
+
+/// OK if the DB answers a trivial query, otherwise 503 Service Unavailable
+async fn readyz(ctx: State<ApiContext>) -> (StatusCode, Json<Health>) {
+    let start = Instant::now();
+    let res = tokio::time::timeout(READY_TIMEOUT, db::ping(&ctx.db)).await;
+    let (code, status, latency) = match res {
+        Ok(Ok(latency)) => (StatusCode::OK, "ok", latency),
+        Ok(Err(err)) => {
+            warn!(%err, "Readiness check failed");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                start.elapsed(),
+            )
+        }
+        Err(_) => {
+            warn!(timeout = ?READY_TIMEOUT, "Readiness check timed out");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                start.elapsed(),
+            )
+        }
+    };
+    (
+        code,
+        Json(Health {
+            status,
+            db_latency_ms: Some(latency.as_secs_f64() * 1000.0),
+        }),
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ListQueryLevel {
     Empty,
     Country,
@@ -37,8 +227,21 @@ pub enum ListQueryLevel {
     // Dish,
 }
 
+impl ListQueryLevel {
+    /// Name of the level, as told to clients
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::Country => "country",
+            Self::City => "city",
+            Self::Site => "site",
+            Self::Restaurant => "restaurant",
+        }
+    }
+}
+
 #[serde_as]
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ListQuery {
     #[serde_as(as = "NoneAsEmptyString")]
@@ -68,6 +271,161 @@ impl ListQuery {
         }
         ListQueryLevel::Empty
     }
+
+    /// Returns a copy with all values trimmed, and any values below the resolved level removed,
+    /// so that queries giving the same result compare equal, e.g. when used as a cache key.
+    pub fn normalized(&self) -> Self {
+        let trim = |v: &Option<String>| {
+            v.as_deref()
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+        };
+        let q = Self {
+            country: trim(&self.country),
+            city: trim(&self.city),
+            site: trim(&self.site),
+            restaurant: trim(&self.restaurant),
+        };
+        match q.level() {
+            ListQueryLevel::Empty => Self::default(),
+            ListQueryLevel::Country => Self {
+                country: q.country,
+                ..Default::default()
+            },
+            ListQueryLevel::City => Self {
+                country: q.country,
+                city: q.city,
+                ..Default::default()
+            },
+            ListQueryLevel::Site => Self {
+                restaurant: None,
+                ..q
+            },
+            ListQueryLevel::Restaurant => q,
+        }
+    }
+}
+
+/// What level to start the response tree from, leaving out all parents above it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Depth {
+    #[default]
+    Country,
+    City,
+    Site,
+    Restaurant,
+    Dish,
+}
+
+/// Whether a dish needs to have all, or just any, of the requested tags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    All,
+    Any,
+}
+
+/// Query options for the dishes endpoints
+#[serde_as]
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DishesQuery {
+    pub depth: Depth,
+    pub sort: DishSort,
+    /// Leave out dishes without a price, which are most often parse failures
+    #[serde(deserialize_with = "deserialize_flag")]
+    pub hide_free: bool,
+    /// Only include dishes costing at least this much. Dishes without a price are left out.
+    #[serde_as(as = "NoneAsEmptyString")]
+    pub min_price: Option<Price>,
+    /// Only include dishes costing at most this much
+    #[serde_as(as = "NoneAsEmptyString")]
+    pub max_price: Option<Price>,
+    /// Only include dishes with these tags, given as a comma separated list, e.g. "vego,gluten".
+    /// Restaurants left without dishes are left out as well.
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    pub tags: Vec<String>,
+    /// Whether dishes need to have all of the given tags, or just any of them
+    pub tag_match: MatchMode,
+    /// Response format. CSV can also be asked for with "Accept: text/csv".
+    pub format: DishesFormat,
+}
+
+/// Formats the dishes endpoints can respond with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DishesFormat {
+    #[default]
+    Json,
+    /// One row per dish, see export::to_csv
+    Csv,
+}
+
+impl DishesQuery {
+    /// Apply the filters in the query to the given tree
+    pub fn filter(&self, data: &mut crate::models::LunchData) -> Result<()> {
+        if let (Some(min), Some(max)) = (self.min_price, self.max_price) {
+            if min > max {
+                return Err(Error::BadRequest(
+                    "min_price is larger than max_price".into(),
+                ));
+            }
+        }
+        if self.hide_free {
+            data.drop_zero_price();
+        }
+        if self.min_price.is_some() || self.max_price.is_some() {
+            data.retain_price_range(self.min_price, self.max_price);
+        }
+        let tags: Vec<&str> = self
+            .tags
+            .iter()
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if !tags.is_empty() {
+            data.retain_tags(&tags, self.tag_match == MatchMode::Any);
+        }
+        Ok(())
+    }
+}
+
+/// Accept the usual ways of saying yes in a query string, like ?flag=1, and treat all else as no
+fn deserialize_flag<'de, D>(d: D) -> std::result::Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let v = String::deserialize(d)?;
+    Ok(matches!(
+        v.trim().to_ascii_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    ))
+}
+
+/// A response tree, starting at the level selected by Depth
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Subtree {
+    Countries(LunchData),
+    Cities(Vec<City>),
+    Sites(Vec<Site>),
+    Restaurants(Vec<Restaurant>),
+    Dishes(Vec<Dish>),
+}
+
+impl Subtree {
+    pub fn new(data: LunchData, depth: Depth) -> Self {
+        match depth {
+            Depth::Country => Self::Countries(data),
+            Depth::City => Self::Cities(data.into_cities()),
+            Depth::Site => Self::Sites(data.into_sites()),
+            Depth::Restaurant => Self::Restaurants(data.into_restaurants()),
+            Depth::Dish => Self::Dishes(data.into_dishes()),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -75,6 +433,18 @@ pub enum Error {
     /// 404 Not Found
     #[error("request path not found")]
     NotFound,
+    /// 400 Bad Request
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    /// 401 Unauthorized
+    #[error("missing or invalid token")]
+    Unauthorized,
+    /// 409 Conflict
+    #[error("{0}")]
+    Conflict(String),
+    /// 422 Unprocessable Entity
+    #[error("invalid request body: {0}")]
+    Unprocessable(String),
     #[error("an error occurred with the database")]
     Sqlx(#[from] sqlx::Error),
     #[error("an internal server error occurred")]
@@ -85,23 +455,41 @@ impl Error {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
+/// Response extension marking that the request failed because the DB couldn't be reached, as
+/// opposed to a failing query, so that a cached response can be served instead
+#[derive(Debug, Clone, Copy)]
+pub struct DbUnavailable;
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
+        let mut db_unavailable = false;
         match self {
             Self::Sqlx(ref e) => {
                 error!(err = %e, "SQLx error");
+                db_unavailable = matches!(
+                    e,
+                    sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed
+                );
             }
             Self::Anyhow(ref e) => {
                 error!(err = %e, "Internal error");
             }
             _ => (),
         }
-        (self.status_code(), self.to_string()).into_response()
+        let mut res = (self.status_code(), self.to_string()).into_response();
+        if db_unavailable {
+            res.extensions_mut().insert(DbUnavailable);
+        }
+        res
     }
 }
 
@@ -111,3 +499,188 @@ fn check_id(id: Uuid) -> Result<()> {
     }
     Ok(())
 }
+
+/// Check that the request has the given bearer token. Without a token to compare against, the
+/// routes are treated as nonexistent.
+fn check_bearer(want: Option<&str>, headers: &HeaderMap) -> Result<()> {
+    let Some(want) = want else {
+        return Err(Error::NotFound);
+    };
+    let got = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // compare all bytes, so the time taken doesn't tell how much of the token was right
+    let eq = got.len() == want.len()
+        && got
+            .bytes()
+            .zip(want.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+    if !eq {
+        return Err(Error::Unauthorized);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{self, Country, Restaurant, Site};
+    use axum::{extract::Query, http::Uri};
+
+    fn query(q: &str) -> DishesQuery {
+        let uri: Uri = format!("/dishes?{q}").parse().unwrap();
+        Query::<DishesQuery>::try_from_uri(&uri).unwrap().0
+    }
+
+    /// A single restaurant with the given dishes, as (name, price, tags)
+    fn menu(dishes: &[(&str, &str, &[&str])]) -> models::LunchData {
+        let site = Site::new("Lindholmen");
+        let r = Restaurant::new_for_site("Kårhuset", site.site_id);
+        let dishes = dishes
+            .iter()
+            .map(|(name, price, tags)| models::Dish {
+                price: price.parse().unwrap(),
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                ..models::Dish::new(name).for_restaurant(r.restaurant_id)
+            })
+            .collect();
+        models::LunchData::new().with_country(
+            Country {
+                country_id: Uuid::new_v4(),
+                ..Country::new("Sverige")
+            }
+            .with_city(
+                models::City::new("Göteborg")
+                    .with_site(site.with_restaurant(r.with_dishes(dishes))),
+            ),
+        )
+    }
+
+    fn dish_names(data: &models::LunchData) -> Vec<String> {
+        let mut names: Vec<String> = data
+            .countries
+            .values()
+            .flat_map(|co| co.cities.values())
+            .flat_map(|ci| ci.sites.values())
+            .flat_map(|si| si.restaurants.values())
+            .flat_map(|r| r.dishes.values().map(|d| d.name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn filtered(data: &models::LunchData, q: &str) -> Result<Vec<String>> {
+        let mut data = data.clone();
+        query(q).filter(&mut data)?;
+        Ok(dish_names(&data))
+    }
+
+    #[test]
+    fn filter_price() {
+        let data = menu(&[
+            ("Soppa", "85", &[]),
+            ("Fisk", "135", &[]),
+            ("Gratis", "0", &[]),
+        ]);
+        assert_eq!(filtered(&data, "").unwrap(), ["Fisk", "Gratis", "Soppa"]);
+        assert_eq!(filtered(&data, "min_price=100").unwrap(), ["Fisk"]);
+        assert_eq!(
+            filtered(&data, "max_price=100").unwrap(),
+            ["Gratis", "Soppa"]
+        );
+        assert_eq!(
+            filtered(&data, "min_price=85&max_price=135").unwrap(),
+            ["Fisk", "Soppa"]
+        );
+        assert_eq!(
+            filtered(&data, "min_price=&max_price=").unwrap(),
+            ["Fisk", "Gratis", "Soppa"]
+        );
+        assert_eq!(filtered(&data, "hide_free=1").unwrap(), ["Fisk", "Soppa"]);
+        assert!(matches!(
+            filtered(&data, "min_price=100&max_price=90"),
+            Err(Error::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn filter_tags() {
+        let data = menu(&[
+            ("Falafel", "95", &["vego", "gluten"]),
+            ("Sallad", "85", &["vego"]),
+            ("Biff", "189", &[]),
+        ]);
+        assert_eq!(filtered(&data, "tags=vego").unwrap(), ["Falafel", "Sallad"]);
+        assert_eq!(filtered(&data, "tags=vego,gluten").unwrap(), ["Falafel"]);
+        assert_eq!(
+            filtered(&data, "tags=vego,gluten&tag_match=all").unwrap(),
+            ["Falafel"]
+        );
+        assert_eq!(
+            filtered(&data, "tags=gluten,%20vego&tag_match=any").unwrap(),
+            ["Falafel", "Sallad"]
+        );
+        // empty tags are ignored, rather than matching nothing
+        assert_eq!(
+            filtered(&data, "tags=,").unwrap(),
+            ["Biff", "Falafel", "Sallad"]
+        );
+        assert!(filtered(&data, "tags=fisk").unwrap().is_empty());
+    }
+    #[test]
+    fn filter_tags_unicode_and_commas() {
+        let data = menu(&[
+            ("Omelett", "75", &["ägg", "vego"]),
+            ("Pannkaka", "65", &["ägg,mjölk"]),
+        ]);
+        assert_eq!(filtered(&data, "tags=ägg").unwrap(), ["Omelett"]);
+        assert_eq!(filtered(&data, "tags=%C3%A4gg").unwrap(), ["Omelett"]);
+        // the list is split after decoding, so a tag with a comma in it can't be asked for, even
+        // with the comma encoded
+        assert!(filtered(&data, "tags=ägg%2Cmjölk").unwrap().is_empty());
+        assert_eq!(
+            filtered(&data, "tags=ägg%2Cmjölk&tag_match=any").unwrap(),
+            ["Omelett"]
+        );
+    }
+    async fn probe(pg: PgPool, uri: &str) -> (StatusCode, serde_json::Value) {
+        use axum::body::{to_bytes, Body};
+        use tower::ServiceExt;
+
+        let app = probe_router().with_state(ApiContext::new(pg, CompactString::default()));
+        let res = app
+            .oneshot(
+                axum::extract::Request::get(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[sqlx::test]
+    async fn probes(pg: PgPool) {
+        let (status, body) = probe(pg.clone(), "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({"status": "ok"}));
+
+        let (status, body) = probe(pg.clone(), "/readyz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+        assert!(body["db_latency_ms"].as_f64().unwrap() >= 0.0);
+
+        // without a DB the process is still alive, but not ready
+        pg.close().await;
+        let (status, _) = probe(pg.clone(), "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = probe(pg, "/readyz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "unavailable");
+    }
+}
@@ -0,0 +1,397 @@
+// The admin server is for editing the structure of countries, cities and sites, and for adding
+// restaurants that no scraper knows about, and for disabling scrapers. All routes require the
+// admin token, and take and return the same api structs as the JSON server. Ids in request bodies
+// are ignored, in favor of the ids in the path.
+
+use super::{check_bearer, check_id, ApiContext, Error, Result};
+use crate::{
+    db,
+    models::{
+        self,
+        api::{City, Country, Restaurant, Site},
+        ScraperFlag,
+    },
+    signals::shutdown_signal,
+};
+use anyhow::Context;
+use axum::{
+    extract::{rejection::JsonRejection, Path, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::Response,
+    routing::{post, put},
+    Json, Router,
+};
+use compact_str::CompactString;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tower_http::{catch_panic::CatchPanicLayer, timeout::TimeoutLayer, trace::TraceLayer};
+use tracing::trace;
+use uuid::Uuid;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+type Body<T> = std::result::Result<Json<T>, JsonRejection>;
+
+pub async fn serve(pg: PgPool, addr: &str, admin_token: CompactString) -> anyhow::Result<()> {
+    trace!(addr, "Starting HTTP admin server...");
+    let ctx = ApiContext::new(pg, CompactString::from("")).with_admin_token(Some(admin_token));
+    axum::serve(TcpListener::bind(addr).await?, admin_router(ctx))
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .context("failed to start HTTP admin server")
+}
+
+pub fn admin_router(ctx: ApiContext) -> Router {
+    Router::new()
+        .route("/countries", post(create_country))
+        .route(
+            "/countries/:country_id",
+            put(update_country).delete(delete_country),
+        )
+        .route("/countries/:country_id/cities", post(create_city))
+        .route("/cities/:city_id", put(update_city).delete(delete_city))
+        .route("/cities/:city_id/sites", post(create_site))
+        .route("/sites/:site_id", put(update_site).delete(delete_site))
+        .route("/sites/:site_id/restaurants", post(create_restaurant))
+        .route(
+            "/restaurants/:restaurant_id",
+            put(update_restaurant).delete(delete_restaurant),
+        )
+        .route("/scrapers/:scraper_name/enabled", put(set_scraper_enabled))
+        .route_layer(middleware::from_fn_with_state(
+            ctx.clone(),
+            require_admin_token,
+        ))
+        .layer((
+            TraceLayer::new_for_http().on_failure(()),
+            TimeoutLayer::new(TIMEOUT),
+            CatchPanicLayer::new(),
+        ))
+        .with_state(ctx)
+}
+
+async fn require_admin_token(ctx: State<ApiContext>, req: Request, next: Next) -> Result<Response> {
+    check_bearer(ctx.admin_token.as_deref(), req.headers())?;
+    Ok(next.run(req).await)
+}
+
+/// Unwrap a JSON body, or tell why it couldn't be parsed
+fn body<T>(body: Body<T>) -> Result<T> {
+    body.map(|Json(v)| v)
+        .map_err(|e| Error::Unprocessable(e.body_text()))
+}
+
+/// Names and url_ids are what everything is looked up and shown by, so they can't be empty
+fn check_names(name: &str, url_id: Option<&str>) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(Error::BadRequest("name can't be empty".into()));
+    }
+    if url_id.is_some_and(|v| v.trim().is_empty()) {
+        return Err(Error::BadRequest("url_id can't be empty".into()));
+    }
+    Ok(())
+}
+
+/// Map errors from the db functions to something the client can act on
+fn db_error(err: anyhow::Error) -> Error {
+    if let Some(conflict) = err.downcast_ref::<db::UrlIdConflict>() {
+        return Error::Conflict(conflict.to_string());
+    }
+    match err.downcast::<sqlx::Error>() {
+        Ok(err) => sqlx_error(err),
+        Err(err) => Error::Anyhow(err),
+    }
+}
+
+fn sqlx_error(err: sqlx::Error) -> Error {
+    if let sqlx::Error::RowNotFound = err {
+        return Error::NotFound;
+    }
+    if let Some(e) = err.as_database_error() {
+        if e.is_unique_violation() {
+            return Error::Conflict(format!("conflict: {}", e.message()));
+        }
+        // the parent in the path doesn't exist
+        if e.is_foreign_key_violation() {
+            return Error::NotFound;
+        }
+    }
+    Error::Sqlx(err)
+}
+
+async fn create_country(
+    ctx: State<ApiContext>,
+    body: Body<Country>,
+) -> Result<(StatusCode, Json<Country>)> {
+    let c = self::body(body)?;
+    check_names(&c.name, Some(&c.url_id))?;
+    let mut tx = ctx.get_tx().await?;
+    let country_id = db::insert_country(
+        &mut tx,
+        &models::Country {
+            name: c.name,
+            url_id: c.url_id,
+            currency_suffix: c.currency_suffix,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    let res = db::get_country(&mut *tx, country_id).await?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(res.into())))
+}
+
+async fn update_country(
+    ctx: State<ApiContext>,
+    Path(country_id): Path<Uuid>,
+    body: Body<Country>,
+) -> Result<Json<Country>> {
+    check_id(country_id)?;
+    let c = self::body(body)?;
+    check_names(&c.name, Some(&c.url_id))?;
+    let mut tx = ctx.get_tx().await?;
+    db::update_country(
+        &mut tx,
+        &models::Country {
+            country_id,
+            name: c.name,
+            url_id: c.url_id,
+            currency_suffix: c.currency_suffix,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    let res = db::get_country(&mut *tx, country_id).await?;
+    tx.commit().await?;
+    Ok(Json(res.into()))
+}
+
+/// Delete the country and everything in it
+async fn delete_country(
+    ctx: State<ApiContext>,
+    Path(country_id): Path<Uuid>,
+) -> Result<StatusCode> {
+    check_id(country_id)?;
+    let mut tx = ctx.get_tx().await?;
+    db::delete_country(&mut tx, country_id)
+        .await
+        .map_err(sqlx_error)?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_city(
+    ctx: State<ApiContext>,
+    Path(country_id): Path<Uuid>,
+    body: Body<City>,
+) -> Result<(StatusCode, Json<City>)> {
+    check_id(country_id)?;
+    let c = self::body(body)?;
+    check_names(&c.name, Some(&c.url_id))?;
+    let mut tx = ctx.get_tx().await?;
+    let city_id = db::insert_city(
+        &mut tx,
+        country_id,
+        &models::City {
+            name: c.name,
+            url_id: c.url_id,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    let res = db::get_city(&mut *tx, city_id).await?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(res.into())))
+}
+
+/// Rename the city. It stays in the same country.
+async fn update_city(
+    ctx: State<ApiContext>,
+    Path(city_id): Path<Uuid>,
+    body: Body<City>,
+) -> Result<Json<City>> {
+    check_id(city_id)?;
+    let c = self::body(body)?;
+    check_names(&c.name, Some(&c.url_id))?;
+    let mut tx = ctx.get_tx().await?;
+    db::update_city(
+        &mut tx,
+        &models::City {
+            city_id,
+            name: c.name,
+            url_id: c.url_id,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    let res = db::get_city(&mut *tx, city_id).await?;
+    tx.commit().await?;
+    Ok(Json(res.into()))
+}
+
+/// Delete the city and everything in it
+async fn delete_city(ctx: State<ApiContext>, Path(city_id): Path<Uuid>) -> Result<StatusCode> {
+    check_id(city_id)?;
+    let mut tx = ctx.get_tx().await?;
+    db::delete_city(&mut tx, city_id)
+        .await
+        .map_err(sqlx_error)?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_site(
+    ctx: State<ApiContext>,
+    Path(city_id): Path<Uuid>,
+    body: Body<Site>,
+) -> Result<(StatusCode, Json<Site>)> {
+    check_id(city_id)?;
+    let s = self::body(body)?;
+    check_names(&s.name, Some(&s.url_id))?;
+    let mut tx = ctx.get_tx().await?;
+    let site_id = db::insert_site(
+        &mut tx,
+        city_id,
+        &models::Site {
+            name: s.name,
+            url_id: s.url_id,
+            comment: s.comment,
+            currency_suffix: s.currency_suffix,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    let res = db::get_site(&mut *tx, site_id).await?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(res.into())))
+}
+
+/// Update the details of the site, but not its restaurants. It stays in the same city.
+async fn update_site(
+    ctx: State<ApiContext>,
+    Path(site_id): Path<Uuid>,
+    body: Body<Site>,
+) -> Result<Json<Site>> {
+    check_id(site_id)?;
+    let s = self::body(body)?;
+    check_names(&s.name, Some(&s.url_id))?;
+    let mut tx = ctx.get_tx().await?;
+    db::update_site_details(
+        &mut tx,
+        &models::Site {
+            site_id,
+            name: s.name,
+            url_id: s.url_id,
+            comment: s.comment,
+            currency_suffix: s.currency_suffix,
+            ..Default::default()
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    let res = db::get_site(&mut *tx, site_id).await?;
+    tx.commit().await?;
+    Ok(Json(res.into()))
+}
+
+/// Delete the site and all its restaurants
+async fn delete_site(ctx: State<ApiContext>, Path(site_id): Path<Uuid>) -> Result<StatusCode> {
+    check_id(site_id)?;
+    let mut tx = ctx.get_tx().await?;
+    db::delete_site(&mut tx, site_id)
+        .await
+        .map_err(sqlx_error)?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Fetch a restaurant with its dishes, in the shape it's returned in
+async fn get_restaurant(tx: &mut db::Transaction<'_>, restaurant_id: Uuid) -> Result<Restaurant> {
+    let mut r = db::get_restaurant(&mut **tx, restaurant_id).await?;
+    r.dishes = db::get_dishes_for_restaurant(&mut **tx, restaurant_id)
+        .await?
+        .into();
+    Ok(r.into())
+}
+
+/// Add a restaurant by hand, with its dishes. It's kept as is when the site is scraped, and any
+/// scraped restaurant with the same name is skipped.
+async fn create_restaurant(
+    ctx: State<ApiContext>,
+    Path(site_id): Path<Uuid>,
+    body: Body<Restaurant>,
+) -> Result<(StatusCode, Json<Restaurant>)> {
+    check_id(site_id)?;
+    let mut r = self::body(body)?;
+    check_names(&r.name, None)?;
+    // a nil id gets a new one, which the dishes are then tied to
+    r.restaurant_id = Uuid::nil();
+    let r = models::Restaurant::from(r);
+    let restaurant_id = r.restaurant_id;
+    let mut tx = ctx.get_tx().await?;
+    db::insert_restaurant(&mut tx, site_id, r)
+        .await
+        .map_err(db_error)?;
+    let res = get_restaurant(&mut tx, restaurant_id).await?;
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(res)))
+}
+
+/// Update the restaurant and replace all its dishes. It stays in the same site.
+async fn update_restaurant(
+    ctx: State<ApiContext>,
+    Path(restaurant_id): Path<Uuid>,
+    body: Body<Restaurant>,
+) -> Result<Json<Restaurant>> {
+    check_id(restaurant_id)?;
+    let mut r = self::body(body)?;
+    check_names(&r.name, None)?;
+    r.restaurant_id = restaurant_id;
+    let mut tx = ctx.get_tx().await?;
+    db::update_restaurant(&mut tx, r.into())
+        .await
+        .map_err(db_error)?;
+    let res = get_restaurant(&mut tx, restaurant_id).await?;
+    tx.commit().await?;
+    Ok(Json(res))
+}
+
+/// Delete the restaurant and its dishes
+async fn delete_restaurant(
+    ctx: State<ApiContext>,
+    Path(restaurant_id): Path<Uuid>,
+) -> Result<StatusCode> {
+    check_id(restaurant_id)?;
+    let mut tx = ctx.get_tx().await?;
+    db::delete_restaurant(&mut tx, restaurant_id)
+        .await
+        .map_err(sqlx_error)?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct Enabled {
+    enabled: bool,
+}
+
+/// Enable or disable a scraper by name. Scrapers are only set up at start, so it takes effect
+/// the next time the scrape command is started.
+async fn set_scraper_enabled(
+    ctx: State<ApiContext>,
+    Path(scraper_name): Path<String>,
+    body: Body<Enabled>,
+) -> Result<Json<ScraperFlag>> {
+    let Enabled { enabled } = self::body(body)?;
+    check_names(&scraper_name, None)?;
+    let res = db::set_scraper_enabled(&ctx.db, &scraper_name, enabled).await?;
+    Ok(Json(res))
+}
@@ -1,122 +1,782 @@
-use super::{check_id, ApiContext, ListQuery, ListQueryLevel, Result};
+use super::{
+    check_bearer, check_id, probe_router, sample_pool_stats, ApiContext, DbUnavailable,
+    DishesFormat, DishesQuery, Error, ListQuery, ListQueryLevel, Result, Subtree,
+    POOL_SAMPLE_INTERVAL,
+};
 use crate::{
-    db::{self, SiteKey},
-    models::api::LunchData,
+    db::{
+        self, CityUrlId, CountryUrlId, PoolStats, RestaurantUrlId, SiteKey, SiteRelation, SiteUrlId,
+    },
+    export,
+    metrics::{self, Metrics},
+    models::{
+        self,
+        api::{City, Country, LunchData, Restaurant, Site},
+        ScraperStatus, TagCount,
+    },
+    scrape::ScrapeResult,
     signals::shutdown_signal,
 };
 use anyhow::Context;
 use axum::{
-    extract::{Path, Query, State},
-    response::Redirect,
-    routing::get,
-    Json, Router,
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::{rejection::JsonRejection, MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Json, Router, ServiceExt,
 };
+use chrono::{DateTime, Local};
 use compact_str::CompactString;
-use sqlx::PgPool;
-use std::time::{Duration, Instant};
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, NoneAsEmptyString};
+use sqlx::{postgres::PgListener, PgPool};
+use std::{
+    hash::Hash,
+    time::{Duration, Instant},
+};
 use tokio::net::TcpListener;
-use tower_http::{catch_panic::CatchPanicLayer, timeout::TimeoutLayer, trace::TraceLayer};
-use tracing::trace;
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    cors::{AllowOrigin, CorsLayer},
+    normalize_path::NormalizePath,
+    timeout::TimeoutLayer,
+    trace::TraceLayer,
+};
+use tracing::{error, trace, warn};
 use uuid::Uuid;
 
-pub async fn serve(pg: PgPool, addr: &str) -> anyhow::Result<()> {
+/// Cache for responses from the /list endpoint
+pub type ListCache = Cache<ListQuery, LunchData>;
+
+// Results only change when a scraper updates the DB, and we get notified about that, so the TTL is
+// just a safety net in case we miss a notification.
+const LIST_CACHE_TTL: Duration = Duration::from_secs(300);
+const LIST_CACHE_CAPACITY: u64 = 256;
+
+/// Response header on /list telling which level the query resolved to, see ListQueryLevel.
+/// Empty means all countries, country means the cities in it, and so on down to restaurant, which
+/// is the dishes of a single restaurant.
+pub const LEVEL_HEADER: HeaderName = HeaderName::from_static("x-lunch-level");
+
+/// Timeout for routes returning a single level or less
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default timeout for routes that may return whole sites or more
+pub const DEFAULT_HEAVY_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long browsers may cache the result of a CORS preflight request
+const CORS_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Page size for paginated listings, when not given
+const DEFAULT_PER_PAGE: usize = 50;
+/// Largest page size clients may ask for
+const MAX_PER_PAGE: usize = 500;
+
+// Responses estimated to be bigger than this are logged, so we notice if the data grows to a point
+// where we need to trim or stream responses.
+const LARGE_RESPONSE_BYTES: usize = 1024 * 1024;
+
+fn warn_if_large(data: &LunchData, what: &str) {
+    let size = data.estimated_bytes();
+    if size > LARGE_RESPONSE_BYTES {
+        warn!(size, what, "Large response");
+    }
+}
+
+pub fn build_list_cache() -> ListCache {
+    Cache::builder()
+        .name("ListCache")
+        .max_capacity(LIST_CACHE_CAPACITY)
+        .time_to_live(LIST_CACHE_TTL)
+        .build()
+}
+
+/// Last good response for each read route, by path and known query params, served if the DB goes
+/// away. Unlike the list cache, this is not cleared on updates, since stale data is the point.
+pub type FallbackCache = Cache<String, (HeaderMap, Bytes)>;
+
+const FALLBACK_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Total size of the cached bodies
+const FALLBACK_CACHE_MAX_BYTES: u64 = 256 * 1024 * 1024;
+/// Bigger bodies are passed through without being cached
+const MAX_FALLBACK_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+pub fn build_fallback_cache() -> FallbackCache {
+    Cache::builder()
+        .name("FallbackCache")
+        .weigher(|key: &String, (_, body): &(HeaderMap, Bytes)| {
+            u32::try_from(key.len() + body.len()).unwrap_or(u32::MAX)
+        })
+        .max_capacity(FALLBACK_CACHE_MAX_BYTES)
+        .time_to_live(FALLBACK_CACHE_TTL)
+        .build()
+}
+
+/// The query params each route looks at. Only these go into the fallback cache key, so that
+/// made up params can't be used to fill the cache with copies of the same response.
+fn fallback_params(route: &str) -> &'static [&'static str] {
+    const KEY: &[&str] = &["country", "city", "site", "restaurant"];
+    const DISHES: &[&str] = &[
+        "depth",
+        "sort",
+        "hide_free",
+        "min_price",
+        "max_price",
+        "tags",
+        "tag_match",
+        "format",
+    ];
+    match route {
+        "/countries" => &["page", "per_page"],
+        "/cities" => &["country"],
+        "/sites" => &["page", "per_page", "country", "city"],
+        "/restaurants/near" => &["lat", "lon", "radius"],
+        "/dishes/restaurant/:restaurant_id" | "/dishes/site/:site_id" => DISHES,
+        "/list" | "/resolve" => KEY,
+        "/search" => &["q", "site_id"],
+        "/tags" => &["site_id"],
+        _ => &[],
+    }
+}
+
+/// Fallback cache key for the request, or None if it didn't match a route
+fn fallback_key(req: &Request) -> Option<String> {
+    let route = req.extensions().get::<MatchedPath>()?.as_str();
+    let known = fallback_params(route);
+    let mut params: Vec<(String, String)> =
+        url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .filter(|(k, _)| known.contains(&k.as_ref()))
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+    params.sort();
+    let mut key = req.uri().path().to_string();
+    if !params.is_empty() {
+        key.push('?');
+        key.push_str(
+            &url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(params)
+                .finish(),
+        );
+    }
+    // the dishes endpoints respond with CSV instead of JSON if asked to in the Accept header
+    if accepts_csv(req.headers()) {
+        key.push_str(" csv");
+    }
+    Some(key)
+}
+
+/// Remember successful responses, and serve the last one for the same request with a Warning
+/// header if the DB can't be reached, instead of failing.
+async fn serve_stale_on_db_error(
+    State(cache): State<FallbackCache>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.method() != Method::GET {
+        return next.run(req).await;
+    }
+    let Some(key) = fallback_key(&req) else {
+        return next.run(req).await;
+    };
+    let res = next.run(req).await;
+
+    if res.extensions().get::<DbUnavailable>().is_some() {
+        if let Some((headers, body)) = cache.get(&key).await {
+            warn!(key, "DB unavailable, serving stale response");
+            let mut res = (StatusCode::OK, headers, body).into_response();
+            res.headers_mut().insert(
+                header::WARNING,
+                HeaderValue::from_static("110 - \"Response is Stale\""),
+            );
+            return res;
+        }
+        return res;
+    }
+    if !res.status().is_success() {
+        return res;
+    }
+    // only bodies of a known and reasonable size are buffered, anything else goes through as is
+    match res.body().size_hint().exact() {
+        Some(n) if n <= MAX_FALLBACK_BODY_BYTES as u64 => (),
+        _ => return res,
+    }
+
+    let (parts, body) = res.into_parts();
+    let body = match to_bytes(body, MAX_FALLBACK_BODY_BYTES).await {
+        Ok(b) => b,
+        Err(err) => {
+            error!(%err, "Failed to buffer response body");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let mut headers = HeaderMap::new();
+    for name in [
+        header::CONTENT_TYPE,
+        header::CONTENT_DISPOSITION,
+        header::VARY,
+        LEVEL_HEADER,
+    ] {
+        if let Some(v) = parts.headers.get(&name) {
+            headers.insert(name, v.clone());
+        }
+    }
+    cache.insert(key, (headers, body.clone())).await;
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Clear the given cache each time the DB notifies about updated data.
+/// Runs until the listener connection fails.
+pub(crate) async fn invalidate_on_notify<K, V>(pg: PgPool, cache: Cache<K, V>) -> anyhow::Result<()>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    let mut listener = PgListener::connect_with(&pg).await?;
+    listener.listen(db::UPDATE_CHANNEL).await?;
+    loop {
+        let n = listener.recv().await?;
+        trace!(
+            site_id = n.payload(),
+            cache = cache.name(),
+            "DB updated, invalidating cache"
+        );
+        cache.invalidate_all();
+    }
+}
+
+pub async fn serve(
+    pg: PgPool,
+    addr: &str,
+    internal_token: Option<CompactString>,
+    heavy_timeout: Duration,
+    enable_metrics: bool,
+    cors_origins: &[CompactString],
+    max_dishes_per_restaurant: usize,
+) -> anyhow::Result<()> {
     trace!(addr, "Starting HTTP API server...");
+    let cors_origins = cors_origins
+        .iter()
+        .map(|o| HeaderValue::from_str(o).with_context(|| format!("invalid CORS origin: {o}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let metrics = if enable_metrics {
+        let m = Metrics::install()?;
+        tokio::spawn(m.clone().run_upkeep(metrics::UPKEEP_INTERVAL));
+        Some(m)
+    } else {
+        None
+    };
+    let ctx = ApiContext::new(pg.clone(), CompactString::from(""))
+        .with_internal_token(internal_token)
+        .with_heavy_timeout(heavy_timeout)
+        .with_metrics(metrics)
+        .with_cors_origins(cors_origins)
+        .with_max_dishes_per_restaurant(max_dishes_per_restaurant);
+    let cache = ctx.list_cache.clone();
+    tokio::spawn(sample_pool_stats(pg.clone(), POOL_SAMPLE_INTERVAL));
+    tokio::spawn(async move {
+        if let Err(err) = invalidate_on_notify(pg, cache).await {
+            error!(%err, "Stopped listening for DB updates");
+        }
+    });
     axum::serve(
         TcpListener::bind(addr).await?,
-        api_router(ApiContext {
-            db: pg,
-            gtag: CompactString::from(""),
-        }),
+        ServiceExt::<Request>::into_make_service(app(ctx)),
     )
     .with_graceful_shutdown(shutdown_signal())
     .await
     .context("failed to start HTTP API server")
 }
 
-fn api_router(ctx: ApiContext) -> Router {
-    Router::new()
-        .merge(router())
+/// The API router with trailing slashes trimmed from paths, as served.
+/// Path normalization has to happen before routing, so it wraps the router instead of being added
+/// as a layer on it.
+pub fn app(ctx: ApiContext) -> NormalizePath<Router> {
+    NormalizePath::trim_trailing_slash(api_router(ctx))
+}
+
+pub fn api_router(ctx: ApiContext) -> Router {
+    let mut router = Router::new()
+        .merge(router(ctx.heavy_timeout))
+        .route_layer(middleware::from_fn(track_metrics))
+        // added after the tracking layer, so scrapes of the metrics don't show up in them
+        .route("/metrics", get(render_metrics))
+        .layer(middleware::from_fn_with_state(
+            ctx.fallback_cache.clone(),
+            serve_stale_on_db_error,
+        ))
+        .layer(middleware::from_fn(json_case));
+    // only added when configured, since it answers all preflight requests by itself
+    if let Some(cors) = cors_layer(&ctx.cors_origins) {
+        router = router.layer(cors);
+    }
+    router
         .layer((
             TraceLayer::new_for_http().on_failure(()),
-            TimeoutLayer::new(Duration::from_secs(30)),
             CatchPanicLayer::new(),
         ))
+        .merge(probe_router())
         .with_state(ctx)
 }
 
-fn router() -> Router<ApiContext> {
+/// CORS for the given origins, or None if there are none. An origin of "*" allows any origin.
+fn cors_layer(origins: &[HeaderValue]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().cloned())
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            // POST is for ingesting scrape results, which is also why Authorization is needed
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .expose_headers([LEVEL_HEADER])
+            .max_age(CORS_MAX_AGE),
+    )
+}
+
+/// Record count and latency for each request, by the route it matched
+async fn track_metrics(req: Request, next: Next) -> Response {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_default();
+    let method = req.method().clone();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    metrics::record_request(
+        method.as_str(),
+        &path,
+        res.status().as_u16(),
+        start.elapsed(),
+    );
+    res
+}
+
+/// All metrics in the Prometheus text format. Not found if metrics are disabled.
+async fn render_metrics(ctx: State<ApiContext>) -> Result<impl IntoResponse> {
+    let Some(ref m) = ctx.metrics else {
+        return Err(Error::NotFound);
+    };
+    Ok(([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], m.render()))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Case {
+    #[default]
+    Snake,
+    Camel,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+struct CaseQuery {
+    case: Case,
+}
+
+// Max size of a response body we're willing to buffer for renaming keys. Bigger ones are passed
+// through as is.
+const MAX_CASE_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Rename all keys in JSON responses to camelCase if the request has ?case=camel.
+/// The structs are snake_case, which is also the default for responses, and for unknown cases.
+async fn json_case(req: Request, next: Next) -> Response {
+    // this runs for every route, so a bad value shouldn't fail requests that never asked for it
+    let case = Query::<CaseQuery>::try_from_uri(req.uri())
+        .map(|Query(q)| q.case)
+        .unwrap_or_default();
+    let res = next.run(req).await;
+    if case != Case::Camel
+        || res.headers().get(header::CONTENT_TYPE)
+            != Some(&HeaderValue::from_static("application/json"))
+    {
+        return res;
+    }
+    match res.body().size_hint().exact() {
+        Some(n) if n <= MAX_CASE_BODY_BYTES as u64 => (),
+        _ => return res,
+    }
+    let (mut parts, body) = res.into_parts();
+    let v: serde_json::Value = match to_bytes(body, MAX_CASE_BODY_BYTES)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|b| Ok(serde_json::from_slice(&b)?))
+    {
+        Ok(v) => v,
+        Err(err) => {
+            error!(%err, "Failed to rename JSON keys in response");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    (parts, Json(camel_case_keys(v))).into_response()
+}
+
+fn camel_case_keys(v: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+    match v {
+        Value::Object(m) => Value::Object(
+            m.into_iter()
+                .map(|(k, v)| (snake_to_camel(&k), camel_case_keys(v)))
+                .collect(),
+        ),
+        Value::Array(a) => Value::Array(a.into_iter().map(camel_case_keys).collect()),
+        v => v,
+    }
+}
+
+fn snake_to_camel(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut upper = false;
+    for c in s.chars() {
+        if c == '_' {
+            upper = !out.is_empty();
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn router(heavy_timeout: Duration) -> Router<ApiContext> {
+    // Trailing slashes are trimmed before routing (see app), so routes must be declared without
+    // them, and /countries and /countries/ both end up here.
+    // Routes that may return whole sites or more get a longer timeout than the rest
+    let heavy = Router::new()
+        .route("/dishes/site/:site_id", get(dishes_for_site))
+        .route("/site/:site_id/export.json", get(export_site))
+        .route("/list", get(list))
+        .route("/search", get(search_dishes))
+        .route(
+            "/internal/dishes/site/:site_id",
+            get(internal_dishes_for_site),
+        )
+        .route("/sites/:site_id/scrape", post(ingest_scrape))
+        .route_layer(TimeoutLayer::new(heavy_timeout));
+
     Router::new()
-        .route("/", get(|| async { Redirect::permanent("/countries/") }))
-        .route("/countries/", get(list_countries))
+        .route("/", get(|| async { Redirect::permanent("/countries") }))
+        .route("/countries", get(list_countries))
+        .route("/cities", get(list_all_cities))
         .route("/cities/:country_id", get(list_cities))
+        .route("/sites", get(list_all_sites))
         .route("/sites/:city_id", get(list_sites))
+        .route("/restaurants/near", get(restaurants_near))
         .route("/restaurants/:site_id", get(list_restaurants))
         .route(
             "/dishes/restaurant/:restaurant_id",
             get(list_dishes_for_restaurant),
         )
-        .route("/dishes/site/:site_id", get(list_dishes_for_site))
-        .route("/list/", get(list))
+        .route("/resolve", get(resolve))
+        .route("/tags", get(list_tags))
+        .route("/stats/scrapers", get(list_scraper_statuses))
+        .route("/stats/pool", get(get_pool_stats))
+        .route_layer(TimeoutLayer::new(DEFAULT_TIMEOUT))
+        .merge(heavy)
 }
 
-async fn list(ctx: State<ApiContext>, Query(q): Query<ListQuery>) -> Result<Json<LunchData>> {
+#[serde_as]
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+struct TagQuery {
+    #[serde_as(as = "NoneAsEmptyString")]
+    site_id: Option<Uuid>,
+}
+
+/// List all tags in use, with the number of dishes for each, optionally limited to a single site
+async fn list_tags(
+    ctx: State<ApiContext>,
+    Query(q): Query<TagQuery>,
+) -> Result<Json<Vec<TagCount>>> {
+    let start = Instant::now();
+    let res = ctx.store.list_tags(q.site_id).await?;
+    metrics::record_db_query("tags", start);
+    trace!("Fetched tag list in {:?}", start.elapsed());
+    Ok(Json(res))
+}
+
+#[serde_as]
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SearchQuery {
+    q: String,
+    #[serde_as(as = "NoneAsEmptyString")]
+    site_id: Option<Uuid>,
+}
+
+/// Search the current dishes by name, description and comment, ignoring case and accents,
+/// optionally limited to a single site. Only matching dishes and their parents are returned.
+async fn search_dishes(
+    ctx: State<ApiContext>,
+    Query(q): Query<SearchQuery>,
+) -> Result<Json<LunchData>> {
+    let query = q.q.trim();
+    if query.is_empty() {
+        return Err(Error::BadRequest("q can't be empty".into()));
+    }
+    let start = Instant::now();
+    let res = ctx.store.search_dishes(query, q.site_id).await?;
+    metrics::record_db_query("search", start);
+    trace!(query, "Searched dishes in {:?}", start.elapsed());
+    Ok(Json(res.into()))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct NearQuery {
+    lat: f64,
+    lon: f64,
+    /// Radius in km
+    #[serde(default = "NearQuery::default_radius")]
+    radius: f64,
+}
+
+impl NearQuery {
+    fn default_radius() -> f64 {
+        1.0
+    }
+
+    /// The radius capped to MAX_NEAR_RADIUS_KM, or an error if anything is out of range
+    fn checked_radius(&self) -> Result<f64> {
+        if !(-90.0..=90.0).contains(&self.lat) || !(-180.0..=180.0).contains(&self.lon) {
+            return Err(Error::BadRequest(format!(
+                "coordinates out of range: {},{}",
+                self.lat, self.lon
+            )));
+        }
+        if self.radius.is_nan() || self.radius < 0.0 {
+            return Err(Error::BadRequest(format!(
+                "invalid radius: {}",
+                self.radius
+            )));
+        }
+        Ok(self.radius.min(MAX_NEAR_RADIUS_KM))
+    }
+}
+
+// Anything larger is pretty much the same as listing all restaurants, which there are other routes
+// for
+const MAX_NEAR_RADIUS_KM: f64 = 50.0;
+
+/// List restaurants within the given radius of a coordinate, nearest first.
+/// Only restaurants with known coordinates are included. The radius is capped to 50 km.
+async fn restaurants_near(
+    ctx: State<ApiContext>,
+    Query(q): Query<NearQuery>,
+) -> Result<Json<Vec<Restaurant>>> {
+    let radius = q.checked_radius()?;
+    let start = Instant::now();
+    let res = ctx.store.restaurants_near(q.lat, q.lon, radius).await?;
+    metrics::record_db_query("restaurants_near", start);
+    trace!("Fetched restaurants near in {:?}", start.elapsed());
+    Ok(Json(res.into_iter().map(Restaurant::from).collect()))
+}
+
+/// Outcome of the last runs of each scraper, for monitoring
+async fn list_scraper_statuses(ctx: State<ApiContext>) -> Result<Json<Vec<ScraperStatus>>> {
+    let start = Instant::now();
+    let res = ctx.store.list_scraper_statuses().await?;
+    metrics::record_db_query("scraper_statuses", start);
+    trace!("Fetched scraper statuses in {:?}", start.elapsed());
+    Ok(Json(res))
+}
+
+/// Current utilization of the DB connection pool
+async fn get_pool_stats(ctx: State<ApiContext>) -> Json<PoolStats> {
+    Json(db::pool_stats(&ctx.db))
+}
+
+/// Look up the uuids for the given url_ids, for use with the uuid based routes
+async fn resolve(ctx: State<ApiContext>, Query(q): Query<ListQuery>) -> Result<Json<SiteRelation>> {
+    let start = Instant::now();
+    let res = ctx
+        .store
+        .get_site_relation(
+            SiteKey::new(
+                CountryUrlId(q.country.as_deref().unwrap_or_default()),
+                CityUrlId(q.city.as_deref().unwrap_or_default()),
+                SiteUrlId(q.site.as_deref().unwrap_or_default()),
+            )
+            .with_restaurant(RestaurantUrlId(q.restaurant.as_deref().unwrap_or_default())),
+        )
+        .await;
+    metrics::record_db_query("resolve", start);
+    trace!("Resolved site relation in {:?}", start.elapsed());
+    match res {
+        Err(Error::Sqlx(sqlx::Error::RowNotFound)) => Err(Error::NotFound),
+        res => res.map(Json),
+    }
+}
+
+async fn list(
+    ctx: State<ApiContext>,
+    Query(q): Query<ListQuery>,
+) -> Result<([(HeaderName, &'static str); 1], Json<LunchData>)> {
+    let q = q.normalized();
+    let level = [(LEVEL_HEADER, q.level().as_str())];
+    if let Some(res) = ctx.list_cache.get(&q).await {
+        trace!(?q, "Serving list from cache");
+        return Ok((level, Json(res)));
+    }
+    let res = fetch_list(&ctx, q.clone()).await?;
+    warn_if_large(&res, "list");
+    ctx.list_cache.insert(q, res.clone()).await;
+    Ok((level, Json(res)))
+}
+
+async fn fetch_list(ctx: &ApiContext, q: ListQuery) -> Result<LunchData> {
     match q.level() {
-        // Until we have support for a restaurant level for SiteKey, we do the same for
-        // both restaurant and site level here
-        lvl @ ListQueryLevel::Site | lvl @ ListQueryLevel::Restaurant => {
+        lvl @ ListQueryLevel::Restaurant => {
             trace!("Level: {:?}", lvl);
             let start = Instant::now();
-            let res = db::list_dishes_for_site_by_key(
-                &mut ctx.get_tx().await?,
-                SiteKey::new(
-                    &q.country.unwrap_or_default(),
-                    &q.city.unwrap_or_default(),
-                    &q.site.unwrap_or_default(),
-                ),
-            )
-            .await?;
+            let res = ctx
+                .store
+                .list_dishes_for_restaurant_by_key(
+                    SiteKey::new(
+                        CountryUrlId(&q.country.unwrap_or_default()),
+                        CityUrlId(&q.city.unwrap_or_default()),
+                        SiteUrlId(&q.site.unwrap_or_default()),
+                    )
+                    .with_restaurant(RestaurantUrlId(&q.restaurant.unwrap_or_default())),
+                )
+                .await?;
+            metrics::record_db_query("list_dishes", start);
+            trace!("Fetched dish list in {:?}", start.elapsed());
+            Ok(res.into())
+        }
+        lvl @ ListQueryLevel::Site => {
+            trace!("Level: {:?}", lvl);
+            let start = Instant::now();
+            let res = ctx
+                .store
+                .list_dishes_for_site_by_key(SiteKey::new(
+                    CountryUrlId(&q.country.unwrap_or_default()),
+                    CityUrlId(&q.city.unwrap_or_default()),
+                    SiteUrlId(&q.site.unwrap_or_default()),
+                ))
+                .await?;
+            metrics::record_db_query("list_restaurants", start);
             trace!("Fetched restaurant list in {:?}", start.elapsed());
-            Ok(Json(res.into()))
+            Ok(res.into())
         }
         lvl @ ListQueryLevel::City => {
             trace!("Level: {:?}", lvl);
             let start = Instant::now();
-            let res = db::list_sites_for_city_by_key(
-                &mut ctx.get_tx().await?,
-                SiteKey::new(
-                    &q.country.unwrap_or_default(),
-                    &q.city.unwrap_or_default(),
-                    "",
-                ),
-            )
-            .await?;
+            let res = ctx
+                .store
+                .list_sites_for_city_by_key(SiteKey::new(
+                    CountryUrlId(&q.country.unwrap_or_default()),
+                    CityUrlId(&q.city.unwrap_or_default()),
+                    SiteUrlId(""),
+                ))
+                .await?;
+            metrics::record_db_query("list_sites", start);
             trace!("Fetched site list in {:?}", start.elapsed());
-            Ok(Json(res.into()))
+            Ok(res.into())
         }
         lvl @ ListQueryLevel::Country => {
             trace!("Level: {:?}", lvl);
             let start = Instant::now();
-            let res = db::list_cities_for_country_by_key(
-                &mut ctx.get_tx().await?,
-                SiteKey::new(&q.country.unwrap_or_default(), "", ""),
-            )
-            .await?;
+            let res = ctx
+                .store
+                .list_cities_for_country_by_key(SiteKey::new(
+                    CountryUrlId(&q.country.unwrap_or_default()),
+                    CityUrlId(""),
+                    SiteUrlId(""),
+                ))
+                .await?;
+            metrics::record_db_query("list_cities", start);
             trace!("Fetched city list in {:?}", start.elapsed());
-            Ok(Json(res.into()))
+            Ok(res.into())
         }
         lvl @ ListQueryLevel::Empty => {
             trace!("Level: {:?}", lvl);
-            list_countries(ctx).await
+            let start = Instant::now();
+            let res = ctx.store.list_countries(None, 0).await?;
+            metrics::record_db_query("list_countries", start);
+            trace!("Fetched country list in {:?}", start.elapsed());
+            Ok(res.into())
+        }
+    }
+}
+
+/// ?page=&per_page= for paginated listings. Pages start at 1, and both are clamped to sane values
+/// rather than rejected.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+struct PageQuery {
+    page: usize,
+    per_page: usize,
+}
+
+impl Default for PageQuery {
+    fn default() -> Self {
+        Self {
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+impl PageQuery {
+    fn page(&self) -> usize {
+        self.page.max(1)
+    }
+
+    fn limit(&self) -> usize {
+        self.per_page.clamp(1, MAX_PER_PAGE)
+    }
+
+    fn offset(&self) -> usize {
+        (self.page() - 1).saturating_mul(self.limit())
+    }
+
+    /// Wrap up the items of this page, with the number of the next page, if there are more
+    fn wrap<T>(&self, items: Vec<T>, total: i64) -> Page<T> {
+        let seen = self.offset().saturating_add(items.len());
+        Page {
+            items,
+            total,
+            next: (seen < total as usize).then(|| self.page() + 1),
         }
     }
 }
 
-async fn list_countries(ctx: State<ApiContext>) -> Result<Json<LunchData>> {
+/// A single page of a listing
+#[derive(Debug, Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    /// Number of items in all pages
+    total: i64,
+    /// Next page number, if any
+    next: Option<usize>,
+}
+
+/// A page of countries by name, without cities
+async fn list_countries(
+    ctx: State<ApiContext>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<Page<Country>>> {
     let start = Instant::now();
-    let res = db::list_countries(&ctx.db).await?;
+    let res = ctx
+        .store
+        .list_countries(Some(page.limit()), page.offset())
+        .await?;
+    let total = ctx.store.count_countries().await?;
     let duration = start.elapsed();
+    metrics::record_db_query("countries", start);
     trace!("Fetched country list in {:?}", duration);
-    Ok(Json(res.into()))
+    let res: LunchData = res.into();
+    Ok(Json(page.wrap(res.countries, total)))
 }
 
 async fn list_cities(
@@ -125,19 +785,80 @@ async fn list_cities(
 ) -> Result<Json<LunchData>> {
     check_id(country_id)?;
     let start = Instant::now();
-    let res = db::list_cities_for_country_by_id(&mut ctx.get_tx().await?, country_id).await?;
+    let res = ctx.store.list_cities_for_country_by_id(country_id).await?;
     let duration = start.elapsed();
+    metrics::record_db_query("cities", start);
     trace!("Fetched city list in {:?}", duration);
     Ok(Json(res.into()))
 }
 
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+struct CitiesQuery {
+    /// url_id of the country to list cities for, or empty for all countries
+    country: String,
+}
+
+/// All cities in a flat list by name, without sites, optionally limited to a single country
+async fn list_all_cities(
+    ctx: State<ApiContext>,
+    Query(q): Query<CitiesQuery>,
+) -> Result<Json<Vec<City>>> {
+    let start = Instant::now();
+    let res = ctx.store.list_all_cities(CountryUrlId(&q.country)).await?;
+    metrics::record_db_query("all_cities", start);
+    trace!("Fetched all cities in {:?}", start.elapsed());
+    Ok(Json(res.into_iter().map(City::from).collect()))
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SitesQuery {
+    /// url_id of the country to list sites for, or empty for all countries
+    country: String,
+    /// url_id of the city to list sites for, or empty for all cities
+    city: String,
+}
+
+/// A page of sites by name in a flat list, without restaurants, optionally limited to a single
+/// country and/or city
+async fn list_all_sites(
+    ctx: State<ApiContext>,
+    Query(page): Query<PageQuery>,
+    Query(q): Query<SitesQuery>,
+) -> Result<Json<Page<Site>>> {
+    let key = || SiteKey::new(CountryUrlId(&q.country), CityUrlId(&q.city), SiteUrlId(""));
+    let start = Instant::now();
+    let res = ctx
+        .store
+        .list_all_sites(key(), Some(page.limit()), page.offset())
+        .await?;
+    let total = ctx.store.count_sites(key()).await?;
+    metrics::record_db_query("all_sites", start);
+    trace!("Fetched all sites in {:?}", start.elapsed());
+    let res: LunchData = res.into();
+    let mut sites: Vec<Site> = res
+        .countries
+        .into_iter()
+        .flat_map(|co| co.cities)
+        .flat_map(|ci| ci.sites)
+        .collect();
+    // same order as in the DB, so pages don't overlap
+    sites.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.site_id.cmp(&b.site_id)));
+    Ok(Json(page.wrap(sites, total)))
+}
+
 async fn list_sites(ctx: State<ApiContext>, Path(city_id): Path<Uuid>) -> Result<Json<LunchData>> {
     check_id(city_id)?;
     let start = Instant::now();
-    let res = db::list_sites_for_city_by_id(&mut ctx.get_tx().await?, city_id).await?;
+    let res = ctx.store.list_sites_for_city_by_id(city_id).await;
     let duration = start.elapsed();
+    metrics::record_db_query("sites", start);
     trace!("Fetched site list in {:?}", duration);
-    Ok(Json(res.into()))
+    match res {
+        Err(Error::Sqlx(sqlx::Error::RowNotFound)) => Err(Error::NotFound),
+        res => res.map(|r| Json(r.into())),
+    }
 }
 
 async fn list_restaurants(
@@ -146,32 +867,919 @@ async fn list_restaurants(
 ) -> Result<Json<LunchData>> {
     check_id(site_id)?;
     let start = Instant::now();
-    let res = db::list_restaurants_for_site_by_id(&mut ctx.get_tx().await?, site_id).await?;
+    let res = ctx.store.list_restaurants_for_site_by_id(site_id).await?;
     let duration = start.elapsed();
+    metrics::record_db_query("restaurants", start);
     trace!("Fetched restaurant list in {:?}", duration);
     Ok(Json(res.into()))
 }
 
 async fn list_dishes_for_restaurant(
     ctx: State<ApiContext>,
+    headers: HeaderMap,
     Path(restaurant_id): Path<Uuid>,
-) -> Result<Json<LunchData>> {
+    Query(q): Query<DishesQuery>,
+) -> Result<Dishes> {
     check_id(restaurant_id)?;
+    let csv = wants_csv(&q, &headers);
     let start = Instant::now();
-    let res = db::list_dishes_for_restaurant_by_id(&mut ctx.get_tx().await?, restaurant_id).await?;
+    let mut res = ctx
+        .store
+        .list_dishes_for_restaurant_by_id(restaurant_id)
+        .await?;
+    q.filter(&mut res)?;
     let duration = start.elapsed();
+    metrics::record_db_query("dishes_for_restaurant", start);
     trace!("Fetched dishes for restaurant list in {:?}", duration);
-    Ok(Json(res.into()))
+    let mut res: LunchData = res.into();
+    res.sort_dishes(q.sort);
+    if csv {
+        let r = res
+            .into_restaurants()
+            .into_iter()
+            .find(|r| r.restaurant_id == restaurant_id)
+            .ok_or(Error::NotFound)?;
+        let name = match r.url_id.as_str() {
+            "" => restaurant_id.to_string(),
+            v => v.to_string(),
+        };
+        return Ok(Dishes::Csv(name, vec![r]));
+    }
+    warn_if_large(&res, "dishes for restaurant");
+    Ok(Dishes::Json(Subtree::new(res, q.depth)))
+}
+
+/// Dishes for a site, also as CSV if the id ends with ".csv". The router can't match a suffix
+/// after a parameter, so both go through here.
+async fn dishes_for_site(
+    ctx: State<ApiContext>,
+    headers: HeaderMap,
+    Path(site_id): Path<String>,
+    Query(q): Query<DishesQuery>,
+) -> Result<Dishes> {
+    let (id, csv) = match site_id.strip_suffix(".csv") {
+        Some(id) => (id, true),
+        None => (site_id.as_str(), wants_csv(&q, &headers)),
+    };
+    let site_id =
+        Uuid::parse_str(id).map_err(|_| Error::BadRequest(format!("invalid site id: {id}")))?;
+    list_dishes_for_site(ctx, site_id, q, csv).await
 }
 
 async fn list_dishes_for_site(
     ctx: State<ApiContext>,
+    site_id: Uuid,
+    q: DishesQuery,
+    csv: bool,
+) -> Result<Dishes> {
+    check_id(site_id)?;
+    let start = Instant::now();
+    let mut res = ctx.store.list_dishes_for_site_by_id(site_id).await?;
+    q.filter(&mut res)?;
+    let duration = start.elapsed();
+    metrics::record_db_query("dishes_for_site", start);
+    trace!(counts = ?res.counts(), "Fetched dishes for site list in {:?}", duration);
+    let mut res: LunchData = res.into();
+    res.sort_dishes(q.sort);
+    if csv {
+        let site = res
+            .into_sites()
+            .into_iter()
+            .find(|s| s.site_id == site_id)
+            .ok_or(Error::NotFound)?;
+        return Ok(Dishes::Csv(site.url_id, site.restaurants));
+    }
+    warn_if_large(&res, "dishes for site");
+    Ok(Dishes::Json(Subtree::new(res, q.depth)))
+}
+
+/// Whether the client asks for CSV in the Accept header. Only the media types are looked at, not
+/// any quality values.
+fn accepts_csv(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| v.split(';').next())
+        .any(|v| v.trim().eq_ignore_ascii_case("text/csv"))
+}
+
+fn wants_csv(q: &DishesQuery, headers: &HeaderMap) -> bool {
+    q.format == DishesFormat::Csv || accepts_csv(headers)
+}
+
+/// Response from the dishes endpoints, as JSON, or as CSV if asked for, see wants_csv
+enum Dishes {
+    Json(Subtree),
+    /// The name of the download, without extension, and the restaurants with their dishes
+    Csv(String, Vec<Restaurant>),
+}
+
+impl IntoResponse for Dishes {
+    fn into_response(self) -> Response {
+        // the same URL gives CSV or JSON depending on the Accept header
+        let vary = [(header::VARY, "accept")];
+        match self {
+            Self::Json(v) => (vary, Json(v)).into_response(),
+            Self::Csv(name, restaurants) => match export::to_csv(&restaurants) {
+                Ok(body) => (
+                    vary,
+                    [
+                        (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{name}.csv\""),
+                        ),
+                    ],
+                    body,
+                )
+                    .into_response(),
+                Err(err) => Error::Anyhow(err).into_response(),
+            },
+        }
+    }
+}
+
+/// Check the bearer token against the one configured for the internal routes.
+/// The internal routes are not found at all if there's no token configured.
+fn check_internal_token(ctx: &ApiContext, headers: &HeaderMap) -> Result<()> {
+    check_bearer(ctx.internal_token.as_deref(), headers)
+}
+
+/// Same data as /dishes/site/, for internal tools that need to correlate everything by id.
+/// The api structs carry all ids, so this is the full tree without any trimming by query options.
+async fn internal_dishes_for_site(
+    ctx: State<ApiContext>,
+    headers: HeaderMap,
     Path(site_id): Path<Uuid>,
 ) -> Result<Json<LunchData>> {
+    check_internal_token(&ctx, &headers)?;
     check_id(site_id)?;
     let start = Instant::now();
-    let res = db::list_dishes_for_site_by_id(&mut ctx.get_tx().await?, site_id).await?;
-    let duration = start.elapsed();
-    trace!("Fetched dishes for site list in {:?}", duration);
+    let res = ctx.store.list_dishes_for_site_by_id(site_id).await?;
+    metrics::record_db_query("internal_dishes_for_site", start);
+    trace!("Fetched internal dishes for site in {:?}", start.elapsed());
     Ok(Json(res.into()))
 }
+
+/// Body for posting scrape results from a scraper running outside of this process
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ScrapeBody {
+    restaurants: Vec<Restaurant>,
+    /// Whether the restaurants are all there is for the site, see ScrapeResult
+    authoritative: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeCounts {
+    restaurants: usize,
+    dishes: usize,
+}
+
+/// Write scrape results for the given site, in the same way as the built in scrapers do, so that
+/// scrapers can be run as separate processes, in any language.
+/// Requires the same token as the /internal/ routes.
+async fn ingest_scrape(
+    ctx: State<ApiContext>,
+    headers: HeaderMap,
+    Path(site_id): Path<Uuid>,
+    body: std::result::Result<Json<ScrapeBody>, JsonRejection>,
+) -> Result<Json<ScrapeCounts>> {
+    // check the token before looking at the body, so we don't tell anyone what we accept
+    check_internal_token(&ctx, &headers)?;
+    check_id(site_id)?;
+    let Json(body) = body.map_err(|e| Error::Unprocessable(e.body_text()))?;
+    match db::get_site(&ctx.db, site_id).await {
+        Err(sqlx::Error::RowNotFound) => return Err(Error::NotFound),
+        res => res?,
+    };
+
+    let restaurants: Vec<models::Restaurant> = body
+        .restaurants
+        .into_iter()
+        .map(|r| {
+            let mut r = models::Restaurant::from(r);
+            // the site in the path is the one being updated, whatever the body says
+            r.site_id = site_id;
+            if r.parsed_at == DateTime::<Local>::default() {
+                r.parsed_at = Local::now();
+            }
+            r
+        })
+        .collect();
+    let mut res = ScrapeResult {
+        site_id,
+        restaurants,
+        authoritative: body.authoritative,
+    };
+    let dropped = res.drop_oversized(ctx.max_dishes_per_restaurant);
+    if !dropped.is_empty() {
+        let names: Vec<_> = dropped.iter().map(|(n, _)| n.as_str()).collect();
+        return Err(Error::Unprocessable(format!(
+            "too many dishes, max is {} per restaurant, for: {}",
+            ctx.max_dishes_per_restaurant,
+            names.join(", ")
+        )));
+    }
+    if let Some(to) = db::get_price_rounding(&ctx.db, site_id).await? {
+        res.round_prices(to);
+    }
+    let counts = ScrapeCounts {
+        restaurants: res.num_restaurants(),
+        dishes: res.num_dishes(),
+    };
+
+    let start = Instant::now();
+    db::update_site(&ctx.db, res).await?;
+    metrics::record_db_query("ingest_scrape", start);
+    trace!(%site_id, ?counts, "Ingested scrape result in {:?}", start.elapsed());
+    Ok(Json(counts))
+}
+
+/// Return just the given site with all its restaurants and dishes, without the parent country and
+/// city, e.g. for embedding a single menu elsewhere
+async fn export_site(ctx: State<ApiContext>, Path(site_id): Path<Uuid>) -> Result<Json<Site>> {
+    check_id(site_id)?;
+    let start = Instant::now();
+    let res = ctx.store.list_dishes_for_site_by_id(site_id).await;
+    let duration = start.elapsed();
+    metrics::record_db_query("export_site", start);
+    trace!("Fetched site export in {:?}", duration);
+    let res = match res {
+        Err(Error::Sqlx(sqlx::Error::RowNotFound)) => return Err(Error::NotFound),
+        res => res?,
+    };
+    let site = res.into_site(site_id).map_err(|_| Error::NotFound)?;
+    Ok(Json(site.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{self, Dish},
+        web::store::{FlakyStore, LunchStore, MemStore},
+    };
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn fixture() -> models::LunchData {
+        let country = models::Country {
+            country_id: Uuid::new_v4(),
+            url_id: "se".into(),
+            ..models::Country::new("Sweden")
+        };
+        let city = models::City {
+            url_id: "gbg".into(),
+            ..models::City::new_for_country("Göteborg", country.country_id)
+        };
+        let site = models::Site {
+            url_id: "lh".into(),
+            ..models::Site::new_for_city("Lindholmen", city.city_id)
+        };
+        let restaurant = models::Restaurant {
+            url_id: "bistrot".into(),
+            map_url: Some("https://maps.google.com/?q=57.7065,11.9384".into()),
+            ..models::Restaurant::new_for_site("Bistrot", site.site_id)
+        };
+        let dish = Dish::new("Köttbullar").for_restaurant(restaurant.restaurant_id);
+        models::LunchData::new().with_country(
+            country.with_city(city.with_site(site.with_restaurant(restaurant.with_dish(dish)))),
+        )
+    }
+
+    /// Ids of the first country, city, site and restaurant in the tree
+    fn first_ids(data: &models::LunchData) -> SiteRelation {
+        let co = data.countries.values().next().unwrap();
+        let ci = co.cities.values().next().unwrap();
+        let si = ci.sites.values().next().unwrap();
+        SiteRelation {
+            country_id: co.country_id,
+            city_id: ci.city_id,
+            site_id: si.site_id,
+            restaurant_id: si.restaurants.keys().next().copied().unwrap_or_default(),
+        }
+    }
+
+    fn router(data: models::LunchData) -> Router {
+        router_with(Arc::new(MemStore::new(data)))
+    }
+
+    fn router_with(store: Arc<dyn LunchStore>) -> Router {
+        // never connected to, everything goes through the store
+        let db = PgPool::connect_lazy("postgres://localhost/rlunch").unwrap();
+        api_router(ApiContext {
+            store,
+            ..ApiContext::new(db, CompactString::default())
+        })
+    }
+
+    async fn get(router: Router, uri: &str) -> (StatusCode, HeaderMap, serde_json::Value) {
+        let res = router
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or_default();
+        (parts.status, parts.headers, body)
+    }
+
+    #[tokio::test]
+    async fn list_country() {
+        let (status, headers, body) = get(router(fixture()), "/list?country=se").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[LEVEL_HEADER], "country");
+        let countries = body["countries"].as_array().unwrap();
+        assert_eq!(countries.len(), 1);
+        assert_eq!(countries[0]["url_id"], "se");
+        let cities = countries[0]["cities"].as_array().unwrap();
+        assert_eq!(cities.len(), 1);
+        assert_eq!(cities[0]["name"], "Göteborg");
+        assert_eq!(cities[0]["url_id"], "gbg");
+        // only one level down
+        assert_eq!(cities[0]["sites"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn list_level_header() {
+        let app = router(fixture());
+        for (query, level) in [
+            ("", "empty"),
+            ("country=se", "country"),
+            ("country=se&city=gbg", "city"),
+            ("country=se&city=gbg&site=lh", "site"),
+            (
+                "country=se&city=gbg&site=lh&restaurant=bistrot",
+                "restaurant",
+            ),
+        ] {
+            let (status, headers, body) = get(app.clone(), &format!("/list?{query}")).await;
+            assert_eq!(status, StatusCode::OK, "{query}");
+            assert_eq!(headers[LEVEL_HEADER], level, "{query}");
+            assert_eq!(body["countries"][0]["url_id"], "se", "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn list_all_cities() {
+        let mut data = fixture();
+        data.add(
+            models::Country {
+                country_id: Uuid::new_v4(),
+                url_id: "no".into(),
+                ..models::Country::new("Norway")
+            }
+            .with_city(models::City {
+                url_id: "oslo".into(),
+                ..models::City::new("Oslo")
+            }),
+        );
+        let app = router(data);
+        let url_ids = |body: serde_json::Value| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|c| c["url_id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let (status, _, body) = get(app.clone(), "/cities").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(url_ids(body), ["gbg", "oslo"]);
+        let (_, _, body) = get(app.clone(), "/cities?country=no").await;
+        assert_eq!(url_ids(body), ["oslo"]);
+        let (_, _, body) = get(app.clone(), "/cities?country=se").await;
+        assert_eq!(url_ids(body), ["gbg"]);
+        let (status, _, body) = get(app, "/cities?country=dk").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn list_sites_for_city() {
+        let data = fixture();
+        let city = data
+            .countries
+            .values()
+            .next()
+            .unwrap()
+            .cities
+            .values()
+            .next();
+        let uri = format!("/sites/{}", city.unwrap().city_id);
+        let (status, _, body) = get(router(data), &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        let cities = body["countries"][0]["cities"].as_array().unwrap();
+        assert_eq!(cities.len(), 1);
+        let sites = cities[0]["sites"].as_array().unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0]["url_id"], "lh");
+        assert_eq!(sites[0]["restaurants"], serde_json::json!([]));
+
+        let uri = format!("/sites/{}", Uuid::new_v4());
+        let (status, _, _) = get(router(fixture()), &uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn restaurants_near() {
+        let site_id = Uuid::new_v4();
+        let at = |name: &str, lat, lon| models::Restaurant {
+            lat: Some(lat),
+            lon: Some(lon),
+            ..models::Restaurant::new_for_site(name, site_id)
+        };
+        let site = models::Site {
+            site_id,
+            url_id: "lh".into(),
+            ..models::Site::new("Lindholmen")
+        }
+        .with_restaurants(vec![
+            at("Lindholmen", 57.7065, 11.9384),
+            at("Järntorget", 57.6996, 11.9528),
+            at("Stockholm", 59.3293, 18.0686),
+            models::Restaurant::new_for_site("Nowhere", site_id),
+        ]);
+        let data = models::LunchData::new().with_country(
+            models::Country {
+                country_id: Uuid::new_v4(),
+                url_id: "se".into(),
+                ..models::Country::new("Sweden")
+            }
+            .with_city(models::City::new("Göteborg").with_site(site)),
+        );
+        let names = |body: serde_json::Value| -> Vec<String> {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let (status, _, body) = get(
+            router(data.clone()),
+            "/restaurants/near?lat=57.7065&lon=11.9384",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(names(body), ["Lindholmen"]);
+
+        let (_, _, body) = get(
+            router(data.clone()),
+            "/restaurants/near?lat=57.7065&lon=11.9384&radius=2",
+        )
+        .await;
+        assert_eq!(names(body), ["Lindholmen", "Järntorget"]);
+
+        // capped, so Stockholm is still too far away
+        let (status, _, body) = get(
+            router(data.clone()),
+            "/restaurants/near?lat=57.7065&lon=11.9384&radius=10000",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(names(body), ["Lindholmen", "Järntorget"]);
+
+        for q in [
+            "lat=91&lon=11.9",
+            "lat=57.7&lon=-181",
+            "lat=57.7&lon=11.9&radius=-1",
+            "lat=57.7&lon=11.9&radius=NaN",
+        ] {
+            let (status, _, _) = get(router(data.clone()), &format!("/restaurants/near?{q}")).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{q}");
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_response_when_db_is_down() {
+        let store = Arc::new(FlakyStore::new(fixture()));
+        let app = router_with(store.clone());
+        let (status, headers, body) = get(app.clone(), "/cities?country=se&x=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key(header::WARNING));
+
+        store.set_down(true);
+        // unknown params are not part of the key
+        let (status, headers, stale) = get(app.clone(), "/cities?x=2&country=se").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::WARNING], "110 - \"Response is Stale\"");
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(stale, body);
+        assert_eq!(stale[0]["url_id"], "gbg");
+
+        let (status, _, _) = get(app, "/cities?country=no").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heavy_routes_get_a_longer_timeout() {
+        let store = Arc::new(FlakyStore::new(fixture()));
+        store.set_delay(DEFAULT_TIMEOUT + Duration::from_secs(1));
+        let app = router_with(store);
+        let (status, _, _) = get(app.clone(), "/countries").await;
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+        let (status, _, body) = get(app, "/search?q=kott").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["countries"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_response_is_not_cached() {
+        let cache = build_fallback_cache();
+        let app = Router::new()
+            .route(
+                "/countries",
+                axum::routing::get(|| async { vec![b'a'; MAX_FALLBACK_BODY_BYTES + 1] }),
+            )
+            .layer(middleware::from_fn_with_state(
+                cache.clone(),
+                serve_stale_on_db_error,
+            ));
+        let res = app
+            .oneshot(Request::get("/countries").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), MAX_FALLBACK_BODY_BYTES + 1);
+        cache.run_pending_tasks().await;
+        assert_eq!(cache.entry_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn huge_page() {
+        let uri = format!("/countries?page={}&per_page=500", usize::MAX);
+        let (status, _, body) = get(router(fixture()), &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["items"], serde_json::json!([]));
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["next"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn sites_with_the_same_name_keep_their_order() {
+        let mut data = fixture();
+        let city = data
+            .countries
+            .values_mut()
+            .next()
+            .unwrap()
+            .cities
+            .values_mut()
+            .next()
+            .unwrap();
+        for _ in 0..5 {
+            let site = models::Site::new_for_city("Lindholmen", city.city_id);
+            city.sites.insert(site.site_id, site);
+        }
+        let mut want: Vec<Uuid> = city.sites.keys().copied().collect();
+        want.sort();
+
+        let mut got = Vec::new();
+        for page in 1..=3 {
+            let uri = format!("/sites?page={page}&per_page=2");
+            let (_, _, body) = get(router(data.clone()), &uri).await;
+            for site in body["items"].as_array().unwrap() {
+                got.push(site["site_id"].as_str().unwrap().parse::<Uuid>().unwrap());
+            }
+        }
+        assert_eq!(got, want);
+    }
+
+    #[tokio::test]
+    async fn case_param() {
+        let restaurant = |body: &serde_json::Value| {
+            body["countries"][0]["cities"][0]["sites"][0]["restaurants"][0].clone()
+        };
+        let uri = "/list?country=se&city=gbg&site=lh";
+        let (status, _, body) = get(router(fixture()), uri).await;
+        assert_eq!(status, StatusCode::OK);
+        let r = restaurant(&body);
+        assert!(r.get("map_url").is_some());
+        assert!(r.get("mapUrl").is_none());
+
+        let (status, _, body) = get(router(fixture()), &format!("{uri}&case=camel")).await;
+        assert_eq!(status, StatusCode::OK);
+        let r = restaurant(&body);
+        assert!(r.get("mapUrl").is_some());
+        assert!(r.get("map_url").is_none());
+
+        // unknown cases are ignored
+        let (status, _, body) = get(router(fixture()), &format!("{uri}&case=CAMEL")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(restaurant(&body).get("map_url").is_some());
+    }
+
+    async fn post_scrape(
+        router: Router,
+        site_id: Uuid,
+        token: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::post(format!("/sites/{site_id}/scrape"))
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let res = router
+            .oneshot(req.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[sqlx::test]
+    async fn ingest_scrape(pg: PgPool) {
+        let site_id = db::ensure_site_relation(
+            &pg,
+            SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh")),
+        )
+        .await
+        .unwrap()
+        .site_id;
+        let app = api_router(
+            ApiContext::new(pg.clone(), CompactString::default())
+                .with_internal_token(Some("secret".into()))
+                .with_max_dishes_per_restaurant(2),
+        );
+        let body = |dishes: &[&str]| {
+            serde_json::json!({
+                "restaurants": [{
+                    "name": "Bistrot",
+                    "dishes": dishes.iter().map(|d| serde_json::json!({"name": d})).collect::<Vec<_>>(),
+                }],
+                "authoritative": true,
+            })
+            .to_string()
+        };
+
+        let (status, _) = post_scrape(app.clone(), site_id, None, &body(&["Fisk"])).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post_scrape(app.clone(), site_id, Some("wrong"), &body(&["Fisk"])).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = post_scrape(app.clone(), site_id, Some("secret"), "{").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        // over the limit given to the context
+        let (status, _) = post_scrape(
+            app.clone(),
+            site_id,
+            Some("secret"),
+            &body(&["Fisk", "Köttbullar", "Pasta"]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, counts) =
+            post_scrape(app, site_id, Some("secret"), &body(&["Fisk", "Köttbullar"])).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(counts, serde_json::json!({"restaurants": 1, "dishes": 2}));
+        let data = db::list_dishes_for_site_by_id(&mut pg.begin().await.unwrap(), site_id)
+            .await
+            .unwrap();
+        assert_eq!(data.counts().dishes, 2);
+    }
+
+    #[tokio::test]
+    async fn trailing_slash() {
+        let db = PgPool::connect_lazy("postgres://localhost/rlunch").unwrap();
+        let app = app(ApiContext {
+            store: Arc::new(MemStore::new(fixture())),
+            ..ApiContext::new(db, CompactString::default())
+        });
+        for uri in ["/countries", "/countries/"] {
+            let res = app
+                .clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn export_site() {
+        let data = fixture();
+        let site_id = first_ids(&data).site_id;
+        let (status, _, body) = get(router(data), &format!("/site/{site_id}/export.json")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.get("countries").is_none());
+        assert_eq!(body["site_id"], site_id.to_string());
+        assert_eq!(body["url_id"], "lh");
+        let restaurants = body["restaurants"].as_array().unwrap();
+        assert_eq!(restaurants.len(), 1);
+        assert_eq!(restaurants[0]["dishes"][0]["name"], "Köttbullar");
+
+        let uri = format!("/site/{}/export.json", Uuid::new_v4());
+        let (status, _, _) = get(router(fixture()), &uri).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let data = fixture();
+        let ids = first_ids(&data);
+        let nil = Uuid::nil();
+        for (q, want) in [
+            (
+                "country=se&city=gbg&site=lh&restaurant=bistrot",
+                ids.clone(),
+            ),
+            (
+                "country=se&city=gbg&site=lh",
+                SiteRelation {
+                    restaurant_id: nil,
+                    ..ids.clone()
+                },
+            ),
+            (
+                "country=se&city=gbg",
+                SiteRelation {
+                    country_id: ids.country_id,
+                    city_id: ids.city_id,
+                    ..Default::default()
+                },
+            ),
+            (
+                "country=se",
+                SiteRelation {
+                    country_id: ids.country_id,
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let (status, _, body) = get(router(data.clone()), &format!("/resolve?{q}")).await;
+            assert_eq!(status, StatusCode::OK, "{q}");
+            assert_eq!(body, serde_json::to_value(want).unwrap(), "{q}");
+        }
+
+        for q in [
+            "country=no",
+            "country=se&city=sthlm",
+            "country=se&city=gbg&site=x",
+        ] {
+            let (status, _, _) = get(router(data.clone()), &format!("/resolve?{q}")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{q}");
+        }
+    }
+
+    #[tokio::test]
+    async fn list_cache_hit() {
+        let store = Arc::new(FlakyStore::new(fixture()));
+        let app = router_with(store.clone());
+        let (status, _, first) = get(app.clone(), "/list?country=se&city=gbg").await;
+        assert_eq!(status, StatusCode::OK);
+        let calls = store.calls();
+        assert!(calls > 0);
+
+        // the same query, normalized, is served without going to the store
+        let (status, headers, second) = get(app.clone(), "/list?country=se&city=gbg&site=").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[LEVEL_HEADER], "city");
+        assert_eq!(second, first);
+        assert_eq!(store.calls(), calls);
+
+        get(app, "/list?country=se").await;
+        assert!(store.calls() > calls);
+    }
+
+    #[tokio::test]
+    async fn internal_dishes_for_site() {
+        let data = fixture();
+        let ids = first_ids(&data);
+        let db = PgPool::connect_lazy("postgres://localhost/rlunch").unwrap();
+        let app = api_router(ApiContext {
+            store: Arc::new(MemStore::new(data)),
+            ..ApiContext::new(db, CompactString::default())
+                .with_internal_token(Some("secret".into()))
+        });
+        let uri = format!("/internal/dishes/site/{}", ids.site_id);
+
+        let (status, _, _) = get(app.clone(), &uri).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let res = app
+            .oneshot(
+                Request::get(&uri)
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let restaurant = &body["countries"][0]["cities"][0]["sites"][0]["restaurants"][0];
+        assert_eq!(restaurant["restaurant_id"], ids.restaurant_id.to_string());
+        let dish_id = restaurant["dishes"][0]["dish_id"].as_str().unwrap();
+        assert!(!dish_id.parse::<Uuid>().unwrap().is_nil());
+    }
+    #[tokio::test]
+    async fn list_tags() {
+        let mut data = fixture();
+        let ids = first_ids(&data);
+        let tagged = |name, tags: &[&str]| Dish {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Dish::new(name).for_restaurant(ids.restaurant_id)
+        };
+        data.countries
+            .get_mut(&ids.country_id)
+            .unwrap()
+            .cities
+            .get_mut(&ids.city_id)
+            .unwrap()
+            .sites
+            .get_mut(&ids.site_id)
+            .unwrap()
+            .add_dishes(vec![
+                tagged("Falafel", &["vego", "glutenfri"]),
+                tagged("Pasta", &["vego"]),
+            ]);
+        let app = router(data);
+        let want = serde_json::json!([
+            {"tag": "vego", "count": 2},
+            {"tag": "glutenfri", "count": 1},
+        ]);
+
+        let (status, _, body) = get(app.clone(), "/tags").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, want);
+        let (_, _, body) = get(app.clone(), &format!("/tags?site_id={}", ids.site_id)).await;
+        assert_eq!(body, want);
+        let (_, _, body) = get(app, &format!("/tags?site_id={}", Uuid::new_v4())).await;
+        assert_eq!(body, serde_json::json!([]));
+    }
+    #[tokio::test]
+    async fn dishes_for_site_depth() {
+        let data = fixture();
+        let ids = first_ids(&data);
+        let app = router(data);
+        let uri = format!("/dishes/site/{}", ids.site_id);
+
+        let (_, _, body) = get(app.clone(), &uri).await;
+        assert_eq!(body["countries"][0]["url_id"], "se");
+
+        let (status, _, body) = get(app.clone(), &format!("{uri}?depth=site")).await;
+        assert_eq!(status, StatusCode::OK);
+        // a list of sites, without the country and city above
+        let sites = body.as_array().unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0]["url_id"], "lh");
+        assert_eq!(
+            sites[0]["restaurants"][0]["dishes"][0]["name"],
+            "Köttbullar"
+        );
+
+        let (_, _, body) = get(app.clone(), &format!("{uri}?depth=dish")).await;
+        assert_eq!(body[0]["name"], "Köttbullar");
+
+        let (status, _, _) = get(app, &format!("{uri}?depth=galaxy")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    #[sqlx::test]
+    async fn scraper_statuses(pg: PgPool) {
+        let app = api_router(ApiContext::new(pg.clone(), CompactString::default()));
+        let (status, _, body) = get(app.clone(), "/stats/scrapers").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([]));
+
+        let site_id = db::ensure_site_relation(
+            &pg,
+            SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId("lh")),
+        )
+        .await
+        .unwrap()
+        .site_id;
+        db::record_scraper_success(&pg, "lh", site_id, 12)
+            .await
+            .unwrap();
+        db::record_scraper_failure(&pg, "broken", "boom")
+            .await
+            .unwrap();
+
+        let (_, _, body) = get(app, "/stats/scrapers").await;
+        let statuses = body.as_array().unwrap();
+        assert_eq!(statuses.len(), 2);
+        // ordered by name, and leaving out what hasn't happened
+        let broken = statuses[0].as_object().unwrap();
+        assert_eq!(broken["scraper_name"], "broken");
+        assert_eq!(broken["last_error"], "boom");
+        let mut keys: Vec<_> = broken.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["last_error", "last_failure_at", "scraper_name"]);
+
+        let lh = statuses[1].as_object().unwrap();
+        assert_eq!(lh["scraper_name"], "lh");
+        assert_eq!(lh["site_id"], site_id.to_string());
+        assert_eq!(lh["last_dish_count"], 12);
+        assert!(lh["last_success_at"].is_string());
+        assert!(!lh.contains_key("last_failure_at"));
+    }
+}
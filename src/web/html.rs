@@ -1,30 +1,41 @@
-use super::{ApiContext, Result};
+use super::{
+    api::invalidate_on_notify, probe_router, sample_pool_stats, ApiContext, Result,
+    POOL_SAMPLE_INTERVAL,
+};
 use crate::{
-    db::{self},
-    models::api::{LunchData, Site},
+    db::{CityUrlId, CountryUrlId, SiteKey, SiteUrlId},
+    export,
+    models::{
+        self,
+        api::{LunchData, Site},
+    },
     signals::shutdown_signal,
 };
 use anyhow::Context;
 use axum::{
     extract::{Path, State},
-    response::{Html, Redirect},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Redirect},
     routing::get,
     Router,
 };
 use axum_embed::ServeEmbed;
+use chrono::{DateTime, Local, TimeDelta};
+use chrono_humanize::HumanTime;
 use compact_str::CompactString;
 use minijinja::{context, Environment};
 use minijinja_autoreload::AutoReloader;
+use moka::future::Cache;
 use rust_decimal::prelude::*;
 use rust_embed::RustEmbed;
 use serde::Serialize;
 use shadow_rs::shadow;
 use sqlx::PgPool;
+use std::sync::LazyLock;
 use std::{borrow::Cow, time::Duration};
-use std::{path::PathBuf, sync::LazyLock};
 use tokio::net::TcpListener;
 use tower_http::{catch_panic::CatchPanicLayer, timeout::TimeoutLayer, trace::TraceLayer};
-use tracing::trace;
+use tracing::{error, trace, warn};
 use uuid::Uuid;
 
 shadow!(build);
@@ -62,47 +73,128 @@ fn strip_zeros(v: f32) -> String {
     format!("{:.2}", v)
 }
 
+// filter function for template to display a timestamp relative to now, e.g. "2 hours ago".
+// Timestamps in the future, which might happen if the clocks of the scraper and the DB are a bit
+// off, are shown as "now".
+fn time_ago(v: &str) -> std::result::Result<String, minijinja::Error> {
+    let t = DateTime::parse_from_rfc3339(v).map_err(|e| {
+        minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!("invalid timestamp {v:?}: {e}"),
+        )
+    })?;
+    let delta = (t.with_timezone(&Local) - Local::now()).min(TimeDelta::zero());
+    // the Display impl picks the tense, so the last few seconds are "now" rather than "now ago"
+    Ok(HumanTime::from(delta).to_string())
+}
+
+/// A site is stale if even its most recently scraped restaurant is older than the given threshold.
+/// A site without restaurants has nothing to be stale, so it's not.
+fn is_stale(site: &Site, stale_after: Duration) -> bool {
+    site.restaurants
+        .iter()
+        .map(|r| r.parsed_at)
+        .max()
+        .is_some_and(|t| {
+            (Local::now() - t)
+                .to_std()
+                .is_ok_and(|age| age > stale_after)
+        })
+}
+
+#[cfg(not(feature = "bundled"))]
+fn template_path() -> std::path::PathBuf {
+    std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("templates")
+}
+
+/// The template environment with all filters added, loading templates from the binary when
+/// bundled, and from template_path otherwise
+fn build_env() -> Environment<'static> {
+    let mut env = Environment::new();
+    minijinja_contrib::add_to_environment(&mut env);
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.add_filter("stripz", strip_zeros);
+    env.add_filter("time_ago", time_ago);
+
+    #[cfg(feature = "bundled")]
+    {
+        minijinja_embed::load_templates!(&mut env);
+    }
+
+    #[cfg(not(feature = "bundled"))]
+    {
+        env.set_loader(minijinja::path_loader(template_path()));
+    }
+
+    env
+}
+
 static LOADER: LazyLock<AutoReloader> = LazyLock::new(|| {
     #[allow(unused_variables)]
     AutoReloader::new(move |notifier| {
-        let template_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("templates");
-        let mut env = Environment::new();
-        minijinja_contrib::add_to_environment(&mut env);
-        env.set_trim_blocks(true);
-        env.set_lstrip_blocks(true);
-        env.add_filter("stripz", strip_zeros);
-
-        #[cfg(feature = "bundled")]
-        {
-            minijinja_embed::load_templates!(&mut env);
-        }
-
         #[cfg(not(feature = "bundled"))]
         {
-            env.set_loader(minijinja::path_loader(&template_path));
             notifier.set_fast_reload(true);
-            notifier.watch_path(&template_path, true);
+            notifier.watch_path(template_path(), true);
         }
-
-        Ok(env)
+        Ok(build_env())
     })
 });
 
-pub async fn serve(pg: PgPool, addr: &str, gtag: CompactString) -> anyhow::Result<()> {
+/// Cache for the tree of all sites, without restaurants, which is what the index pages show.
+/// There's only ever a single entry.
+pub type SitesCache = Cache<(), LunchData>;
+
+// Cleared on DB updates, like the API list cache, so the TTL is just a safety net
+const SITES_CACHE_TTL: Duration = Duration::from_secs(60);
+
+pub fn build_sites_cache() -> SitesCache {
+    Cache::builder()
+        .name("SitesCache")
+        .max_capacity(1)
+        .time_to_live(SITES_CACHE_TTL)
+        .build()
+}
+
+/// Default for how old the data for a site can be before we show a warning about it
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub async fn serve(
+    pg: PgPool,
+    addr: &str,
+    gtag: CompactString,
+    stale_after: Duration,
+    country: Option<CompactString>,
+) -> anyhow::Result<()> {
     trace!(addr, "Starting HTTP server...");
-    axum::serve(
-        TcpListener::bind(addr).await?,
-        html_router(ApiContext { db: pg, gtag }),
-    )
-    .with_graceful_shutdown(shutdown_signal())
-    .await
-    .context("failed to start HTTP server")
+    let ctx = ApiContext::new(pg.clone(), gtag)
+        .with_stale_after(stale_after)
+        .with_index_country(country);
+    // warm up the cache, so the first visitor doesn't have to wait for it
+    if let Err(err) = all_sites(&ctx).await {
+        warn!(%err, "Failed to preload site list");
+    }
+    let cache = ctx.sites_cache.clone();
+    tokio::spawn(sample_pool_stats(pg.clone(), POOL_SAMPLE_INTERVAL));
+    tokio::spawn(async move {
+        if let Err(err) = invalidate_on_notify(pg, cache).await {
+            error!(%err, "Stopped listening for DB updates");
+        }
+    });
+    axum::serve(TcpListener::bind(addr).await?, html_router(ctx))
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .context("failed to start HTTP server")
 }
 
 fn router() -> Router<ApiContext> {
     Router::new()
-        .route("/", get(list_sites))
+        .route("/", get(index))
+        .route("/sites", get(list_sites))
         .route("/site/:site_id", get(list_dishes_for_site))
+        .route("/sites/:site_id/calendar.ics", get(site_calendar))
+        .route("/sites/:site_id/feed.xml", get(site_feed))
         // I found out that I had solved this in the Go version by letting the Caddy
         // frontend handle the rewrite. But it doesn't hurt to have this here as well, so I know
         // how to do it in just Rust.
@@ -110,9 +202,10 @@ fn router() -> Router<ApiContext> {
             "/favicon.ico",
             get(|| async { Redirect::permanent("/static/favicon.ico") }),
         )
+        .fallback(not_found)
 }
 
-fn html_router(ctx: ApiContext) -> Router {
+pub fn html_router(ctx: ApiContext) -> Router {
     Router::new()
         .nest_service("/static", ServeEmbed::<Assets>::new())
         .merge(router())
@@ -121,18 +214,72 @@ fn html_router(ctx: ApiContext) -> Router {
             TimeoutLayer::new(Duration::from_secs(30)),
             CatchPanicLayer::new(),
         ))
+        .merge(probe_router())
         .with_state(ctx)
 }
 
 fn render<S: Serialize>(name: &str, ctx: S) -> Result<String> {
-    let env = LOADER.acquire_env().map_err(anyhow::Error::from)?;
-    let tmpl = env.get_template(name).map_err(anyhow::Error::from)?;
-    let content = tmpl.render(ctx).map_err(anyhow::Error::from)?;
+    let env = LOADER
+        .acquire_env()
+        .map_err(|err| template_error(name, err))?;
+    let tmpl = env
+        .get_template(name)
+        .map_err(|err| template_error(name, err))?;
+    let content = tmpl.render(ctx).map_err(|err| template_error(name, err))?;
     Ok(content)
 }
 
+// The response only gets a generic error message, so we log all details we have here, including
+// the template and line the error is from, which might be an included template.
+fn template_error(name: &str, err: minijinja::Error) -> super::Error {
+    error!(
+        template = name,
+        source_template = err.name(),
+        line = err.line(),
+        kind = ?err.kind(),
+        detail = err.detail(),
+        "Failed to render template: {err:#}"
+    );
+    anyhow::Error::from(err).into()
+}
+
+/// Render the named template with the given data as context, without a server or DB, for
+/// working on the templates. Build info is added to the context, like for all pages.
+pub fn render_offline(name: &str, data: serde_json::Value) -> anyhow::Result<String> {
+    let env = build_env();
+    let tmpl = env.get_template(name)?;
+    let ctx = context! { build => BuildInfo::new(), ..minijinja::Value::from_serialize(data) };
+    Ok(tmpl.render(ctx)?)
+}
+
+/// All sites, from the cache if possible
+async fn all_sites(ctx: &ApiContext) -> Result<LunchData> {
+    if let Some(data) = ctx.sites_cache.get(&()).await {
+        trace!("Serving site list from cache");
+        return Ok(data);
+    }
+    let filter = SiteKey::new(
+        CountryUrlId(ctx.index_country.as_deref().unwrap_or_default()),
+        CityUrlId(""),
+        SiteUrlId(""),
+    );
+    let data: LunchData = ctx.store.list_all_sites(filter, None, 0).await?.into();
+    ctx.sites_cache.insert((), data.clone()).await;
+    Ok(data)
+}
+
+/// Landing page, with a picker for the cities of each country, linking into the site list
+async fn index(ctx: State<ApiContext>) -> Result<Html<String>> {
+    let data = all_sites(&ctx).await?;
+
+    Ok(Html(render(
+        "index.html",
+        context!(gtag => &ctx.gtag, data, build => BuildInfo::new()),
+    )?))
+}
+
 async fn list_sites(ctx: State<ApiContext>) -> Result<Html<String>> {
-    let data: LunchData = db::list_all_sites(&mut ctx.get_tx().await?).await?.into();
+    let data = all_sites(&ctx).await?;
 
     Ok(Html(render(
         "sites.html",
@@ -140,26 +287,334 @@ async fn list_sites(ctx: State<ApiContext>) -> Result<Html<String>> {
     )?))
 }
 
+// Browsers get a real page with a way back, instead of the plain text error body
+async fn not_found(ctx: State<ApiContext>) -> Result<(StatusCode, Html<String>)> {
+    Ok((
+        StatusCode::NOT_FOUND,
+        Html(render(
+            "404.html",
+            context!(gtag => &ctx.gtag, build => BuildInfo::new()),
+        )?),
+    ))
+}
+
+// A currency set on the site itself wins over the one for the country it's in. Only the country
+// owning the site is considered, since the tree may span countries with different currencies.
+fn currency_suffix(data: &models::LunchData, site_id: Uuid) -> CompactString {
+    data.countries
+        .values()
+        .find_map(|country| {
+            country
+                .cities
+                .values()
+                .find_map(|ci| ci.sites.get(&site_id))
+                .map(|site| (country, site))
+        })
+        .and_then(|(country, site)| {
+            site.currency_suffix
+                .as_ref()
+                .or(country.currency_suffix.as_ref())
+        })
+        .map(CompactString::from)
+        .unwrap_or_default()
+}
+
 async fn list_dishes_for_site(
     ctx: State<ApiContext>,
     Path(site_id): Path<Uuid>,
 ) -> Result<Html<String>> {
     super::check_id(site_id)?;
-    let data = db::list_dishes_for_site_by_id(&mut ctx.get_tx().await?, site_id).await?;
-    let currency_suffix = || -> CompactString {
-        for country in data.countries.values() {
-            if let Some(ref v) = country.currency_suffix {
-                return CompactString::from(v);
-            }
-        }
-        CompactString::from("")
-    }();
+    let data = ctx.store.list_dishes_for_site_by_id(site_id).await?;
+    let currency_suffix = currency_suffix(&data, site_id);
     // TODO: Consider if we should extract all useful info from the chain of ancestors,
     // to use as a bread crumb back in the template, before we lose all parent info here.
     let site: Site = data.into_site(site_id)?.into();
+    let stale = is_stale(&site, ctx.stale_after);
 
     Ok(Html(render(
         "dishes_for_site.html",
-        context!(gtag => &ctx.gtag, currency_suffix, site, build => BuildInfo::new()),
+        context!(gtag => &ctx.gtag, currency_suffix, site, stale, build => BuildInfo::new()),
     )?))
 }
+
+/// Today's menu of the site as a calendar to subscribe to, with an all-day event per restaurant.
+/// Only today's menu is known, so there's only events for today.
+async fn site_calendar(
+    ctx: State<ApiContext>,
+    Path(site_id): Path<Uuid>,
+) -> Result<impl IntoResponse> {
+    super::check_id(site_id)?;
+    let data = ctx.store.list_dishes_for_site_by_id(site_id).await?;
+    let currency_suffix = currency_suffix(&data, site_id);
+    let site: Site = data.into_site(site_id)?.into();
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        export::to_ics(&site, &currency_suffix, Local::now().date_naive()),
+    ))
+}
+
+/// The current menus of the site as an Atom feed, with an entry per restaurant
+async fn site_feed(ctx: State<ApiContext>, Path(site_id): Path<Uuid>) -> Result<impl IntoResponse> {
+    super::check_id(site_id)?;
+    let data = ctx.store.list_dishes_for_site_by_id(site_id).await?;
+    let currency_suffix = currency_suffix(&data, site_id);
+    let site: Site = data.into_site(site_id)?.into();
+    Ok((
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        export::to_atom(&site, &currency_suffix),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::Dish,
+        web::store::{FlakyStore, LunchStore, MemStore},
+    };
+    use axum::{
+        body::{to_bytes, Body},
+        extract::Request,
+        http::HeaderMap,
+    };
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    fn fixture() -> models::LunchData {
+        let country = models::Country {
+            country_id: Uuid::new_v4(),
+            url_id: "se".into(),
+            ..models::Country::new("Sweden")
+        };
+        let city = models::City {
+            url_id: "gbg".into(),
+            ..models::City::new_for_country("Göteborg", country.country_id)
+        };
+        let site = models::Site {
+            url_id: "lh".into(),
+            ..models::Site::new_for_city("Lindholmen", city.city_id)
+        };
+        let restaurant = models::Restaurant::new_for_site("Bistrot", site.site_id);
+        let dish = Dish::new("Köttbullar").for_restaurant(restaurant.restaurant_id);
+        models::LunchData::new().with_country(
+            country.with_city(city.with_site(site.with_restaurant(restaurant.with_dish(dish)))),
+        )
+    }
+
+    fn first_site_id(data: &models::LunchData) -> Uuid {
+        *data
+            .countries
+            .values()
+            .flat_map(|co| co.cities.values())
+            .flat_map(|ci| ci.sites.keys())
+            .next()
+            .unwrap()
+    }
+
+    fn app(store: Arc<dyn LunchStore>) -> Router {
+        // never connected to, everything goes through the store
+        let db = PgPool::connect_lazy("postgres://localhost/rlunch").unwrap();
+        html_router(ApiContext {
+            store,
+            ..ApiContext::new(db, CompactString::default())
+        })
+    }
+
+    async fn get(app: Router, uri: &str) -> (StatusCode, HeaderMap, String) {
+        let res = app
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn html_charset() {
+        let data = fixture();
+        let site_id = first_site_id(&data);
+        let app = app(Arc::new(MemStore::new(data)));
+        for uri in [
+            "/".to_string(),
+            "/sites".to_string(),
+            format!("/site/{site_id}"),
+            "/nowhere".to_string(),
+        ] {
+            let (_, headers, _) = get(app.clone(), &uri).await;
+            assert_eq!(
+                headers[header::CONTENT_TYPE],
+                "text/html; charset=utf-8",
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn index_groups_cities_by_country() {
+        let mut data = fixture();
+        let gbg_id = *data
+            .countries
+            .values()
+            .next()
+            .unwrap()
+            .cities
+            .keys()
+            .next()
+            .unwrap();
+        let country = models::Country {
+            country_id: Uuid::new_v4(),
+            ..models::Country::new("Norway")
+        };
+        let oslo = models::City::new_for_country("Oslo", country.country_id);
+        let oslo_id = oslo.city_id;
+        data.add(country.with_city(oslo.with_sites(vec![
+            models::Site::new_for_city("Sentrum", oslo_id),
+            models::Site::new_for_city("Nydalen", oslo_id),
+        ])));
+
+        let (status, _, body) = get(app(Arc::new(MemStore::new(data))), "/").await;
+        assert_eq!(status, StatusCode::OK);
+        // countries in name order, each followed by its own cities
+        let pos = |s: &str| {
+            body.find(s)
+                .unwrap_or_else(|| panic!("{s} missing: {body}"))
+        };
+        let oslo = pos(&format!(r#"<a href="/sites#city-{oslo_id}">Oslo</a>"#));
+        let gbg = pos(&format!(r#"<a href="/sites#city-{gbg_id}">Göteborg</a>"#));
+        assert!(pos("<li>Norway</li>") < oslo);
+        assert!(oslo < pos("<li>Sweden</li>"));
+        assert!(pos("<li>Sweden</li>") < gbg);
+        assert!(body[oslo..]
+            .trim_start_matches(|c| c != '(')
+            .starts_with("(2 sites)"));
+        assert!(body[gbg..]
+            .trim_start_matches(|c| c != '(')
+            .starts_with("(1 sites)"));
+    }
+
+    #[test]
+    fn template_error_is_logged_with_location() {
+        let mut env = Environment::new();
+        let err = env
+            .add_template("broken.html", "<p>\n{% if %}\n</p>")
+            .unwrap_err();
+        let logs = crate::util::LogCapture::start();
+        template_error("page.html", err);
+        let out = logs.output();
+        assert!(out.contains(r#"template="page.html""#), "{out}");
+        assert!(out.contains(r#"source_template="broken.html""#), "{out}");
+        assert!(out.contains("line=2"), "{out}");
+    }
+
+    #[tokio::test]
+    async fn site_list_is_cached() {
+        let store = Arc::new(FlakyStore::new(fixture()));
+        let app = app(store.clone());
+        let (status, _, first) = get(app.clone(), "/sites").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(store.calls(), 1);
+
+        // the index uses the same cached list
+        let (_, _, second) = get(app.clone(), "/sites").await;
+        assert_eq!(second, first);
+        let (status, _, _) = get(app, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(store.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn index_country() {
+        let mut data = fixture();
+        data.add(
+            models::Country {
+                country_id: Uuid::new_v4(),
+                url_id: "no".into(),
+                ..models::Country::new("Norway")
+            }
+            .with_city(models::City::new("Oslo")),
+        );
+        let store: Arc<dyn LunchStore> = Arc::new(MemStore::new(data));
+        // a new context each time, since clones share the site cache
+        let ctx = || {
+            let db = PgPool::connect_lazy("postgres://localhost/rlunch").unwrap();
+            ApiContext {
+                store: store.clone(),
+                ..ApiContext::new(db, CompactString::default())
+            }
+        };
+
+        let (_, _, body) = get(html_router(ctx()), "/").await;
+        assert!(body.contains("Norway") && body.contains("Sweden"), "{body}");
+
+        let app = html_router(ctx().with_index_country(Some("se".into())));
+        for uri in ["/", "/sites"] {
+            let (status, _, body) = get(app.clone(), uri).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(body.contains("Göteborg"), "{uri}: {body}");
+            assert!(
+                !body.contains("Norway") && !body.contains("Oslo"),
+                "{uri}: {body}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let (status, headers, body) =
+            get(app(Arc::new(MemStore::default())), "/no/such/page").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(body.contains("<title>Page not found"), "{body}");
+        assert!(body.contains(r#"<a href="/">"#), "{body}");
+    }
+
+    #[test]
+    fn is_stale() {
+        let scraped = |hours_ago: i64| models::api::Restaurant {
+            parsed_at: Local::now() - TimeDelta::hours(hours_ago),
+            ..Default::default()
+        };
+        let site = |restaurants| Site {
+            restaurants,
+            ..Default::default()
+        };
+        let day = Duration::from_secs(24 * 3600);
+        assert!(!super::is_stale(&site(vec![scraped(1)]), day));
+        assert!(super::is_stale(&site(vec![scraped(48)]), day));
+        // the most recent scrape decides
+        assert!(!super::is_stale(&site(vec![scraped(48), scraped(1)]), day));
+        assert!(!super::is_stale(&site(vec![]), day));
+    }
+
+    #[test]
+    fn currency_suffix() {
+        let mut data = fixture();
+        let site_id = first_site_id(&data);
+        assert_eq!(super::currency_suffix(&data, site_id), "");
+
+        let country = data.countries.values_mut().next().unwrap();
+        country.currency_suffix = Some("kr".into());
+        assert_eq!(super::currency_suffix(&data, site_id), "kr");
+
+        let country = data.countries.values_mut().next().unwrap();
+        let city = country.cities.values_mut().next().unwrap();
+        city.sites.get_mut(&site_id).unwrap().currency_suffix = Some("SEK".into());
+        assert_eq!(super::currency_suffix(&data, site_id), "SEK");
+        assert_eq!(super::currency_suffix(&data, Uuid::new_v4()), "");
+    }
+
+    #[test]
+    fn time_ago() {
+        let ago = |d: TimeDelta| super::time_ago(&(Local::now() + d).to_rfc3339()).unwrap();
+        assert_eq!(ago(-TimeDelta::hours(3)), "3 hours ago");
+        assert_eq!(ago(-TimeDelta::days(2)), "2 days ago");
+        // clocks being a bit off shouldn't put the scrape in the future
+        assert_eq!(ago(TimeDelta::hours(1)), "now");
+        assert!(super::time_ago("yesterday").is_err());
+    }
+}
@@ -0,0 +1,737 @@
+// The handlers only need read access to the data, so we abstract that behind a trait, in order to
+// be able to run them against something other than Postgres, e.g. an in-memory tree when testing.
+// The Postgres implementation just wraps the functions in db.rs, each within its own transaction.
+
+use super::Result;
+use crate::{
+    db::{self, CountryUrlId, SiteKey, SiteRelation},
+    models::{City, LunchData, Restaurant, ScraperStatus, TagCount},
+};
+use async_trait::async_trait;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[async_trait]
+pub trait LunchStore: std::fmt::Debug + Send + Sync {
+    async fn get_site_relation(&self, key: SiteKey<'_>) -> Result<SiteRelation>;
+
+    async fn list_countries(&self, limit: Option<usize>, offset: usize) -> Result<LunchData>;
+
+    async fn count_countries(&self) -> Result<i64>;
+
+    async fn list_cities_for_country_by_id(&self, country_id: Uuid) -> Result<LunchData>;
+
+    async fn list_cities_for_country_by_key(&self, key: SiteKey<'_>) -> Result<LunchData>;
+
+    async fn list_all_cities(&self, country: CountryUrlId<'_>) -> Result<Vec<City>>;
+
+    async fn list_sites_for_city_by_id(&self, city_id: Uuid) -> Result<LunchData>;
+
+    async fn list_sites_for_city_by_key(&self, key: SiteKey<'_>) -> Result<LunchData>;
+
+    async fn list_restaurants_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData>;
+
+    async fn list_dishes_for_restaurant_by_id(&self, restaurant_id: Uuid) -> Result<LunchData>;
+
+    async fn list_dishes_for_restaurant_by_key(&self, key: SiteKey<'_>) -> Result<LunchData>;
+
+    async fn list_dishes_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData>;
+
+    async fn list_dishes_for_site_by_key(&self, key: SiteKey<'_>) -> Result<LunchData>;
+
+    async fn list_all_sites(
+        &self,
+        filter: SiteKey<'_>,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<LunchData>;
+
+    async fn count_sites(&self, filter: SiteKey<'_>) -> Result<i64>;
+
+    async fn list_tags(&self, site_id: Option<Uuid>) -> Result<Vec<TagCount>>;
+
+    async fn search_dishes(&self, query: &str, site_id: Option<Uuid>) -> Result<LunchData>;
+
+    async fn restaurants_near(&self, lat: f64, lon: f64, radius_km: f64)
+        -> Result<Vec<Restaurant>>;
+
+    async fn list_scraper_statuses(&self) -> Result<Vec<ScraperStatus>>;
+}
+
+#[async_trait]
+impl LunchStore for PgPool {
+    async fn get_site_relation(&self, key: SiteKey<'_>) -> Result<SiteRelation> {
+        Ok(db::get_site_relation(self, key).await?)
+    }
+
+    async fn list_countries(&self, limit: Option<usize>, offset: usize) -> Result<LunchData> {
+        Ok(db::list_countries(self, limit, offset).await?)
+    }
+
+    async fn count_countries(&self) -> Result<i64> {
+        Ok(db::count_countries(self).await?)
+    }
+
+    async fn list_cities_for_country_by_id(&self, country_id: Uuid) -> Result<LunchData> {
+        Ok(db::list_cities_for_country_by_id(&mut self.begin().await?, country_id).await?)
+    }
+
+    async fn list_cities_for_country_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+        Ok(db::list_cities_for_country_by_key(&mut self.begin().await?, key).await?)
+    }
+
+    async fn list_all_cities(&self, country: CountryUrlId<'_>) -> Result<Vec<City>> {
+        Ok(db::get_all_cities(self, country).await?)
+    }
+
+    async fn list_sites_for_city_by_id(&self, city_id: Uuid) -> Result<LunchData> {
+        Ok(db::list_sites_for_city_by_id(&mut self.begin().await?, city_id).await?)
+    }
+
+    async fn list_sites_for_city_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+        Ok(db::list_sites_for_city_by_key(&mut self.begin().await?, key).await?)
+    }
+
+    async fn list_restaurants_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData> {
+        Ok(db::list_restaurants_for_site_by_id(&mut self.begin().await?, site_id).await?)
+    }
+
+    async fn list_dishes_for_restaurant_by_id(&self, restaurant_id: Uuid) -> Result<LunchData> {
+        Ok(db::list_dishes_for_restaurant_by_id(&mut self.begin().await?, restaurant_id).await?)
+    }
+
+    async fn list_dishes_for_restaurant_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+        Ok(db::list_dishes_for_restaurant_by_key(&mut self.begin().await?, key).await?)
+    }
+
+    async fn list_dishes_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData> {
+        Ok(db::list_dishes_for_site_by_id(&mut self.begin().await?, site_id).await?)
+    }
+
+    async fn list_dishes_for_site_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+        Ok(db::list_dishes_for_site_by_key(&mut self.begin().await?, key).await?)
+    }
+
+    async fn list_all_sites(
+        &self,
+        filter: SiteKey<'_>,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<LunchData> {
+        Ok(db::list_all_sites(&mut self.begin().await?, filter, limit, offset).await?)
+    }
+
+    async fn count_sites(&self, filter: SiteKey<'_>) -> Result<i64> {
+        Ok(db::count_sites(self, filter).await?)
+    }
+
+    async fn list_tags(&self, site_id: Option<Uuid>) -> Result<Vec<TagCount>> {
+        Ok(db::list_tags(self, site_id).await?)
+    }
+
+    async fn search_dishes(&self, query: &str, site_id: Option<Uuid>) -> Result<LunchData> {
+        Ok(db::search_dishes(&mut self.begin().await?, query, site_id).await?)
+    }
+
+    async fn restaurants_near(
+        &self,
+        lat: f64,
+        lon: f64,
+        radius_km: f64,
+    ) -> Result<Vec<Restaurant>> {
+        Ok(db::restaurants_near(self, lat, lon, radius_km).await?)
+    }
+
+    async fn list_scraper_statuses(&self) -> Result<Vec<ScraperStatus>> {
+        Ok(db::get_scraper_statuses(self).await?)
+    }
+}
+
+#[cfg(test)]
+pub use mem::FlakyStore;
+#[cfg(any(test, feature = "test-support"))]
+pub use mem::MemStore;
+
+#[cfg(any(test, feature = "test-support"))]
+mod mem {
+    // An in-memory store, serving trimmed copies of a fixed tree, mimicking what the DB functions
+    // would return for each level.
+    // We can't use bincode snapshots of the models directly, since the id fields are
+    // skip_serializing, so fixtures are built in code with the usual with_* builders instead.
+
+    use super::{LunchStore, Result};
+    use crate::web::Error;
+    use crate::{
+        db::{CountryUrlId, SiteKey, SiteRelation},
+        models::{City, Country, LunchData, Restaurant, ScraperStatus, Site, TagCount},
+        util::{self, haversine_km},
+    };
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    #[cfg(test)]
+    use std::{
+        sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use uuid::Uuid;
+
+    #[derive(Debug, Clone, Default)]
+    pub struct MemStore {
+        data: LunchData,
+    }
+
+    impl MemStore {
+        pub fn new(data: LunchData) -> Self {
+            Self { data }
+        }
+
+        fn not_found() -> Error {
+            sqlx::Error::RowNotFound.into()
+        }
+
+        fn country(&self, country_id: Uuid) -> Result<&Country> {
+            self.data
+                .countries
+                .get(&country_id)
+                .ok_or_else(Self::not_found)
+        }
+
+        fn city(&self, city_id: Uuid) -> Result<(&Country, &City)> {
+            self.data
+                .countries
+                .values()
+                .find_map(|co| co.cities.get(&city_id).map(|ci| (co, ci)))
+                .ok_or_else(Self::not_found)
+        }
+
+        fn site(&self, site_id: Uuid) -> Result<(&Country, &City, &Site)> {
+            self.data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values().map(move |ci| (co, ci)))
+                .find_map(|(co, ci)| ci.sites.get(&site_id).map(|si| (co, ci, si)))
+                .ok_or_else(Self::not_found)
+        }
+
+        fn restaurant(&self, restaurant_id: Uuid) -> Result<(&Country, &City, &Site, &Restaurant)> {
+            self.data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values().map(move |ci| (co, ci)))
+                .flat_map(|(co, ci)| ci.sites.values().map(move |si| (co, ci, si)))
+                .find_map(|(co, ci, si)| {
+                    si.restaurants.get(&restaurant_id).map(|r| (co, ci, si, r))
+                })
+                .ok_or_else(Self::not_found)
+        }
+
+        /// Resolve a key the same way as db::get_site_relation, returning the ids for the levels
+        /// given
+        fn resolve(&self, key: &SiteKey<'_>) -> Result<SiteRelation> {
+            let country = self
+                .data
+                .countries
+                .values()
+                .find(|co| same_url_id(&co.url_id, key.country_url_id.as_str()))
+                .ok_or_else(Self::not_found)?;
+            let mut rel = SiteRelation {
+                country_id: country.country_id,
+                ..Default::default()
+            };
+            if key.city_url_id.is_empty() {
+                return Ok(rel);
+            }
+            let city = country
+                .cities
+                .values()
+                .find(|ci| same_url_id(&ci.url_id, key.city_url_id.as_str()))
+                .ok_or_else(Self::not_found)?;
+            rel.city_id = city.city_id;
+            if key.site_url_id.is_empty() {
+                return Ok(rel);
+            }
+            let site = city
+                .sites
+                .values()
+                .find(|si| same_url_id(&si.url_id, key.site_url_id.as_str()))
+                .ok_or_else(Self::not_found)?;
+            rel.site_id = site.site_id;
+            if key.restaurant_url_id.is_empty() {
+                return Ok(rel);
+            }
+            rel.restaurant_id = site
+                .restaurants
+                .values()
+                .find(|r| same_url_id(&r.url_id, key.restaurant_url_id.as_str()))
+                .ok_or_else(Self::not_found)?
+                .restaurant_id;
+            Ok(rel)
+        }
+    }
+
+    // like url_id_key in the DB
+    fn same_url_id(a: &str, b: &str) -> bool {
+        util::slug(a) == util::slug(b)
+    }
+
+    fn bare_country(co: &Country) -> Country {
+        Country {
+            cities: Default::default(),
+            ..co.clone()
+        }
+    }
+
+    fn bare_city(ci: &City) -> City {
+        City {
+            sites: Default::default(),
+            ..ci.clone()
+        }
+    }
+
+    fn bare_site(si: &Site) -> Site {
+        Site {
+            restaurants: Default::default(),
+            ..si.clone()
+        }
+    }
+
+    fn bare_restaurant(r: &Restaurant) -> Restaurant {
+        Restaurant {
+            dishes: Default::default(),
+            ..r.clone()
+        }
+    }
+
+    #[async_trait]
+    impl LunchStore for MemStore {
+        async fn get_site_relation(&self, key: SiteKey<'_>) -> Result<SiteRelation> {
+            self.resolve(&key)
+        }
+
+        async fn list_countries(&self, limit: Option<usize>, offset: usize) -> Result<LunchData> {
+            let mut countries: Vec<Country> =
+                self.data.countries.values().map(bare_country).collect();
+            countries.sort_by(|a, b| {
+                a.name
+                    .cmp(&b.name)
+                    .then_with(|| a.country_id.cmp(&b.country_id))
+            });
+            Ok(LunchData::new().with_countries(
+                countries
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit.unwrap_or(usize::MAX))
+                    .collect(),
+            ))
+        }
+
+        async fn count_countries(&self) -> Result<i64> {
+            Ok(self.data.countries.len() as i64)
+        }
+
+        async fn list_cities_for_country_by_id(&self, country_id: Uuid) -> Result<LunchData> {
+            let co = self.country(country_id)?;
+            Ok(LunchData::new().with_country(
+                bare_country(co).with_cities(co.cities.values().map(bare_city).collect()),
+            ))
+        }
+
+        async fn list_cities_for_country_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            let country_id = self.resolve(&key)?.country_id;
+            self.list_cities_for_country_by_id(country_id).await
+        }
+
+        async fn list_all_cities(&self, country: CountryUrlId<'_>) -> Result<Vec<City>> {
+            let mut cities: Vec<City> = self
+                .data
+                .countries
+                .values()
+                .filter(|co| country.is_empty() || same_url_id(&co.url_id, country.as_str()))
+                .flat_map(|co| co.cities.values())
+                .map(bare_city)
+                .collect();
+            cities.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(cities)
+        }
+
+        async fn list_sites_for_city_by_id(&self, city_id: Uuid) -> Result<LunchData> {
+            let (co, ci) = self.city(city_id)?;
+            Ok(LunchData::new().with_country(
+                bare_country(co).with_city(
+                    bare_city(ci).with_sites(ci.sites.values().map(bare_site).collect()),
+                ),
+            ))
+        }
+
+        async fn list_sites_for_city_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            let city_id = self.resolve(&key)?.city_id;
+            self.list_sites_for_city_by_id(city_id).await
+        }
+
+        async fn list_restaurants_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData> {
+            let (co, ci, si) = self.site(site_id)?;
+            Ok(LunchData::new().with_country(
+                bare_country(co).with_city(
+                    bare_city(ci).with_site(
+                        bare_site(si).with_restaurants(
+                            si.restaurants.values().map(bare_restaurant).collect(),
+                        ),
+                    ),
+                ),
+            ))
+        }
+
+        async fn list_dishes_for_restaurant_by_id(&self, restaurant_id: Uuid) -> Result<LunchData> {
+            let (co, ci, si, r) = self.restaurant(restaurant_id)?;
+            Ok(LunchData::new().with_country(
+                bare_country(co)
+                    .with_city(bare_city(ci).with_site(bare_site(si).with_restaurant(r.clone()))),
+            ))
+        }
+
+        async fn list_dishes_for_restaurant_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            let restaurant_id = self.resolve(&key)?.restaurant_id;
+            self.list_dishes_for_restaurant_by_id(restaurant_id).await
+        }
+
+        async fn list_dishes_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData> {
+            let (co, ci, si) = self.site(site_id)?;
+            Ok(LunchData::new()
+                .with_country(bare_country(co).with_city(bare_city(ci).with_site(si.clone()))))
+        }
+
+        async fn list_dishes_for_site_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            let site_id = self.resolve(&key)?.site_id;
+            self.list_dishes_for_site_by_id(site_id).await
+        }
+
+        async fn list_all_sites(
+            &self,
+            filter: SiteKey<'_>,
+            limit: Option<usize>,
+            offset: usize,
+        ) -> Result<LunchData> {
+            let countries: Vec<&Country> = self
+                .data
+                .countries
+                .values()
+                .filter(|co| {
+                    filter.country_url_id.is_empty()
+                        || same_url_id(&co.url_id, filter.country_url_id.as_str())
+                })
+                .collect();
+            let cities: Vec<&City> = countries
+                .iter()
+                .flat_map(|co| co.cities.values())
+                .filter(|ci| {
+                    filter.city_url_id.is_empty()
+                        || same_url_id(&ci.url_id, filter.city_url_id.as_str())
+                })
+                .collect();
+            let mut sites: Vec<Site> = cities
+                .iter()
+                .flat_map(|ci| ci.sites.values())
+                .map(bare_site)
+                .collect();
+            sites.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.site_id.cmp(&b.site_id)));
+            let sites = sites
+                .into_iter()
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect();
+            Ok(LunchData::build(
+                countries.into_iter().map(bare_country).collect(),
+                cities.into_iter().map(bare_city).collect(),
+                sites,
+                Vec::new(),
+                Vec::new(),
+            ))
+        }
+
+        async fn count_sites(&self, filter: SiteKey<'_>) -> Result<i64> {
+            let all = self.list_all_sites(filter, None, 0).await?;
+            Ok(all
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .map(|ci| ci.sites.len() as i64)
+                .sum())
+        }
+
+        async fn list_tags(&self, site_id: Option<Uuid>) -> Result<Vec<TagCount>> {
+            let mut counts: HashMap<&str, i64> = HashMap::new();
+            self.data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .flat_map(|ci| ci.sites.values())
+                .filter(|si| site_id.is_none_or(|id| id == si.site_id))
+                .flat_map(|si| si.restaurants.values())
+                .flat_map(|r| r.dishes.values())
+                .flat_map(|d| d.tags.iter())
+                .filter(|t| !t.is_empty())
+                .for_each(|t| *counts.entry(t).or_default() += 1);
+            let mut tags: Vec<TagCount> = counts
+                .into_iter()
+                .map(|(tag, count)| TagCount {
+                    tag: tag.into(),
+                    count,
+                })
+                .collect();
+            tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+            Ok(tags)
+        }
+
+        async fn search_dishes(&self, query: &str, site_id: Option<Uuid>) -> Result<LunchData> {
+            // an unknown site just gives no hits in the DB
+            let data = match site_id {
+                Some(id) => self
+                    .list_dishes_for_site_by_id(id)
+                    .await
+                    .unwrap_or_default(),
+                None => self.data.clone(),
+            };
+            Ok(data.search(query))
+        }
+
+        async fn restaurants_near(
+            &self,
+            lat: f64,
+            lon: f64,
+            radius_km: f64,
+        ) -> Result<Vec<Restaurant>> {
+            let mut res: Vec<(f64, Restaurant)> = self
+                .data
+                .countries
+                .values()
+                .flat_map(|co| co.cities.values())
+                .flat_map(|ci| ci.sites.values())
+                .flat_map(|si| si.restaurants.values())
+                .filter_map(|r| match (r.lat, r.lon) {
+                    (Some(rlat), Some(rlon)) => Some((haversine_km(lat, lon, rlat, rlon), r)),
+                    _ => None,
+                })
+                .filter(|(d, _)| *d <= radius_km)
+                .map(|(d, r)| (d, bare_restaurant(r)))
+                .collect();
+            res.sort_by(|a, b| a.0.total_cmp(&b.0));
+            Ok(res.into_iter().map(|(_, r)| r).collect())
+        }
+
+        // there are no scrapers running against the in-memory store
+        async fn list_scraper_statuses(&self) -> Result<Vec<ScraperStatus>> {
+            Ok(Vec::new())
+        }
+    }
+
+    /// A MemStore counting the calls made to it, and failing them as if the DB was unreachable
+    /// while set to be down. Calls can also be made to take a while, like slow queries.
+    #[cfg(test)]
+    #[derive(Debug, Default)]
+    pub struct FlakyStore {
+        inner: MemStore,
+        calls: AtomicUsize,
+        down: AtomicBool,
+        delay_ms: AtomicU64,
+    }
+
+    #[cfg(test)]
+    impl FlakyStore {
+        pub fn new(data: LunchData) -> Self {
+            Self {
+                inner: MemStore::new(data),
+                ..Default::default()
+            }
+        }
+
+        pub fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        pub fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+
+        pub fn set_delay(&self, delay: Duration) {
+            self.delay_ms
+                .store(delay.as_millis() as u64, Ordering::SeqCst);
+        }
+
+        async fn check(&self) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let delay_ms = self.delay_ms.load(Ordering::SeqCst);
+            if delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            }
+            if self.down.load(Ordering::SeqCst) {
+                return Err(sqlx::Error::PoolTimedOut.into());
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    #[async_trait]
+    impl LunchStore for FlakyStore {
+        async fn get_site_relation(&self, key: SiteKey<'_>) -> Result<SiteRelation> {
+            self.check().await?;
+            self.inner.get_site_relation(key).await
+        }
+
+        async fn list_countries(&self, limit: Option<usize>, offset: usize) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_countries(limit, offset).await
+        }
+
+        async fn count_countries(&self) -> Result<i64> {
+            self.check().await?;
+            self.inner.count_countries().await
+        }
+
+        async fn list_cities_for_country_by_id(&self, country_id: Uuid) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_cities_for_country_by_id(country_id).await
+        }
+
+        async fn list_cities_for_country_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_cities_for_country_by_key(key).await
+        }
+
+        async fn list_all_cities(&self, country: CountryUrlId<'_>) -> Result<Vec<City>> {
+            self.check().await?;
+            self.inner.list_all_cities(country).await
+        }
+
+        async fn list_sites_for_city_by_id(&self, city_id: Uuid) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_sites_for_city_by_id(city_id).await
+        }
+
+        async fn list_sites_for_city_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_sites_for_city_by_key(key).await
+        }
+
+        async fn list_restaurants_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_restaurants_for_site_by_id(site_id).await
+        }
+
+        async fn list_dishes_for_restaurant_by_id(&self, restaurant_id: Uuid) -> Result<LunchData> {
+            self.check().await?;
+            self.inner
+                .list_dishes_for_restaurant_by_id(restaurant_id)
+                .await
+        }
+
+        async fn list_dishes_for_restaurant_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_dishes_for_restaurant_by_key(key).await
+        }
+
+        async fn list_dishes_for_site_by_id(&self, site_id: Uuid) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_dishes_for_site_by_id(site_id).await
+        }
+
+        async fn list_dishes_for_site_by_key(&self, key: SiteKey<'_>) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_dishes_for_site_by_key(key).await
+        }
+
+        async fn list_all_sites(
+            &self,
+            filter: SiteKey<'_>,
+            limit: Option<usize>,
+            offset: usize,
+        ) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.list_all_sites(filter, limit, offset).await
+        }
+
+        async fn count_sites(&self, filter: SiteKey<'_>) -> Result<i64> {
+            self.check().await?;
+            self.inner.count_sites(filter).await
+        }
+
+        async fn list_tags(&self, site_id: Option<Uuid>) -> Result<Vec<TagCount>> {
+            self.check().await?;
+            self.inner.list_tags(site_id).await
+        }
+
+        async fn search_dishes(&self, query: &str, site_id: Option<Uuid>) -> Result<LunchData> {
+            self.check().await?;
+            self.inner.search_dishes(query, site_id).await
+        }
+
+        async fn restaurants_near(
+            &self,
+            lat: f64,
+            lon: f64,
+            radius_km: f64,
+        ) -> Result<Vec<Restaurant>> {
+            self.check().await?;
+            self.inner.restaurants_near(lat, lon, radius_km).await
+        }
+
+        async fn list_scraper_statuses(&self) -> Result<Vec<ScraperStatus>> {
+            self.check().await?;
+            self.inner.list_scraper_statuses().await
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::db::{CityUrlId, RestaurantUrlId, SiteUrlId};
+
+        #[tokio::test]
+        async fn resolve_ignores_case_and_accents() {
+            let country = Country {
+                country_id: Uuid::new_v4(),
+                url_id: "se".into(),
+                ..Country::new("Sverige")
+            };
+            let city = City {
+                url_id: "göteborg".into(),
+                ..City::new_for_country("Göteborg", country.country_id)
+            };
+            let site = Site {
+                url_id: "lindholmen".into(),
+                ..Site::new_for_city("Lindholmen", city.city_id)
+            };
+            let restaurant = Restaurant {
+                url_id: "kårhuset".into(),
+                ..Restaurant::new_for_site("Kårhuset", site.site_id)
+            };
+            let want = SiteRelation {
+                country_id: country.country_id,
+                city_id: city.city_id,
+                site_id: site.site_id,
+                restaurant_id: restaurant.restaurant_id,
+            };
+            let store =
+                MemStore::new(LunchData::new().with_country(
+                    country.with_city(city.with_site(site.with_restaurant(restaurant))),
+                ));
+
+            for (co, ci, si, r) in [
+                ("se", "göteborg", "lindholmen", "kårhuset"),
+                ("se", "goteborg", "lindholmen", "karhuset"),
+                ("SE", "Göteborg", "LINDHOLMEN", "Kårhuset"),
+            ] {
+                let key = SiteKey::new(CountryUrlId(co), CityUrlId(ci), SiteUrlId(si))
+                    .with_restaurant(RestaurantUrlId(r));
+                assert_eq!(
+                    store.get_site_relation(key).await.unwrap(),
+                    want,
+                    "{ci}/{r}"
+                );
+            }
+
+            let key = SiteKey::new(CountryUrlId("se"), CityUrlId("gbg"), SiteUrlId(""));
+            assert!(store.get_site_relation(key).await.is_err());
+        }
+    }
+}